@@ -0,0 +1,265 @@
+//! A fake language server for the tests of `miv::lsp`, answering from the text of the documents only.
+//! Run miv with `:LspServer text target/debug/examples/fake_lsp` to try it by hand.
+//!
+//! * hover: the line under the cursor
+//! * definition: the first occurrence of the word under the cursor, references: all of them
+//! * rename: all the occurrences
+//! * code actions: uppercase the word, or a command asking to insert a comment at the top
+//! * formatting: remove trailing whitespace
+//! * completion: the words of the document
+
+extern crate miv;
+
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use miv::json::{Json, object};
+use miv::lsp::{self, read_message, write_message};
+
+struct Server {
+    /// The text of the open documents, as lines without their line endings, by URI.
+    documents: HashMap<String, Vec<String>>,
+    next_id: u64,
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut reader = BufReader::new(stdin.lock());
+    let mut server = Server { documents: HashMap::new(), next_id: 1 };
+    while let Ok(Some(message)) = read_message(&mut reader) {
+        let method = match message.get("method").as_str() {
+            Some(method) => method.to_string(),
+            None => continue, // A response to our own requests.
+        };
+        let params = message.get("params");
+        if method == "exit" {
+            break;
+        }
+
+        let result = server.handle(&method, params);
+        let id = message.get("id").clone();
+        if id.is_null() {
+            continue;
+        }
+        let response = match result {
+            Some(result) => object(vec!(("jsonrpc", "2.0".into()), ("id", id), ("result", result))),
+            None => object(vec!(("jsonrpc", "2.0".into()), ("id", id), ("error", object(vec!(
+                ("code", Json::Number(-32601.0)),
+                ("message", format!("Unknown method {}", method).into()),
+            ))))),
+        };
+        send(&response);
+    }
+}
+
+fn send(message: &Json) {
+    let stdout = io::stdout();
+    write_message(&mut stdout.lock(), message).unwrap();
+}
+
+impl Server {
+    /// The result of a request, `None` for unknown methods. Notifications give `Null`.
+    fn handle(&mut self, method: &str, params: &Json) -> Option<Json> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("").to_string();
+        let position = params.get("position");
+        let (line, character) = (position.get("line").as_u64().unwrap_or(0) as usize, position.get("character").as_u64().unwrap_or(0) as usize);
+        let result = match method {
+            "initialize" => object(vec!(("capabilities", object(vec!(
+                ("textDocumentSync", 2usize.into()),
+                ("hoverProvider", true.into()),
+                ("definitionProvider", true.into()),
+                ("referencesProvider", true.into()),
+                ("renameProvider", true.into()),
+                ("codeActionProvider", true.into()),
+                ("documentFormattingProvider", true.into()),
+                ("completionProvider", object(Vec::new())),
+                ("executeCommandProvider", object(vec!(("commands", vec!(Json::from("fake.comment")).into())))),
+            ))))),
+            "initialized" | "textDocument/didSave" => Json::Null,
+            "shutdown" => Json::Null,
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").get("text").as_str().unwrap_or("");
+                self.documents.insert(uri, split(text));
+                Json::Null
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                Json::Null
+            }
+            "textDocument/didChange" => {
+                for change in params.get("contentChanges").items() {
+                    self.change(&uri, change);
+                }
+                Json::Null
+            }
+            "textDocument/hover" => {
+                let text = self.line(&uri, line);
+                object(vec!(("contents", object(vec!(("kind", "plaintext".into()), ("value", text.into()))))))
+            }
+            "textDocument/definition" => {
+                match self.occurrences(&uri, line, character).into_iter().next() {
+                    Some(start) => location(&uri, start, 0),
+                    None => Json::Null,
+                }
+            }
+            "textDocument/references" => {
+                let word = self.word(&uri, line, character).encode_utf16().count();
+                self.occurrences(&uri, line, character).into_iter().map(|start| location(&uri, start, word)).collect::<Vec<Json>>().into()
+            }
+            "textDocument/rename" => {
+                let name = params.get("newName").as_str().unwrap_or("");
+                let edits = self.replace_word(&uri, line, character, name);
+                object(vec!(("changes", Json::Object(vec!((uri, edits))))))
+            }
+            "textDocument/codeAction" => {
+                let range = params.get("range").get("start");
+                let (line, character) = (range.get("line").as_u64().unwrap_or(0) as usize, range.get("character").as_u64().unwrap_or(0) as usize);
+                let upper = self.word(&uri, line, character).to_uppercase();
+                let edits = self.replace_word(&uri, line, character, &upper);
+                vec!(
+                    object(vec!(
+                        ("title", "Uppercase the word".into()),
+                        ("kind", "refactor".into()),
+                        ("edit", object(vec!(("changes", Json::Object(vec!((uri.clone(), edits))))))),
+                    )),
+                    object(vec!(
+                        ("title", "Insert a comment".into()),
+                        ("command", object(vec!(
+                            ("title", "Insert a comment".into()),
+                            ("command", "fake.comment".into()),
+                            ("arguments", vec!(Json::from(uri.as_str())).into()),
+                        ))),
+                    )),
+                ).into()
+            }
+            "workspace/executeCommand" => {
+                let uri = params.get("arguments").items().first().and_then(Json::as_str).unwrap_or("").to_string();
+                let edit = object(vec!(("range", lsp::range((0, 0), (0, 0))), ("newText", "// comment\n".into())));
+                let id = self.next_id;
+                self.next_id += 1;
+                send(&object(vec!(
+                    ("jsonrpc", "2.0".into()),
+                    ("id", id.into()),
+                    ("method", "workspace/applyEdit".into()),
+                    ("params", object(vec!(("edit", object(vec!(("changes", Json::Object(vec!((uri, vec!(edit).into())))))))))),
+                )));
+                Json::Null
+            }
+            "textDocument/formatting" => {
+                let lines = self.documents.get(&uri).cloned().unwrap_or_default();
+                lines.iter().enumerate()
+                    .filter(|&(_, text)| text.trim_end().len() < text.len())
+                    .map(|(idx, text)| {
+                        let start = text.trim_end().encode_utf16().count();
+                        let end = text.encode_utf16().count();
+                        object(vec!(("range", lsp::range((idx, start), (idx, end))), ("newText", "".into())))
+                    })
+                    .collect::<Vec<Json>>().into()
+            }
+            "textDocument/completion" => {
+                let mut words: Vec<String> = Vec::new();
+                for text in self.documents.get(&uri).into_iter().flat_map(|lines| lines.iter()) {
+                    for word in text.split(|c: char| !is_word_char(c)).filter(|word| !word.is_empty()) {
+                        if !words.iter().any(|known| known == word) {
+                            words.push(word.into());
+                        }
+                    }
+                }
+                let items: Vec<Json> = words.into_iter().map(|word| object(vec!(("label", word.into()), ("kind", 6usize.into())))).collect();
+                object(vec!(("isIncomplete", false.into()), ("items", items.into())))
+            }
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /// Apply a change of `textDocument/didChange`, to a range or to the whole text.
+    fn change(&mut self, uri: &str, change: &Json) {
+        let text = change.get("text").as_str().unwrap_or("");
+        let lines = self.documents.entry(uri.into()).or_default();
+        let range = change.get("range");
+        if range.is_null() {
+            *lines = split(text);
+            return;
+        }
+
+        let position = |key| {
+            let position = range.get(key);
+            (position.get("line").as_u64().unwrap_or(0) as usize, position.get("character").as_u64().unwrap_or(0) as usize)
+        };
+        let ((start_line, start), (end_line, end)) = (position("start"), position("end"));
+        let mut whole = lines.join("\n");
+        let start = offset(&whole, start_line, start);
+        let end = offset(&whole, end_line, end);
+        whole.replace_range(start..end, text);
+        *lines = split(&whole);
+    }
+
+    fn line(&self, uri: &str, line: usize) -> String {
+        self.documents.get(uri).and_then(|lines| lines.get(line)).cloned().unwrap_or_default()
+    }
+
+    /// The word at a position, empty when there's none.
+    fn word(&self, uri: &str, line: usize, character: usize) -> String {
+        let text = self.line(uri, line);
+        let idx = byte_index(&text, character);
+        let start = text[..idx].rfind(|c: char| !is_word_char(c)).map_or(0, |start| start + text[start..].chars().next().unwrap().len_utf8());
+        let end = text[idx..].find(|c: char| !is_word_char(c)).map_or(text.len(), |end| idx + end);
+        text[start..end].into()
+    }
+
+    /// Where the word at a position is found in the document, as whole words.
+    fn occurrences(&self, uri: &str, line: usize, character: usize) -> Vec<(usize, usize)> {
+        let word = self.word(uri, line, character);
+        let mut found = Vec::new();
+        if word.is_empty() {
+            return found;
+        }
+        for (idx, text) in self.documents.get(uri).into_iter().flat_map(|lines| lines.iter()).enumerate() {
+            for (start, _) in text.match_indices(word.as_str()) {
+                let before = text[..start].chars().next_back().is_some_and(is_word_char);
+                let after = text[start + word.len()..].chars().next().is_some_and(is_word_char);
+                if !before && !after {
+                    found.push((idx, text[..start].encode_utf16().count()));
+                }
+            }
+        }
+        found
+    }
+
+    fn replace_word(&self, uri: &str, line: usize, character: usize, text: &str) -> Json {
+        let len = self.word(uri, line, character).encode_utf16().count();
+        self.occurrences(uri, line, character).into_iter()
+            .map(|(line, character)| object(vec!(("range", lsp::range((line, character), (line, character + len))), ("newText", text.into()))))
+            .collect::<Vec<Json>>().into()
+    }
+}
+
+fn location(uri: &str, (line, character): (usize, usize), len: usize) -> Json {
+    object(vec!(("uri", uri.into()), ("range", lsp::range((line, character), (line, character + len)))))
+}
+
+fn split(text: &str) -> Vec<String> {
+    text.split('\n').map(String::from).collect()
+}
+
+/// The byte offset of a line and UTF-16 column in a text.
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let start = text.split('\n').take(line).map(|line| line.len() + 1).sum::<usize>().min(text.len());
+    start + byte_index(&text[start..], character)
+}
+
+/// The byte index of a UTF-16 column in a line, the end of the line past it.
+fn byte_index(text: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (idx, c) in text.char_indices() {
+        if units >= character || c == '\n' {
+            return idx;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
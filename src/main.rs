@@ -1,37 +1,113 @@
 extern crate rustbox;
 extern crate miv;
 
+use std::cmp;
 use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 use rustbox::RustBox;
-use rustbox::Key;
+use miv::config;
 use miv::state::State;
 use miv::view::View;
 
+/// Number of errors in a row while reading the input before giving up, e.g. when the terminal is gone.
+const MAX_INPUT_ERRORS: usize = 10;
+
+/// Command line arguments.
+struct Args {
+    /// Configuration file to load, if any. See `-u`.
+    config: Option<PathBuf>,
+    file: Option<String>,
+}
+
+/// Parse the command line: `miv [-u config|NONE] [file]`.
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { config: config::find(), file: None };
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "-u" => match iter.next() {
+                Some(ref path) if path == "NONE" => args.config = None,
+                Some(path) => args.config = Some(PathBuf::from(path)),
+                None => return Err("Argument missing after -u".into()),
+            },
+            _ if args.file.is_none() => args.file = Some(arg),
+            _ => return Err(format!("Too many arguments: {}", arg)),
+        }
+    }
+    Ok(args)
+}
+
 fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            let _ = writeln!(io::stderr(), "miv: {}\nUsage: miv [-u config|NONE] [file]", message);
+            process::exit(2);
+        }
+    };
+
     let mut options = rustbox::InitOptions::default();
     options.output_mode = rustbox::OutputMode::EightBit;
     options.buffer_stderr = true;
-    let rustbox = RustBox::init(options).unwrap();
+    let rustbox = match RustBox::init(options) {
+        Ok(rustbox) => rustbox,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "miv: can't initialize the terminal: {}", e);
+            process::exit(1);
+        }
+    };
 
     let mut view = View::new(&rustbox);
     let mut state = State::new(rustbox.width(), rustbox.height());
 
-    let args: Vec<String> = env::args().collect();
-    if let Some(path) = args.get(1) {
-        state.buffer.borrow_mut().load_file(path.clone());
-        state.buffer.borrow_mut().filepath = Some(path.clone());
+    if let Some(ref path) = args.config {
+        state.load_config(path);
+    }
+    // Keep the configuration errors visible rather than the file's status.
+    let config_errors = state.status.take();
+    if let Some(path) = args.file {
+        state.load_file(path);
+    }
+    if config_errors.is_some() {
+        state.status = config_errors;
     }
 
     rustbox.clear();
     rustbox.set_cursor(0, 0);
     view.render(&state);
 
+    let mut input_errors = 0;
+    let mut input_failure = None;
+    let mut last_idle = Instant::now();
     'running: loop {
-        match rustbox.poll_event(false) {
-            Ok(rustbox::Event::KeyEvent(Key::Ctrl('c'))) => {
-                break 'running;
+        // Write the swap files and look for files changed by other programs when idle for a while.
+        // Wake up earlier when keys typed may be the beginning of a longer mapping, see `timeoutlen`,
+        // to show the which-key popup, and to handle the messages of the language servers.
+        let idle = Duration::from_millis(state.options.updatetime as u64);
+        let timeout = [state.pending_timeout(), state.which_key_timeout(), state.language_server_timeout()].iter()
+            .filter_map(|&timeout| timeout)
+            .fold(idle.checked_sub(last_idle.elapsed()).unwrap_or(Duration::from_secs(0)), cmp::min);
+        let event = rustbox.peek_event(timeout, false);
+        if event.is_ok() {
+            input_errors = 0;
+        }
+        match event {
+            Ok(rustbox::Event::NoEvent) => {
+                if state.pending_timeout() == Some(Duration::from_secs(0)) && state.handle_timeout() {
+                    break 'running;
+                }
+                if last_idle.elapsed() >= idle {
+                    state.update_swap_files();
+                    state.check_external_changes();
+                    last_idle = Instant::now();
+                }
             }
             Ok(rustbox::Event::KeyEvent(key)) => {
+                last_idle = Instant::now();
                 let exit = state.handle_key(key);
                 if exit { break 'running }
             }
@@ -39,10 +115,29 @@ fn main() {
                 state.width = w as usize;
                 state.height = h as usize;
             }
-            Err(e) => panic!("{}", e),
+            Err(e) => {
+                input_errors += 1;
+                if input_errors >= MAX_INPUT_ERRORS {
+                    input_failure = Some(e);
+                    break 'running;
+                }
+                state.status = Some(format!("Error while reading the input: {}", e));
+                thread::sleep(Duration::from_millis(100));
+            }
             _ => {}
         }
 
+        state.handle_language_servers();
         view.render(&state);
     }
+
+    state.stop_language_servers();
+    if let Some(error) = input_failure {
+        // Restore the terminal before reporting it. The swap files are written and kept for recovery.
+        state.update_swap_files();
+        drop(rustbox);
+        let _ = writeln!(io::stderr(), "miv: can't read the input: {}", error);
+        process::exit(1);
+    }
+    state.remove_swap_files();
 }
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use command::split_word;
+
+/// Events commands can be run on, with `:autocmd`.
+#[derive(PartialEq,Eq,Debug,Copy,Clone)]
+pub enum Event {
+    /// After reading a file into a buffer.
+    BufRead,
+    /// After starting to edit a file that doesn't exist.
+    BufNewFile,
+    /// After switching to a buffer.
+    BufEnter,
+    /// After the file type of a buffer is set. The pattern matches the file type, e.g. `rust`.
+    FileType,
+    /// Before saving a buffer.
+    BufWritePre,
+    /// After saving a buffer.
+    BufWritePost,
+}
+
+impl Event {
+    /// The event called `name`, ignoring case like Vim. `BufReadPost` is accepted for `BufRead`.
+    pub fn from_name(name: &str) -> Option<Event> {
+        match name.to_lowercase().as_ref() {
+            "bufread" | "bufreadpost" => Some(Event::BufRead),
+            "bufnewfile" => Some(Event::BufNewFile),
+            "bufenter" => Some(Event::BufEnter),
+            "filetype" => Some(Event::FileType),
+            "bufwritepre" => Some(Event::BufWritePre),
+            "bufwritepost" => Some(Event::BufWritePost),
+            _ => None,
+        }
+    }
+}
+
+/// A command to run when an event happens to a file matching a pattern.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct Autocmd {
+    pub event: Event,
+    pub pattern: String,
+    pub command: String,
+}
+
+impl Autocmd {
+    /// Parse the arguments of `:autocmd`, e.g. `BufRead *.md set wrap`.
+    /// Several events can be given at once, separated by commas.
+    pub fn parse(args: &str) -> Result<Vec<Autocmd>, String> {
+        let (events, rest) = split_word(args);
+        let (pattern, command) = split_word(rest);
+        if events.is_empty() || pattern.is_empty() || command.is_empty() {
+            return Err("Usage: autocmd Event pattern command".into());
+        }
+
+        events.split(',').map(|name| {
+            let event = Event::from_name(name).ok_or_else(|| format!("No such event: {}", name))?;
+            Ok(Autocmd { event, pattern: pattern.into(), command: command.into() })
+        }).collect()
+    }
+
+    /// Whether the command runs for `event` on the file at `path`, or of type `path` for `FileType`.
+    pub fn matches(&self, event: Event, path: Option<&str>) -> bool {
+        self.event == event && path.is_some_and(|path| matches_pattern(&self.pattern, path))
+    }
+}
+
+/// Whether a file matches an autocommand pattern.
+///
+/// Patterns are globs with `*` and `?`, and can list alternatives separated by commas, e.g. `*.c,*.h`.
+/// A pattern with a `/` is matched against the whole path, otherwise against the file name only.
+pub fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let name = Path::new(path).file_name().map_or(path.into(), |name| name.to_string_lossy());
+    pattern.split(',').any(|pattern| {
+        let text = if pattern.contains('/') { path } else { name.as_ref() };
+        glob(&pattern.chars().collect::<Vec<char>>(), &text.chars().collect::<Vec<char>>())
+    })
+}
+
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((&'*', rest)) => (0..text.len() + 1).any(|skip| glob(rest, &text[skip..])),
+        Some((&'?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use autocmd::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("*", "/tmp/notes.md"));
+        assert!(matches_pattern("*.md", "/tmp/notes.md"));
+        assert!(matches_pattern("*.c,*.h", "src/main.h"));
+        assert!(matches_pattern("notes.??", "notes.md"));
+        assert!(matches_pattern("/tmp/*.md", "/tmp/notes.md"));
+        assert!(!matches_pattern("*.md", "/tmp/notes.txt"));
+        assert!(!matches_pattern("/home/*.md", "/tmp/notes.md"));
+    }
+
+    #[test]
+    fn parse() {
+        let autocmds = Autocmd::parse("BufRead,bufnewfile *.md set wrap linebreak").unwrap();
+        assert_eq!(autocmds, vec!(
+            Autocmd { event: Event::BufRead, pattern: "*.md".into(), command: "set wrap linebreak".into() },
+            Autocmd { event: Event::BufNewFile, pattern: "*.md".into(), command: "set wrap linebreak".into() },
+        ));
+        assert_eq!(Autocmd::parse("FileType rust nmap <buffer> Q :q<CR>").unwrap()[0].event, Event::FileType);
+        assert!(Autocmd::parse("BufRead *.md").is_err());
+        assert!(Autocmd::parse("BufOops * set wrap").is_err());
+    }
+}
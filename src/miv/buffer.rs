@@ -1,11 +1,128 @@
-use std::io::{Read, Write};
-use std::fs::OpenOptions;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
 use std::cmp;
+use std::mem;
+use libc;
+use unicode_segmentation::UnicodeSegmentation;
 
+use encoding::{self, Encoding};
+use file::{self, Stamp};
+use options;
 use point::Point;
+use swap::SwapFile;
+
+/// A change of the text, recorded in `Buffer::journal` for language servers.
+/// Positions are lines and UTF-16 columns, as counted by the Language Server Protocol.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct Edit {
+    /// Where the replaced text starts.
+    pub start: (usize, usize),
+    /// Where the replaced text ends, excluded.
+    pub end: (usize, usize),
+    /// The text replacing it.
+    pub text: String,
+}
+
+/// Errors happening when loading or saving a `Buffer`.
+#[derive(Debug)]
+pub enum Error {
+    /// The text can't be represented in the file encoding.
+    Conversion(String),
+    /// Saving a read-only buffer without forcing it.
+    ReadOnly,
+    /// Saving a buffer that has no file yet.
+    NoFileName,
+    /// Saving over changes made by another program without forcing it.
+    ChangedOnDisk,
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl fmt::Display for Error {
+    /// A short message, suitable for the status bar.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Conversion(ref message) => write!(f, "{}", message),
+            Error::ReadOnly => write!(f, "File is read-only (add ! to override)"),
+            Error::NoFileName => write!(f, "No file name"),
+            Error::ChangedOnDisk => write!(f, "File changed on disk since it was read (add ! to override)"),
+            Error::Io(ref error) => {
+                let message = match (error.kind(), error.raw_os_error()) {
+                    (io::ErrorKind::NotFound, _) => "No such file or directory",
+                    (io::ErrorKind::PermissionDenied, _) => "Permission denied",
+                    (_, Some(libc::EISDIR)) => "Is a directory",
+                    (_, Some(libc::ENOSPC)) => "No space left on device",
+                    (_, Some(libc::EROFS)) => "Read-only file system",
+                    _ => return write!(f, "{}", error),
+                };
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+/// How the file of a buffer changed on disk. See `Buffer::check_disk`.
+#[derive(PartialEq,Eq,Debug,Copy,Clone)]
+pub enum DiskChange {
+    Unchanged,
+    Changed,
+    Deleted,
+}
+
+/// Byte order mark, as decoded from any Unicode encoding.
+const BOM: &str = "\u{feff}";
+
+/// Line ending used when saving a file. Lines are always terminated by `\n` in memory.
+#[derive(PartialEq,Eq,Debug,Copy,Clone)]
+pub enum FileFormat {
+    /// `LF`
+    Unix,
+    /// `CRLF`
+    Dos,
+    /// `CR`
+    Mac,
+}
+
+impl FileFormat {
+    /// The line ending itself.
+    pub fn ending(&self) -> &'static str {
+        match *self {
+            FileFormat::Unix => "\n",
+            FileFormat::Dos => "\r\n",
+            FileFormat::Mac => "\r",
+        }
+    }
+
+    /// Name used by `:set fileformat` and displayed in the bottom bar.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FileFormat::Unix => "unix",
+            FileFormat::Dos => "dos",
+            FileFormat::Mac => "mac",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FileFormat> {
+        match name {
+            "unix" => Some(FileFormat::Unix),
+            "dos" => Some(FileFormat::Dos),
+            "mac" => Some(FileFormat::Mac),
+            _ => None,
+        }
+    }
+}
 
 /// Holds the text data and the filepath, if any.
-/// This buffer has no knowledge of the editor.
+/// This buffer has no knowledge of the editor, except for its number and last cursor position.
+///
+/// Columns are counted in grapheme clusters (i.e. user perceived characters), not bytes nor chars.
 pub struct Buffer {
     /// Holds the actual data.
     /// This is a *very* inefficient data structure and will eventually be replace by a GapBuffer or Rope.
@@ -14,6 +131,57 @@ pub struct Buffer {
 
     /// The file path associated with that buffer.
     pub filepath: Option<String>,
+
+    /// Line ending written when saving. Detected when loading a file.
+    pub fileformat: FileFormat,
+
+    /// Whether the file had more than one kind of line ending when loaded.
+    /// They are all converted to `fileformat` when saving.
+    pub mixed_line_endings: bool,
+
+    /// Whether the last line is terminated when saving.
+    /// Unset when loading a file without a final new line, so that it's saved as it was.
+    pub endofline: bool,
+
+    /// Whether the file starts with a byte order mark.
+    pub bomb: bool,
+
+    /// Encoding used when saving. Detected when loading a file.
+    pub fileencoding: Encoding,
+
+    /// Whether the file didn't exist when loaded. It's only created when first saved.
+    pub new_file: bool,
+
+    /// Whether the file can't be written. Saving it must then be forced.
+    pub readonly: bool,
+
+    /// Language of the file, e.g. `rust`, guessed from its name when loaded. Empty when unknown.
+    /// See `filetype::detect`.
+    pub filetype: String,
+
+    /// Identifies the current state of the text. Every edit gives it a new, never used value.
+    pub change_id: usize,
+
+    /// The `change_id` when the buffer was last loaded or saved. See `modified`.
+    pub saved_change_id: usize,
+
+    /// Last `change_id` given out.
+    last_change_id: usize,
+
+    /// Swap file kept up to date with the text for crash recovery, if this process owns one.
+    pub swap: Option<SwapFile>,
+
+    /// State of the file when last loaded or saved. See `check_disk`.
+    pub disk: Option<Stamp>,
+
+    /// Number given by the editor, shown by `:ls` and used by `:b N`. Never reused.
+    pub number: usize,
+
+    /// Position of the cursor when the buffer was last left, restored when coming back to it.
+    pub last_cursor: Point,
+
+    /// Edits not sent to the language server yet, in order. `None` when no server has the buffer open.
+    pub journal: Option<Vec<Edit>>,
 }
 
 impl Buffer {
@@ -22,9 +190,144 @@ impl Buffer {
         Buffer {
             data: vec!("\n".into()),
             filepath: None,
+            fileformat: FileFormat::Unix,
+            mixed_line_endings: false,
+            endofline: true,
+            bomb: false,
+            fileencoding: Encoding::Utf8,
+            new_file: false,
+            readonly: false,
+            filetype: String::new(),
+            change_id: 0,
+            saved_change_id: 0,
+            last_change_id: 0,
+            swap: None,
+            disk: None,
+            number: 0,
+            last_cursor: Point::new(0, 0),
+            journal: None,
         }
     }
 
+    /// Name to display for this buffer, i.e. its file path or `[No Name]`.
+    pub fn name(&self) -> String {
+        self.filepath.clone().unwrap_or_else(|| "[No Name]".into())
+    }
+
+    /// Whether the text changed since the buffer was last loaded or saved.
+    pub fn modified(&self) -> bool {
+        self.change_id != self.saved_change_id
+    }
+
+    /// Replace the whole text, e.g. with the one recovered from a swap file.
+    pub fn set_lines(&mut self, lines: Vec<String>) {
+        let lines = if lines.is_empty() { vec!("\n".into()) } else { lines };
+        self.record(Point::new(0, 0), Point::new(0, self.data.len()), &lines.concat());
+        self.data = lines;
+        self.changed();
+    }
+
+    /// Write the text to the swap file if it changed since the last time. See `swap`.
+    pub fn update_swap(&mut self) -> io::Result<()> {
+        let modified = self.modified();
+        match self.swap {
+            Some(ref mut swap) => swap.update(&self.data, modified, self.change_id),
+            None => Ok(()),
+        }
+    }
+
+    /// The whole text, as sent to language servers.
+    pub fn text(&self) -> String {
+        self.data.concat()
+    }
+
+    /// The position of a location for language servers, i.e. its line and UTF-16 column. See `Edit`.
+    /// A location past the line ending is the start of the next line.
+    pub fn lsp_position(&self, location: Point) -> (usize, usize) {
+        match self.data.get(location.y) {
+            Some(line) => {
+                let idx = self.byte_index(location);
+                if idx == line.len() && line.ends_with('\n') {
+                    (location.y + 1, 0)
+                } else {
+                    (location.y, line[..idx].encode_utf16().count())
+                }
+            }
+            None => (location.y, 0),
+        }
+    }
+
+    /// The location of a position given by a language server. See `lsp_position`.
+    /// Positions past the end of the text are at the end of the last line.
+    pub fn lsp_location(&self, (line, character): (usize, usize)) -> Point {
+        if line >= self.data.len() {
+            let last = self.data.len() - 1;
+            return Point::new(self.grapheme_len(last), last);
+        }
+
+        let mut units = 0;
+        for (x, grapheme) in self.data[line].graphemes(true).enumerate() {
+            if units >= character || grapheme == "\n" {
+                return Point::new(x, line);
+            }
+            units += grapheme.encode_utf16().count();
+        }
+        Point::new(self.grapheme_len(line), line)
+    }
+
+    /// Add the replacement of the text from `start` to `end` to the journal, if kept. Called before the edit.
+    fn record(&mut self, start: Point, end: Point, text: &str) {
+        if self.journal.is_none() { return }
+        let edit = Edit { start: self.lsp_position(start), end: self.lsp_position(end), text: text.into() };
+        if let Some(ref mut journal) = self.journal {
+            journal.push(edit);
+        }
+    }
+
+    /// Record an edit. See `change_id`.
+    fn changed(&mut self) {
+        self.last_change_id += 1;
+        self.change_id = self.last_change_id;
+    }
+
+    /// Apply a buffer local `:set` argument. See `Options::set`.
+    /// Returns `None` when the argument isn't about a buffer local option.
+    /// Options changing what is saved mark the buffer as modified, `readonly` doesn't.
+    pub fn set_option(&mut self, arg: &str) -> Option<Result<(), String>> {
+        if options::set_flag(&mut self.readonly, arg, &["readonly", "ro"]) {
+            return Some(Ok(()));
+        }
+
+        let result = self.set_file_option(arg);
+        if let Some(Ok(())) = result {
+            self.changed();
+        }
+        result
+    }
+
+    fn set_file_option(&mut self, arg: &str) -> Option<Result<(), String>> {
+        if let Some(value) = options::value_of(arg, &["fileencoding", "fenc"]) {
+            return Some(match Encoding::from_name(value) {
+                Some(encoding) => { self.fileencoding = encoding; Ok(()) }
+                None => Err(options::invalid_value("fileencoding", value)),
+            });
+        }
+
+        if let Some(value) = options::value_of(arg, &["fileformat", "ff"]) {
+            return Some(match FileFormat::from_name(value) {
+                Some(fileformat) => { self.fileformat = fileformat; Ok(()) }
+                None => Err(options::invalid_value("fileformat", value)),
+            });
+        }
+
+        if options::set_flag(&mut self.endofline, arg, &["endofline", "eol"]) ||
+           options::set_flag(&mut self.bomb, arg, &["bomb"]) {
+            return Some(Ok(()));
+        }
+
+        None
+    }
+
     /// Number of line in the buffer. Returns 1 if the buffer is empty.
     pub fn line_len(&self) -> usize {
         cmp::max(1, self.data.len())
@@ -35,14 +338,25 @@ impl Buffer {
         self.data.get(y)
     }
 
-    /// Find a char at a specific column/row.
-    pub fn char_at(&mut self, location: Point) -> Option<char> {
-        self.line_at(location.y).and_then(|line| line.chars().nth(location.x))
+    /// Find the grapheme cluster at a specific column/row.
+    pub fn grapheme_at(&mut self, location: Point) -> Option<&str> {
+        self.line_at(location.y).and_then(|line| line.graphemes(true).nth(location.x))
     }
 
-    /// Insert a row at the specified point, pushing the other characters to the right.
+    /// Number of grapheme clusters in a line, including the final new line.
+    pub fn grapheme_len(&self, y: usize) -> usize {
+        self.data.get(y).map_or(0, |line| line.graphemes(true).count())
+    }
+
+    /// Insert a character at the specified point, pushing the other characters to the right.
+    ///
+    /// Note that a combining character merges with the preceding grapheme,
+    /// so the line doesn't necessarily grow by one column. See `grapheme_len`.
     pub fn insert(&mut self, location: Point, c: char) {
-        self.data[location.y].insert(location.x, c);
+        self.record(location, location, &c.to_string());
+        let idx = self.byte_index(location);
+        self.data[location.y].insert(idx, c);
+        self.changed();
     }
 
     /// Replace a specific character.
@@ -53,88 +367,592 @@ impl Buffer {
 
     /// Replace a character at the given location or insert it.
     pub fn upsert(&mut self, location: Point, c: char) {
-        if let Some('\n') = self.char_at(location) {
+        if let Some("\n") = self.grapheme_at(location) {
             self.insert(location, c);
         } else {
             self.replace(location, c);
         }
     }
 
-    /// Delete a character at the given location, shifting the rest of the line to the left.
-    pub fn delete(&mut self, location: Point) -> char {
-        self.data[location.y].remove(location.x)
+    /// Delete a grapheme at the given location, shifting the rest of the line to the left.
+    pub fn delete(&mut self, location: Point) -> String {
+        self.record(location, location.offset(1, 0), "");
+        let start = self.byte_index(location);
+        let end = self.byte_index(location.offset(1, 0));
+        self.changed();
+        self.data[location.y].drain(start..end).collect()
     }
 
     /// Insert several characters at the given location. See `insert`.
     pub fn insert_text(&mut self, location: Point, string: String) {
-        for (x, c) in string.chars().enumerate() {
-            self.data[location.y].insert(location.x + x, c);
+        self.record(location, location, &string);
+        let idx = self.byte_index(location);
+        self.data[location.y].insert_str(idx, &string);
+        self.changed();
+    }
+
+    /// Insert text spanning any number of lines at the given location.
+    /// Returns the location right after the inserted text.
+    pub fn insert_lines(&mut self, location: Point, text: &str) -> Point {
+        self.record(location, location, text);
+        let line = self.data[location.y].clone();
+        let (left, right) = line.split_at(self.byte_index(location));
+        let mut lines: Vec<String> = text.split('\n').map(String::from).collect();
+        let end = match lines.len() {
+            1 => Point::new(location.x + text.graphemes(true).count(), location.y),
+            n => Point::new(lines[n - 1].graphemes(true).count(), location.y + n - 1),
+        };
+
+        lines[0].insert_str(0, left);
+        lines.last_mut().unwrap().push_str(right);
+        let last = lines.len() - 1;
+        for line in &mut lines[..last] {
+            line.push('\n');
+        }
+        self.data.splice(location.y..location.y + 1, lines);
+        self.changed();
+        end
+    }
+
+    /// The text from `start` up to `end` excluded, which can span several lines. `start` must come first.
+    pub fn text_range(&self, start: Point, end: Point) -> String {
+        let (first, last) = (self.byte_index(start), self.byte_index(end));
+        if start.y == end.y {
+            return self.data[start.y][first..last].into();
+        }
+
+        let mut text = String::from(&self.data[start.y][first..]);
+        for line in &self.data[start.y + 1..end.y] {
+            text.push_str(line);
+        }
+        text.push_str(&self.data[end.y][..last]);
+        text
+    }
+
+    /// Delete the text from `start` up to `end` excluded, joining the lines in between. Returns the text.
+    pub fn delete_range(&mut self, start: Point, end: Point) -> String {
+        self.record(start, end, "");
+        let text = self.text_range(start, end);
+        let mut line = String::from(&self.data[start.y][..self.byte_index(start)]);
+        line.push_str(&self.data[end.y][self.byte_index(end)..]);
+        self.data.splice(start.y..end.y + 1, Some(line));
+        self.changed();
+        text
+    }
+
+    /// Replace the text from `start` up to `end` excluded, e.g. with an edit of a language server.
+    /// The last line stays terminated.
+    /// It's journaled as a single edit, with the line ending added if any.
+    pub fn replace_range(&mut self, start: Point, end: Point, text: &str) {
+        let (from, to) = (self.lsp_position(start), self.lsp_position(end));
+        let journal = self.journal.take();
+        let mut inserted = String::from(text);
+
+        // Past the last line ending, i.e. at the very end of the text, the text goes on a line of its own.
+        let last = self.data.len() - 1;
+        let (start, end) = if start.y == last && start.x == self.grapheme_len(last) && self.data[last].ends_with('\n') {
+            self.data.push(String::new());
+            (Point::new(0, last + 1), Point::new(0, last + 1))
+        } else {
+            (start, end)
+        };
+        self.delete_range(start, end);
+        self.insert_lines(start, text);
+        if self.data.len() > 1 && self.data.last().is_some_and(String::is_empty) {
+            // The text ended with a new line, the one after it is nothing.
+            self.data.pop();
+        }
+        let last = self.data.len() - 1;
+        if !self.data[last].ends_with('\n') {
+            let len = self.grapheme_len(last);
+            self.insert(Point::new(len, last), '\n');
+            inserted.push('\n');
         }
+
+        self.journal = journal;
+        if let Some(ref mut journal) = self.journal {
+            journal.push(Edit { start: from, end: to, text: inserted });
+        }
+    }
+
+    /// The location of the next occurrence of `text`, starting at `location`. Doesn't wrap around.
+    pub fn find(&self, text: &str, location: Point) -> Option<Point> {
+        let first = self.byte_index(location);
+        for (y, line) in self.data.iter().enumerate().skip(location.y) {
+            let from = if y == location.y { first } else { 0 };
+            if let Some(idx) = line[from..].find(text) {
+                return Some(Point::new(line[..from + idx].graphemes(true).count(), y));
+            }
+        }
+        None
     }
 
     /// Insert an empty line at the given location, shifting the subsequent lines down if any.
     pub fn new_line(&mut self, location: Point) {
+        self.record(Point::new(0, location.y + 1), Point::new(0, location.y + 1), "\n");
         self.data.insert(location.y + 1, "\n".into());
+        self.changed();
     }
 
     /// Delete a line at the given location, shifting the subsequent lines up if any.
     pub fn delete_line(&mut self, location: Point) -> String {
+        self.record(Point::new(0, location.y), Point::new(0, location.y + 1), "");
+        self.changed();
         self.data.remove(location.y)
     }
 
     /// Split a line in half, inserting the second half as a new line below the first half.
     pub fn split_line(&mut self, location: Point) {
+        self.record(location, location, "\n");
         let line = self.data[location.y].clone();
-        let (left, right) = line.split_at(location.x);
+        let (left, right) = line.split_at(self.byte_index(location));
         self.data[location.y] = String::from(left);
         self.data[location.y].push('\n');
         self.data.insert(location.y + 1, right.into());
+        self.changed();
     }
 
     /// Find the last column that is non empty.
     /// Returns 0 if the whole line is empty.
     pub fn last_non_empty_col(&mut self, location: Point) -> usize {
-        self.line_at(location.y).map_or(0, |s| s.len() - 1)
+        self.line_at(location.y).map_or(0, |s| s.graphemes(true).count().saturating_sub(1))
+    }
+
+    /// Convert a grapheme column into a byte index in the line.
+    /// Columns past the end of the line map to the end of the line.
+    fn byte_index(&self, location: Point) -> usize {
+        let line = &self.data[location.y];
+        line.grapheme_indices(true).nth(location.x).map_or(line.len(), |(idx, _)| idx)
     }
 
     /// Load a file from a path and populate the internal data buffer.
-    /// Unfortunately replaces the original line endings with CRs for now.
-    /// # Panics
-    /// When the file can't be loaded, e.g. if it doesn't exist.
-    pub fn load_file(&mut self, path: String) {
-        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path.clone()).unwrap();
-        let mut s = String::new();
-        let _ = file.read_to_string(&mut s);
-        let mut buf = Vec::new();
+    /// The line endings are detected and converted to `\n`. See `fileformat`.
+    /// A missing final new line and a byte order mark are remembered so that saving
+    /// an unchanged buffer gives back the exact same file. See `endofline` and `bomb`.
+    ///
+    /// A path that doesn't exist gives an empty buffer, without creating the file. See `new_file`.
+    /// Files we can't write to are loaded as `readonly`.
+    ///
+    /// The buffer is left untouched if the file can't be read.
+    pub fn load_file(&mut self, path: String) -> Result<(), Error> {
+        self.load_file_with_encoding(path, None)
+    }
 
-        for line in s.lines() {
-            let mut l = String::from(line);
-            l.push('\n');
-            buf.push(l);
-        }
+    /// Same as `load_file`, but forcing the encoding instead of detecting it.
+    /// Bytes that are invalid in that encoding are replaced with U+FFFD.
+    pub fn load_file_with_encoding(&mut self, path: String, encoding: Option<Encoding>) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        let metadata = match File::open(&path) {
+            Ok(mut file) => { file.read_to_end(&mut bytes)?; file.metadata()? }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.record(Point::new(0, 0), Point::new(0, self.data.len()), "\n");
+                *self = Buffer {
+                    filepath: Some(path),
+                    fileencoding: encoding.unwrap_or(Encoding::Utf8),
+                    new_file: true,
+                    last_change_id: self.last_change_id,
+                    swap: self.swap.take(),
+                    number: self.number,
+                    last_cursor: self.last_cursor,
+                    journal: self.journal.take(),
+                    ..Buffer::new()
+                };
+                self.changed();
+                self.saved_change_id = self.change_id;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let encoding = encoding.unwrap_or_else(|| encoding::detect(&bytes));
+        let s = encoding.decode(&bytes).unwrap_or_else(|| encoding.decode_lossy(&bytes));
+
+        let bomb = s.starts_with(BOM);
+        let text = if bomb { &s[BOM.len()..] } else { &s[..] };
+        let (mut buf, fileformat, mixed) = split_lines(text);
 
         if buf.is_empty() {
             buf.push("\n".into());
         }
 
+        self.record(Point::new(0, 0), Point::new(0, self.data.len()), &buf.concat());
         self.data = buf;
         self.filepath = Some(path);
+        self.fileformat = fileformat;
+        self.mixed_line_endings = mixed;
+        self.endofline = text.ends_with('\n') || text.ends_with('\r');
+        self.bomb = bomb;
+        self.fileencoding = encoding;
+        self.new_file = false;
+        self.changed();
+        self.saved_change_id = self.change_id;
+        self.readonly = file::is_read_only(Path::new(self.filepath.as_ref().unwrap()));
+        self.disk = Some(Stamp::new(&metadata, &bytes));
+        Ok(())
     }
 
     /// Save the buffer at the internal filepath, returning the number of bytes written.
+    /// Lines are terminated according to `fileformat`, except maybe the last one. See `endofline`.
+    /// The text is converted to `fileencoding`. Nothing is written if that conversion fails.
     ///
-    /// Probably doesn't handle UTF-8 very well du to the `char` to `u8` conversion happening.
-    /// # Panics
-    /// When no filepath was given when creating the `Buffer`.
-    pub fn save_file(&mut self) -> u64 {
-        if self.filepath.is_none() { return 0 } // TODO: choose filepath
+    /// The file is replaced atomically, optionally keeping a backup. See `file::write_atomically`.
+    /// A `readonly` buffer is only saved when forced, after which it isn't read-only anymore.
+    pub fn save_file(&mut self, backup: bool, force: bool) -> Result<u64, Error> {
+        let path = match self.filepath.clone() {
+            Some(path) => path,
+            None => return Err(Error::NoFileName),
+        };
+        if self.readonly && !force { return Err(Error::ReadOnly) }
+        if self.check_disk() == DiskChange::Changed && !force { return Err(Error::ChangedOnDisk) }
+
+        let last = self.data.len() - 1;
+        let bytes = self.encode_lines(0, last, self.bomb)?;
+        let written = file::write_atomically(Path::new(&path), &bytes, backup)?;
+        self.new_file = false;
+        self.readonly = false;
+        self.saved_change_id = self.change_id;
+        self.disk = fs::metadata(&path).ok().map(|metadata| Stamp::new(&metadata, &bytes));
+        Ok(written)
+    }
 
-        let path = self.filepath.clone().unwrap();
-        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path).unwrap();
-        for line in &self.data {
-            let _ = file.write_all(line.as_bytes());
+    /// Whether the file was changed by another program since it was last loaded or saved.
+    /// A file that was only touched isn't considered changed.
+    pub fn check_disk(&mut self) -> DiskChange {
+        let (path, stamp) = match (self.filepath.clone(), self.disk) {
+            (Some(path), Some(stamp)) => (path, stamp),
+            _ => return DiskChange::Unchanged,
+        };
+
+        match fs::metadata(&path) {
+            Ok(ref metadata) if stamp.matches(metadata) => DiskChange::Unchanged,
+            Ok(_) => match file::stamp(Path::new(&path)) {
+                Ok(ref new_stamp) if new_stamp.hash == stamp.hash => {
+                    self.disk = Some(*new_stamp);
+                    DiskChange::Unchanged
+                }
+                _ => DiskChange::Changed,
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => DiskChange::Deleted,
+            Err(_) => DiskChange::Changed,
         }
+    }
+
+    /// Write the lines from `start` to `end` included to another file, like `:1,3w path`.
+    /// The buffer's own file and state are left alone.
+    /// With `append`, the lines are added at the end of the file instead of replacing it.
+    pub fn write_lines(&self, path: &Path, start: usize, end: usize, append: bool, backup: bool) -> Result<u64, Error> {
+        if append {
+            let bytes = self.encode_lines(start, end, false)?;
+            let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+            Ok(bytes.len() as u64)
+        } else {
+            let bytes = self.encode_lines(start, end, self.bomb && start == 0)?;
+            Ok(file::write_atomically(path, &bytes, backup)?)
+        }
+    }
+
+    /// The lines from `start` to `end` included, as they are saved. See `save_file`.
+    pub fn encode_lines(&self, start: usize, end: usize, bom: bool) -> Result<Vec<u8>, Error> {
+        let mut text = String::new();
+        if bom {
+            text.push_str(BOM);
+        }
+
+        let last = self.data.len() - 1;
+        for y in start..end + 1 {
+            text.push_str(self.data[y].trim_end_matches('\n'));
+            if y < last || self.endofline {
+                text.push_str(self.fileformat.ending());
+            }
+        }
+
+        self.fileencoding.encode(&text).map_err(Error::Conversion)
+    }
+}
+
+/// Splits a text into `\n` terminated lines, whatever the original line endings were.
+/// Also returns the most common line ending, and whether there were several kinds of them.
+fn split_lines(text: &str) -> (Vec<String>, FileFormat, bool) {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let (mut unix, mut dos, mut mac) = (0, 0, 0);
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => unix += 1,
+            '\r' if chars.peek() == Some(&'\n') => { chars.next(); dos += 1 }
+            '\r' => mac += 1,
+            c => { line.push(c); continue }
+        }
+
+        line.push('\n');
+        lines.push(mem::take(&mut line));
+    }
+
+    if !line.is_empty() {
+        line.push('\n');
+        lines.push(line);
+    }
+
+    let fileformat = if dos > unix && dos >= mac {
+        FileFormat::Dos
+    } else if mac > unix && mac > dos {
+        FileFormat::Mac
+    } else {
+        FileFormat::Unix
+    };
+    let kinds = [unix, dos, mac].iter().filter(|&&count| count > 0).count();
+
+    (lines, fileformat, kinds > 1)
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use buffer::*;
+    use point::Point;
+    use test_dir::TestDir;
+
+    #[test]
+    fn unicode_editing() {
+        let mut buffer = Buffer::new();
+        buffer.data = vec!("héllo 日本\n".into());
+
+        buffer.insert(Point::new(2, 0), 'x');
+        assert_eq!(buffer.data[0], "héxllo 日本\n");
+        assert_eq!(buffer.delete(Point::new(1, 0)), "é");
+        assert_eq!(buffer.grapheme_at(Point::new(7, 0)), Some("本"));
+        assert_eq!(buffer.last_non_empty_col(Point::new(0, 0)), 8);
+
+        buffer.insert(Point::new(1, 0), '\u{301}');
+        assert_eq!(buffer.grapheme_len(0), 9);
+
+        buffer.split_line(Point::new(7, 0));
+        assert_eq!(buffer.data, vec!("h\u{301}xllo 日\n", "本\n"));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut buffer = Buffer::new();
+        buffer.data = vec!("one two\n".into(), "three\n".into());
+
+        assert_eq!(buffer.insert_lines(Point::new(3, 0), " é\nfour"), Point::new(4, 1));
+        assert_eq!(buffer.data, vec!("one é\n", "four two\n", "three\n"));
+        assert_eq!(buffer.insert_lines(Point::new(0, 2), "ø"), Point::new(1, 2));
+        assert_eq!(buffer.text_range(Point::new(4, 0), Point::new(2, 1)), "é\nfo");
+        assert_eq!(buffer.find("t", Point::new(0, 0)), Some(Point::new(5, 1)));
+        assert_eq!(buffer.find("t", Point::new(6, 1)), Some(Point::new(1, 2)));
+        assert_eq!(buffer.find("one", Point::new(1, 0)), None);
+
+        assert_eq!(buffer.delete_range(Point::new(4, 0), Point::new(2, 1)), "é\nfo");
+        assert_eq!(buffer.data, vec!("one ur two\n", "øthree\n"));
+        assert_eq!(buffer.delete_range(Point::new(3, 0), Point::new(6, 0)), " ur");
+        assert_eq!(buffer.data, vec!("one two\n", "øthree\n"));
+    }
+
+    #[test]
+    fn journal() {
+        let mut buffer = Buffer::new();
+        buffer.data = vec!("日本 x\n".into(), "😀 y\n".into());
+        buffer.journal = Some(Vec::new());
+        buffer.insert(Point::new(2, 0), 'é');
+        buffer.delete(Point::new(1, 1));
+        buffer.split_line(Point::new(3, 0));
+        assert_eq!(buffer.journal.take().unwrap(), vec!(
+            Edit { start: (0, 2), end: (0, 2), text: "é".into() },
+            Edit { start: (1, 2), end: (1, 3), text: "".into() },
+            Edit { start: (0, 3), end: (0, 3), text: "\n".into() },
+        ));
+
+        assert_eq!(buffer.data, vec!("日本é\n", " x\n", "😀y\n"));
+        assert_eq!(buffer.lsp_position(Point::new(1, 2)), (2, 2));
+        assert_eq!(buffer.lsp_location((2, 2)), Point::new(1, 2));
+        assert_eq!(buffer.lsp_location((2, 9)), Point::new(2, 2));
+        assert_eq!(buffer.lsp_location((7, 0)), Point::new(3, 2));
+
+        assert_eq!(buffer.lsp_position(Point::new(3, 2)), (3, 0));
+
+        buffer.journal = Some(Vec::new());
+        buffer.replace_range(Point::new(0, 0), Point::new(3, 2), "a\nb\n");
+        assert_eq!(buffer.data, vec!("a\n", "b\n"));
+        buffer.replace_range(Point::new(0, 1), Point::new(2, 1), "c");
+        assert_eq!(buffer.data, vec!("a\n", "c\n"));
+        buffer.delete(Point::new(1, 0));
+        buffer.set_lines(Vec::new());
+        assert_eq!(buffer.journal.take().unwrap(), vec!(
+            Edit { start: (0, 0), end: (3, 0), text: "a\nb\n".into() },
+            Edit { start: (1, 0), end: (2, 0), text: "c\n".into() },
+            Edit { start: (0, 1), end: (1, 0), text: "".into() },
+            Edit { start: (0, 0), end: (2, 0), text: "\n".into() },
+        ));
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(split_lines("a\nb\n"), (vec!("a\n".into(), "b\n".into()), FileFormat::Unix, false));
+        assert_eq!(split_lines("a\r\nb\r\n"), (vec!("a\n".into(), "b\n".into()), FileFormat::Dos, false));
+        assert_eq!(split_lines("a\rb"), (vec!("a\n".into(), "b\n".into()), FileFormat::Mac, false));
+        assert_eq!(split_lines("a\r\nb\r\nc\n").1, FileFormat::Dos);
+        assert!(split_lines("a\r\nb\r\nc\n").2);
+        assert_eq!(split_lines("").0.len(), 0);
+    }
+
+    #[test]
+    fn set_fileformat() {
+        let mut buffer = Buffer::new();
+        assert_eq!(buffer.set_option("ff=dos"), Some(Ok(())));
+        assert_eq!(buffer.fileformat, FileFormat::Dos);
+        assert!(buffer.set_option("fileformat=amiga").unwrap().is_err());
+        assert_eq!(buffer.set_option("number"), None);
+    }
+
+    fn round_trip(name: &str, content: &[u8]) {
+        let dir = TestDir::new(&format!("round-trip-{}", name));
+        let path = dir.join("file");
+        File::create(&path).unwrap().write_all(content).unwrap();
+
+        let mut buffer = Buffer::new();
+        buffer.load_file(path.to_str().unwrap().into()).unwrap();
+        buffer.save_file(false, false).unwrap();
+
+        let mut saved = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut saved).unwrap();
+        assert!(saved == content, "{} changed after saving", name);
+    }
+
+    #[test]
+    fn round_trips() {
+        let long_line = format!("{}\n", "x".repeat(100000));
+
+        round_trip("empty", b"");
+        round_trip("no-final-newline", b"first\nsecond");
+        round_trip("only-newlines", b"\n\n\n");
+        round_trip("bom", "\u{feff}with a bom\n".as_bytes());
+        round_trip("bom-only", "\u{feff}".as_bytes());
+        round_trip("crlf-no-final-newline", b"first\r\nsecond");
+        round_trip("cr", b"first\rsecond\r");
+        round_trip("unicode", "h\u{e9}llo \u{65e5}\u{672c}\n".as_bytes());
+        round_trip("long-line", long_line.as_bytes());
+        round_trip("latin1", b"caf\xe9\r\n");
+        round_trip("utf-16le-bom", b"\xff\xfeh\x00i\x00\n\x00");
+        round_trip("utf-16be", b"\x00h\x00i\x00\n");
+    }
+
+    #[test]
+    fn io_errors() {
+        let dir = TestDir::new("io-errors");
+        let mut buffer = Buffer::new();
+        buffer.data = vec!("unsaved\n".into());
+
+        let error = buffer.load_file(dir.path().to_str().unwrap().into()).unwrap_err();
+        assert_eq!(error.to_string(), "Is a directory");
+        assert_eq!(buffer.data, vec!("unsaved\n"));
+
+        buffer.filepath = Some(dir.path().to_str().unwrap().into());
+        assert_eq!(buffer.save_file(false, false).unwrap_err().to_string(), "Is a directory");
+
+        buffer.fileencoding = Encoding::Latin1;
+        buffer.data = vec!("\u{65e5}\n".into());
+        assert!(buffer.save_file(false, false).is_err());
+    }
+
+    #[test]
+    fn write_lines() {
+        let dir = TestDir::new("write-lines");
+        let path = dir.join("file");
+        let mut buffer = Buffer::new();
+        buffer.data = vec!("a\n".into(), "b\n".into(), "c\n".into());
+        buffer.fileformat = FileFormat::Dos;
+        buffer.endofline = false;
+
+        assert_eq!(buffer.write_lines(&path, 0, 1, false, false).unwrap(), 6);
+        assert_eq!(buffer.write_lines(&path, 2, 2, true, false).unwrap(), 1);
+        let mut content = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"a\r\nb\r\nc");
+        assert_eq!(buffer.filepath, None);
+        assert_eq!(buffer.save_file(false, false).unwrap_err().to_string(), "No file name");
+    }
+
+    #[test]
+    fn modified() {
+        let dir = TestDir::new("modified");
+        let path = dir.join("file");
+        let mut buffer = Buffer::new();
+        buffer.load_file(path.to_str().unwrap().into()).unwrap();
+        assert!(!buffer.modified());
+
+        buffer.insert(Point::new(0, 0), 'a');
+        assert!(buffer.modified());
+        buffer.save_file(false, false).unwrap();
+        assert!(!buffer.modified());
+
+        buffer.set_option("ro").unwrap().unwrap();
+        assert!(!buffer.modified());
+        buffer.set_option("ff=dos").unwrap().unwrap();
+        assert!(buffer.modified());
+
+        // Reloading discards the changes
+        buffer.load_file(path.to_str().unwrap().into()).unwrap();
+        assert!(!buffer.modified());
+    }
+
+    #[test]
+    fn external_changes() {
+        let dir = TestDir::new("external-changes");
+        let path = dir.join("file");
+        File::create(&path).unwrap().write_all(b"ours\n").unwrap();
+        let mut buffer = Buffer::new();
+        buffer.load_file(path.to_str().unwrap().into()).unwrap();
+        assert_eq!(buffer.check_disk(), DiskChange::Unchanged);
+
+        File::create(&path).unwrap().write_all(b"ours\n").unwrap();
+        assert_eq!(buffer.check_disk(), DiskChange::Unchanged);
+
+        File::create(&path).unwrap().write_all(b"theirs\n").unwrap();
+        assert_eq!(buffer.check_disk(), DiskChange::Changed);
+        assert_eq!(buffer.save_file(false, false).unwrap_err().to_string(), "File changed on disk since it was read (add ! to override)");
+        buffer.save_file(false, true).unwrap();
+        assert_eq!(buffer.check_disk(), DiskChange::Unchanged);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(buffer.check_disk(), DiskChange::Deleted);
+    }
+
+    #[test]
+    fn new_file() {
+        let dir = TestDir::new("new-file");
+        let path = dir.join("file");
+        let mut buffer = Buffer::new();
+        buffer.load_file(path.to_str().unwrap().into()).unwrap();
+        assert!(buffer.new_file);
+        assert_eq!(buffer.data, vec!("\n"));
+        assert!(!path.exists());
+
+        buffer.save_file(false, false).unwrap();
+        assert!(!buffer.new_file);
+        assert!(path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new("read-only");
+        let path = dir.join("file");
+        File::create(&path).unwrap().write_all(b"hi\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o444)).unwrap();
 
-        file.metadata().unwrap().len()
+        let mut buffer = Buffer::new();
+        buffer.load_file(path.to_str().unwrap().into()).unwrap();
+        assert!(buffer.readonly);
+        assert_eq!(buffer.save_file(false, false).unwrap_err().to_string(), "File is read-only (add ! to override)");
+        assert_eq!(buffer.save_file(false, true).unwrap(), 3);
+        assert!(!buffer.readonly);
     }
 }
@@ -0,0 +1,107 @@
+/// Lines a command applies to, e.g. `1,3` in `:1,3w part.txt`.
+/// Both ends are included and counted from 0.
+#[derive(PartialEq,Eq,Debug,Copy,Clone)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Split the range at the beginning of a command line from the command itself.
+///
+/// A range is either `%` for the whole buffer, or one or two addresses separated by a comma.
+/// An address is a line number, `.` for the current line or `$` for the last one,
+/// optionally followed by offsets such as `+2` or `-1`. `current` and `last` are counted from 0.
+pub fn parse_range(command: &str, current: usize, last: usize) -> Result<(Option<LineRange>, &str), String> {
+    if let Some(rest) = command.strip_prefix('%') {
+        return Ok((Some(LineRange { start: 0, end: last }), rest));
+    }
+
+    let (start, rest) = match parse_address(command, current, last)? {
+        Some(address) => address,
+        None => return Ok((None, command)),
+    };
+
+    let (end, rest) = if let Some(rest) = rest.strip_prefix(',') {
+        parse_address(rest, current, last)?.ok_or_else(invalid_range)?
+    } else {
+        (start, rest)
+    };
+
+    // Backwards ranges are swapped, Vim asks first.
+    let range = if start <= end { LineRange { start, end } } else { LineRange { start: end, end: start } };
+    Ok((Some(range), rest))
+}
+
+/// The first word of `text`, and the rest, both trimmed. E.g. the left and right-hand sides of `:map`.
+pub fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim())
+}
+
+fn parse_address(text: &str, current: usize, last: usize) -> Result<Option<(usize, &str)>, String> {
+    let (mut line, mut rest) = if let Some(rest) = text.strip_prefix('.') {
+        (current as isize, rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (last as isize, rest)
+    } else if text.starts_with('+') || text.starts_with('-') {
+        (current as isize, text)
+    } else {
+        match parse_number(text) {
+            Some((number, rest)) => (number - 1, rest),
+            None => return Ok(None),
+        }
+    };
+
+    while rest.starts_with('+') || rest.starts_with('-') {
+        let sign = if rest.starts_with('+') { 1 } else { -1 };
+        let (offset, after) = parse_number(&rest[1..]).unwrap_or((1, &rest[1..]));
+        line += sign * offset;
+        rest = after;
+    }
+
+    if line < 0 || line > last as isize {
+        return Err(invalid_range());
+    }
+    Ok(Some((line as usize, rest)))
+}
+
+fn parse_number(text: &str) -> Option<(isize, &str)> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    text[..digits].parse().ok().map(|number| (number, &text[digits..]))
+}
+
+fn invalid_range() -> String {
+    "Invalid range".into()
+}
+
+#[cfg(test)]
+pub mod test {
+    use command::*;
+
+    fn range(start: usize, end: usize) -> Option<LineRange> {
+        Some(LineRange { start, end })
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("w", 4, 9), Ok((None, "w")));
+        assert_eq!(parse_range("%w out", 4, 9), Ok((range(0, 9), "w out")));
+        assert_eq!(parse_range("2,3w out", 4, 9), Ok((range(1, 2), "w out")));
+        assert_eq!(parse_range(".,$w", 4, 9), Ok((range(4, 9), "w")));
+        assert_eq!(parse_range(".-1,.+2w", 4, 9), Ok((range(3, 6), "w")));
+        assert_eq!(parse_range("+,$-", 4, 9), Ok((range(5, 8), "")));
+        assert_eq!(parse_range("5,2w", 4, 9), Ok((range(1, 4), "w")));
+        assert_eq!(parse_range("7", 4, 9), Ok((range(6, 6), "")));
+        assert!(parse_range("11w", 4, 9).is_err());
+        assert!(parse_range("0w", 4, 9).is_err());
+        assert!(parse_range("1,w", 4, 9).is_err());
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(split_word("  jk   <Esc> x "), ("jk", "<Esc> x"));
+        assert_eq!(split_word("jk"), ("jk", ""));
+        assert_eq!(split_word(""), ("", ""));
+    }
+}
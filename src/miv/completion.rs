@@ -0,0 +1,270 @@
+extern crate rustbox;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use rustbox::Key;
+use file;
+use keys::KeyMap;
+use point::Point;
+use state::Action;
+
+/// What is completed, and where the candidates come from.
+#[derive(Eq,PartialEq,Debug,Clone,Copy)]
+pub enum Source {
+    /// Words of the open buffers, started with Ctrl-n or Ctrl-p.
+    Keyword,
+    /// Whole lines of the open buffers, started with Ctrl-x Ctrl-l.
+    Line,
+    /// File names, started with Ctrl-x Ctrl-f.
+    Path,
+    /// Words of the `dictionary` file, started with Ctrl-x Ctrl-k.
+    Dictionary,
+    /// Items of the language server, started with Ctrl-x Ctrl-o. See `lsp`.
+    Server,
+}
+
+/// A completion candidate.
+#[derive(Eq,PartialEq,Debug,Clone)]
+pub struct Candidate {
+    pub text: String,
+    /// Where it comes from, shown next to it in the popup, e.g. a buffer name.
+    pub menu: String,
+}
+
+impl Candidate {
+    pub fn new(text: &str, menu: &str) -> Candidate {
+        Candidate { text: text.into(), menu: menu.into() }
+    }
+}
+
+/// Completion in Insert mode, shown as a popup under the cursor.
+/// The selected candidate replaces the text being completed, which previews it.
+#[derive(Debug,Clone)]
+pub struct Completion {
+    /// Where the completed text starts.
+    pub start: Point,
+    /// The completed text, as typed before completing it.
+    pub prefix: String,
+    /// Candidates matching `prefix`, the best first. See `rank`.
+    pub candidates: Vec<Candidate>,
+    /// Index of the candidate inserted. `None` for the `prefix` itself, when going past the first or last one.
+    pub selected: Option<usize>,
+}
+
+impl Completion {
+    pub fn new(start: Point, prefix: String, candidates: Vec<Candidate>) -> Completion {
+        Completion {
+            start,
+            prefix,
+            candidates,
+            selected: None,
+        }
+    }
+
+    /// Select the next candidate, or the previous one when not `forward`, going through the prefix again
+    /// after the last one.
+    pub fn select(&mut self, forward: bool) {
+        let len = self.candidates.len();
+        self.selected = match (self.selected, forward) {
+            (None, true) if len > 0 => Some(0),
+            (None, false) if len > 0 => Some(len - 1),
+            (Some(idx), true) if idx + 1 < len => Some(idx + 1),
+            (Some(idx), false) if idx > 0 => Some(idx - 1),
+            _ => None,
+        };
+    }
+
+    /// The text that replaces the completed one.
+    pub fn text(&self) -> &str {
+        self.selected.map_or(&self.prefix, |idx| &self.candidates[idx].text)
+    }
+}
+
+/// The keys handled by the completion popup while it's shown, before those of Insert mode.
+pub fn popup_keys() -> KeyMap {
+    let mut km = KeyMap::new();
+    km.bind(&[Key::Tab], Action::SelectCompletion(true));
+    km.bind(&[Key::Esc, Key::Char('['), Key::Char('Z')], Action::SelectCompletion(false)); // S-Tab
+    km.bind(&[Key::Ctrl('n')], Action::SelectCompletion(true));
+    km.bind(&[Key::Ctrl('p')], Action::SelectCompletion(false));
+    km.bind(&[Key::Down], Action::SelectCompletion(true));
+    km.bind(&[Key::Up], Action::SelectCompletion(false));
+    km.bind(&[Key::Enter], Action::AcceptCompletion);
+    km.bind(&[Key::Ctrl('y')], Action::AcceptCompletion);
+    km.bind(&[Key::Ctrl('e')], Action::CancelCompletion);
+    km.bind(&[Key::Esc], Action::Cancel);
+    km
+}
+
+/// How well `text` matches `pattern` typed as an abbreviation of it, e.g. `hk` for `handle_key`.
+/// Lower is better: the texts starting with `pattern` come first, then those matching it in fewer
+/// pieces, earlier. `None` when the characters of `pattern` aren't all found in order.
+/// Case is ignored when `pattern` is all lowercase.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<usize> {
+    let ignore_case = !pattern.chars().any(char::is_uppercase);
+    let fold = |c: char| if ignore_case { c.to_lowercase().next().unwrap_or(c) } else { c };
+    let text: Vec<char> = text.chars().map(&fold).collect();
+    let pattern: Vec<char> = pattern.chars().map(&fold).collect();
+    if text.starts_with(&pattern) {
+        return Some(0);
+    }
+
+    let (mut score, mut next) = (1, 0);
+    for c in pattern {
+        let idx = next + text[next..].iter().position(|&t| t == c)?;
+        if idx > next || next == 0 {
+            // A new piece, counted with how far it starts.
+            score += 100 + idx;
+        }
+        next = idx + 1;
+    }
+    Some(score)
+}
+
+/// The candidates matching `prefix`, the best first, keeping the order of those scoring the same.
+/// The prefix itself is left out.
+pub fn rank(prefix: &str, candidates: Vec<Candidate>) -> Vec<Candidate> {
+    let mut scored: Vec<(usize, Candidate)> = candidates.into_iter()
+        .filter(|candidate| candidate.text != prefix)
+        .filter_map(|candidate| fuzzy_score(prefix, &candidate.text).map(|score| (score, candidate)))
+        .collect();
+    scored.sort_by_key(|&(score, _)| score);
+    scored.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+/// Add the keywords of `text` to `candidates`, in order, skipping those already `seen`.
+pub fn collect_keywords(text: &str, menu: &str, seen: &mut HashSet<String>, candidates: &mut Vec<Candidate>) {
+    for word in text.split(|c: char| !is_keyword_char(c)).filter(|word| !word.is_empty()) {
+        if seen.insert(word.into()) {
+            candidates.push(Candidate::new(word, menu));
+        }
+    }
+}
+
+/// Add the lines of `text` to `candidates` without their indentation, skipping the blank ones
+/// and those already `seen`.
+pub fn collect_lines(text: &str, menu: &str, seen: &mut HashSet<String>, candidates: &mut Vec<Candidate>) {
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if seen.insert(line.into()) {
+            candidates.push(Candidate::new(line, menu));
+        }
+    }
+}
+
+/// The files completing `path`, i.e. those in its directory whose name matches the rest, the best first.
+/// Directories end with `/`. Hidden files are only listed when the name starts with `.`.
+pub fn path_candidates(path: &str) -> Vec<Candidate> {
+    let (dir, name) = match path.rfind('/') {
+        Some(idx) => (&path[..idx + 1], &path[idx + 1..]),
+        None => ("", path),
+    };
+    let entries = match fs::read_dir(if dir.is_empty() { ".".into() } else { file::expand_home(dir) }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut candidates: Vec<(usize, Candidate)> = entries.filter_map(Result::ok)
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.starts_with('.') && !name.starts_with('.') {
+                return None;
+            }
+            let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+            let (suffix, menu) = if is_dir { ("/", "dir") } else { ("", "file") };
+            fuzzy_score(name, &file_name).map(|score| (score, Candidate::new(&format!("{}{}{}", dir, file_name, suffix), menu)))
+        })
+        .collect();
+    candidates.sort_by(|a, b| (a.0, &a.1.text).cmp(&(b.0, &b.1.text)));
+    candidates.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+/// The words of a dictionary file, one or more per line. See `Options::dictionary`.
+pub fn dictionary_words(path: &Path) -> Result<Vec<Candidate>, String> {
+    let content = fs::read(path).map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+    let mut words = Vec::new();
+    for word in String::from_utf8_lossy(&content).split_whitespace() {
+        words.push(Candidate::new(word, "dict"));
+    }
+    Ok(words)
+}
+
+/// Whether a character is part of a keyword, i.e. alphanumeric or `_`.
+pub fn is_keyword_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Whether a character can be part of a file name completed with Ctrl-x Ctrl-f.
+pub fn is_path_char(c: char) -> bool {
+    !c.is_whitespace() && !"\"'`()[]{}<>=,;".contains(c)
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashSet;
+    use std::fs;
+    use completion::*;
+    use point::Point;
+    use test_dir::TestDir;
+
+    fn texts(candidates: &[Candidate]) -> Vec<&str> {
+        candidates.iter().map(|candidate| candidate.text.as_str()).collect()
+    }
+
+    #[test]
+    fn keywords() {
+        let (mut seen, mut words) = (HashSet::new(), Vec::new());
+        collect_keywords("fn handle_key(key: Key) -> bool {\n", "a.rs", &mut seen, &mut words);
+        collect_keywords("let keys = key;\n", "b.rs", &mut seen, &mut words);
+        assert_eq!(texts(&words), vec!("fn", "handle_key", "key", "Key", "bool", "let", "keys"));
+        assert_eq!(words[2].menu, "a.rs");
+        assert_eq!(texts(&rank("ke", words)), vec!("key", "Key", "keys", "handle_key"));
+    }
+
+    #[test]
+    fn lines() {
+        let (mut seen, mut lines) = (HashSet::new(), Vec::new());
+        collect_lines("    let x = 1;\n\n\tlet x = 1;\nreturn x;\n", "", &mut seen, &mut lines);
+        assert_eq!(texts(&lines), vec!("let x = 1;", "return x;"));
+    }
+
+    #[test]
+    fn fuzzy() {
+        assert_eq!(fuzzy_score("ha", "handle_key"), Some(0));
+        assert!(fuzzy_score("hk", "hook").unwrap() < fuzzy_score("hk", "handle_key").unwrap());
+        assert!(fuzzy_score("key", "handle_key") < fuzzy_score("key", "k_e_y"));
+        assert!(fuzzy_score("hk", "HandleKey").is_some());
+        assert_eq!(fuzzy_score("Hk", "handle_key"), None);
+        assert_eq!(fuzzy_score("kh", "handle_key"), None);
+    }
+
+    #[test]
+    fn paths() {
+        let dir = TestDir::new("completion");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("state.rs"), "").unwrap();
+        fs::write(dir.join("swap.rs"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+
+        let base = format!("{}/", dir.path().display());
+        let found = path_candidates(&format!("{}s", base));
+        assert_eq!(texts(&found), vec!(format!("{}src/", base), format!("{}state.rs", base), format!("{}swap.rs", base)));
+        assert_eq!(found[0].menu, "dir");
+        assert_eq!(texts(&path_candidates(&format!("{}sw", base))), vec!(format!("{}swap.rs", base)));
+        assert_eq!(texts(&path_candidates(&format!("{}.h", base))), vec!(format!("{}.hidden", base)));
+    }
+
+    #[test]
+    fn select() {
+        let candidates = vec!(Candidate::new("key", ""), Candidate::new("keys", ""));
+        let mut completion = Completion::new(Point::new(0, 0), "ke".into(), candidates);
+        assert_eq!(completion.text(), "ke");
+        completion.select(true);
+        assert_eq!(completion.text(), "key");
+        completion.select(true);
+        completion.select(true);
+        assert_eq!(completion.text(), "ke");
+        completion.select(false);
+        assert_eq!(completion.text(), "keys");
+    }
+}
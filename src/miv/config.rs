@@ -0,0 +1,65 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Where the configuration file is looked for at startup, in that order:
+/// `$XDG_CONFIG_HOME/miv/mivrc` (`~/.config/miv/mivrc` by default), then `~/.mivrc`.
+pub fn default_paths() -> Vec<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+
+    let mut paths = Vec::new();
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("miv").join("mivrc"));
+    }
+    if let Some(home) = home {
+        paths.push(home.join(".mivrc"));
+    }
+    paths
+}
+
+/// The first configuration file found. See `default_paths`.
+pub fn find() -> Option<PathBuf> {
+    default_paths().into_iter().find(|path| path.is_file())
+}
+
+/// Read the commands of a configuration file. See `parse`.
+pub fn read(path: &Path) -> io::Result<Vec<(usize, String)>> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+    Ok(parse(&content))
+}
+
+/// The commands of a configuration file with their line numbers, counted from 1.
+///
+/// Each line is a command, as typed after `:`. The leading `:` is optional.
+/// Blank lines and lines starting with `"` are ignored.
+pub fn parse(content: &str) -> Vec<(usize, String)> {
+    content.lines().enumerate().filter_map(|(idx, line)| {
+        let command = line.trim().trim_start_matches(':').trim_start();
+        if command.is_empty() || command.starts_with('"') {
+            None
+        } else {
+            Some((idx + 1, command.to_string()))
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use config::*;
+
+    #[test]
+    fn parse_commands() {
+        let content = "\" Settings\nset number\n\n  :set tabstop=4  \r\nimap jk <Esc>\n";
+        assert_eq!(parse(content), vec!(
+            (2, "set number".to_string()),
+            (4, "set tabstop=4".to_string()),
+            (5, "imap jk <Esc>".to_string()),
+        ));
+    }
+}
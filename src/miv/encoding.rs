@@ -0,0 +1,150 @@
+/// Character encodings a file can be read and saved in.
+#[derive(PartialEq,Eq,Debug,Copy,Clone)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// ISO-8859-1. Every byte is a valid character, so this is the fallback when nothing else matches.
+    Latin1,
+}
+
+impl Encoding {
+    /// Name used by `:set fileencoding` and displayed in the bottom bar.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_lowercase().as_ref() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" | "utf-16" | "utf16" => Some(Encoding::Utf16Be),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    /// Decode some bytes. Returns `None` if they aren't valid in this encoding.
+    /// A byte order mark, if any, is kept as U+FEFF.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        match *self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if !bytes.len().is_multiple_of(2) { return None }
+                String::from_utf16(&self.units(bytes)).ok()
+            }
+            Encoding::Latin1 => Some(bytes.iter().map(|&b| b as char).collect()),
+        }
+    }
+
+    /// Decode some bytes, replacing the invalid sequences with U+FFFD.
+    pub fn decode_lossy(&self, bytes: &[u8]) -> String {
+        match *self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let mut text = String::from_utf16_lossy(&self.units(bytes));
+                if !bytes.len().is_multiple_of(2) { text.push('\u{fffd}') }
+                text
+            }
+            Encoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+        }
+    }
+
+    /// Encode a text. Fails on the first character that can't be represented in this encoding.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match *self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(|u| vec!(u as u8, (u >> 8) as u8)).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(|u| vec!((u >> 8) as u8, u as u8)).collect()),
+            Encoding::Latin1 => {
+                text.chars().map(|c| {
+                    if (c as u32) < 0x100 {
+                        Ok(c as u8)
+                    } else {
+                        Err(format!("Can't convert '{}' to {}", c, self.name()))
+                    }
+                }).collect()
+            }
+        }
+    }
+
+    fn units(&self, bytes: &[u8]) -> Vec<u16> {
+        bytes.chunks(2).filter(|pair| pair.len() == 2).map(|pair| {
+            if *self == Encoding::Utf16Le {
+                (pair[1] as u16) << 8 | pair[0] as u16
+            } else {
+                (pair[0] as u16) << 8 | pair[1] as u16
+            }
+        }).collect()
+    }
+}
+
+/// Guess the encoding of a file's content.
+///
+/// A byte order mark wins, then valid UTF-8. UTF-16 without a byte order mark is recognized
+/// by its zero bytes, which are frequent in mostly ASCII text. Anything else is Latin-1.
+pub fn detect(bytes: &[u8]) -> Encoding {
+    if bytes.starts_with(&[0xef, 0xbb, 0xbf]) {
+        return Encoding::Utf8;
+    } else if bytes.starts_with(&[0xff, 0xfe]) {
+        return Encoding::Utf16Le;
+    } else if bytes.starts_with(&[0xfe, 0xff]) {
+        return Encoding::Utf16Be;
+    }
+
+    if ::std::str::from_utf8(bytes).is_ok() && !bytes.contains(&0) {
+        return Encoding::Utf8;
+    }
+
+    if bytes.len().is_multiple_of(2) {
+        let zeros_at = |parity: usize| bytes.iter().skip(parity).step_by(2).filter(|&&b| b == 0).count();
+        let half = bytes.len() / 2;
+        for &(encoding, parity) in &[(Encoding::Utf16Le, 1), (Encoding::Utf16Be, 0)] {
+            if zeros_at(parity) * 2 > half && encoding.decode(bytes).is_some() {
+                return encoding;
+            }
+        }
+    }
+
+    if ::std::str::from_utf8(bytes).is_ok() {
+        Encoding::Utf8
+    } else {
+        Encoding::Latin1
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use encoding::*;
+
+    #[test]
+    fn detection() {
+        assert_eq!(detect(b""), Encoding::Utf8);
+        assert_eq!(detect("h\u{e9}llo".as_bytes()), Encoding::Utf8);
+        assert_eq!(detect(b"\xef\xbb\xbfhi"), Encoding::Utf8);
+        assert_eq!(detect(b"h\xe9llo"), Encoding::Latin1);
+        assert_eq!(detect(b"\xff\xfeh\x00i\x00"), Encoding::Utf16Le);
+        assert_eq!(detect(b"\xfe\xff\x00h\x00i"), Encoding::Utf16Be);
+        assert_eq!(detect(b"h\x00i\x00\n\x00"), Encoding::Utf16Le);
+        assert_eq!(detect(b"\x00h\x00i\x00\n"), Encoding::Utf16Be);
+    }
+
+    #[test]
+    fn conversion() {
+        for &encoding in &[Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be, Encoding::Latin1] {
+            let bytes = encoding.encode("h\u{e9}llo\n").unwrap();
+            assert_eq!(encoding.decode(&bytes), Some("h\u{e9}llo\n".into()));
+        }
+
+        assert_eq!(Encoding::Latin1.encode("h\u{e9}"), Ok(vec!(b'h', 0xe9)));
+        assert!(Encoding::Latin1.encode("\u{65e5}").is_err());
+        assert_eq!(Encoding::Utf16Le.decode(b"h\x00i"), None);
+        assert_eq!(Encoding::Utf16Le.decode_lossy(b"h\x00i"), "h\u{fffd}");
+        assert_eq!(Encoding::from_name("ISO-8859-1"), Some(Encoding::Latin1));
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::SystemTime;
+#[cfg(unix)]
+use libc;
+
+/// Atomically replace the content of the file at `path`, returning the number of bytes written.
+///
+/// The bytes are written and synced to a temporary file in the same directory, which is then
+/// renamed over the original. A crash at any point leaves either the old or the new content,
+/// never a mix of both. The original permissions, ownership and extended attributes are kept
+/// when possible. With `backup` set, a copy of the original is kept next to it, suffixed with `~`.
+///
+/// Symbolic links are followed, and files with several hard links are written in place so that
+/// the links aren't broken. So are files in directories we can't create the temporary file in.
+pub fn write_atomically(path: &Path, bytes: &[u8], backup: bool) -> io::Result<u64> {
+    replace_file(path, bytes, backup, 0o666)
+}
+
+/// Like `write_atomically`, but a new file is only readable and writable by its owner,
+/// e.g. a swap file holding the text of a file other users may not be allowed to read.
+pub fn write_private(path: &Path, bytes: &[u8]) -> io::Result<u64> {
+    replace_file(path, bytes, false, 0o600)
+}
+
+/// See `write_atomically`. A new file gets the permissions `new_mode` minus the umask.
+fn replace_file(path: &Path, bytes: &[u8], backup: bool, new_mode: u32) -> io::Result<u64> {
+    // Replace the target of a symlink rather than the link itself.
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let original = fs::metadata(&path).ok();
+
+    if let Some(ref metadata) = original {
+        if metadata.is_dir() {
+            return Err(io::Error::other("Is a directory"));
+        }
+        if backup {
+            fs::copy(&path, backup_path(&path))?;
+        }
+        if hard_links(metadata) > 1 {
+            return write_in_place(&path, bytes, new_mode);
+        }
+    }
+
+    // The temporary file is never readable by more users than the original, even before its permissions are copied.
+    let mode = original.as_ref().map_or(new_mode, permission_bits);
+    let (tmp_path, mut tmp) = match create_temporary(&path, mode) {
+        Ok(tmp) => tmp,
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => return write_in_place(&path, bytes, new_mode),
+        Err(e) => return Err(e),
+    };
+
+    let metadata = match original {
+        Some(ref metadata) => copy_metadata(&path, &tmp, metadata),
+        None => Ok(()),
+    };
+    let result = metadata
+        .and_then(|_| write_and_sync(&mut tmp, bytes))
+        .and_then(|_| fs::rename(&tmp_path, &path));
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    // Make sure the rename itself hits the disk.
+    if let Some(dir) = path.parent() {
+        let _ = File::open(dir).and_then(|dir| dir.sync_all());
+    }
+
+    Ok(bytes.len() as u64)
+}
+
+/// Whether the file at `path` can't be written, either because it has no write permission at all
+/// or because the current user isn't allowed to write it.
+pub fn is_read_only(path: &Path) -> bool {
+    let no_permission = fs::metadata(path).map(|metadata| metadata.permissions().readonly()).unwrap_or(false);
+    no_permission || OpenOptions::new().write(true).open(path).is_err()
+}
+
+/// What a file looked like when we last read or wrote it, to notice when another program changes it.
+#[derive(PartialEq,Eq,Debug,Copy,Clone)]
+pub struct Stamp {
+    pub modified: Option<SystemTime>,
+    pub len: u64,
+    /// Hash of the content, so that a file that was only touched isn't considered changed.
+    pub hash: u64,
+}
+
+impl Stamp {
+    pub fn new(metadata: &fs::Metadata, bytes: &[u8]) -> Stamp {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(bytes);
+        Stamp { modified: metadata.modified().ok(), len: metadata.len(), hash: hasher.finish() }
+    }
+
+    /// Whether the file still has the same modification time and size.
+    /// If so it's most likely unchanged, without having to read it again.
+    pub fn matches(&self, metadata: &fs::Metadata) -> bool {
+        self.modified == metadata.modified().ok() && self.len == metadata.len()
+    }
+}
+
+/// Read a file to get its `Stamp`.
+pub fn stamp(path: &Path) -> io::Result<Stamp> {
+    let mut bytes = Vec::new();
+    let mut file = File::open(path)?;
+    file.read_to_end(&mut bytes)?;
+    Ok(Stamp::new(&file.metadata()?, &bytes))
+}
+
+/// Absolute path without symbolic links nor `..`, even for a file that doesn't exist yet.
+pub fn canonical(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(dir), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Replace a leading `~/` with the home directory.
+pub fn expand_home(path: &str) -> String {
+    match env::var("HOME") {
+        Ok(ref home) if path.starts_with("~/") => format!("{}{}", home, &path[1..]),
+        _ => path.into(),
+    }
+}
+
+/// Whether two paths point to the same file, e.g. through a symbolic link.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    canonical(a) == canonical(b)
+}
+
+/// Run a shell command with `input` as its standard input, like `:w !cmd`.
+/// Returns its exit status and what it printed on both its standard output and error.
+pub fn pipe_to_command(command: &str, input: Vec<u8>) -> io::Result<(process::ExitStatus, String)> {
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+
+    // Feed the input from another thread so that a command filling its output pipe can't block us.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || { let _ = stdin.write_all(&input); });
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+
+    let mut printed = String::from_utf8_lossy(&output.stdout).into_owned();
+    printed.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok((output.status, printed))
+}
+
+/// Path of the backup copy kept by `write_atomically`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map_or_else(Default::default, |name| name.to_os_string());
+    name.push("~");
+    path.with_file_name(name)
+}
+
+fn write_and_sync(file: &mut File, bytes: &[u8]) -> io::Result<()> {
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// Truncate and rewrite a file. Not atomic, only used when `write_atomically` can't be.
+/// A new file gets the permissions `new_mode` minus the umask.
+fn write_in_place(path: &Path, bytes: &[u8], new_mode: u32) -> io::Result<u64> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    set_mode(&mut options, new_mode);
+    let mut file = options.open(path)?;
+    write_and_sync(&mut file, bytes)?;
+    Ok(bytes.len() as u64)
+}
+
+/// Create a new hidden file next to `path`, e.g. `.file.txt.1234-0.miv`, with the permissions `mode` minus the umask.
+fn create_temporary(path: &Path, mode: u32) -> io::Result<(PathBuf, File)> {
+    let name = path.file_name().map_or_else(Default::default, |name| name.to_string_lossy().into_owned());
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    set_mode(&mut options, mode);
+
+    for attempt in 0.. {
+        let tmp_path = path.with_file_name(format!(".{}.{}-{}.miv", name, process::id(), attempt));
+        match options.open(&tmp_path) {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!()
+}
+
+#[cfg(unix)]
+fn permission_bits(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn permission_bits(_metadata: &fs::Metadata) -> u32 {
+    0o666
+}
+
+#[cfg(unix)]
+fn set_mode(options: &mut OpenOptions, mode: u32) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(mode);
+}
+
+#[cfg(not(unix))]
+fn set_mode(_options: &mut OpenOptions, _mode: u32) {}
+
+#[cfg(unix)]
+fn hard_links(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn hard_links(_metadata: &fs::Metadata) -> u64 {
+    1
+}
+
+/// Give `file` the permissions, owner and extended attributes of the file at `original_path`.
+/// Only the permissions are mandatory, the rest is done on a best effort basis
+/// since e.g. only root can give a file to another user.
+fn copy_metadata(original_path: &Path, file: &File, original: &fs::Metadata) -> io::Result<()> {
+    file.set_permissions(original.permissions())?;
+    copy_owner(file, original);
+    copy_xattrs(original_path, file);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_owner(file: &File, original: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    unsafe { libc::fchown(file.as_raw_fd(), original.uid(), original.gid()) };
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_file: &File, _original: &fs::Metadata) {}
+
+#[cfg(target_os = "linux")]
+fn copy_xattrs(original_path: &Path, file: &File) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let path = match CString::new(original_path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    unsafe {
+        let size = libc::listxattr(path.as_ptr(), ::std::ptr::null_mut(), 0);
+        if size <= 0 { return }
+
+        let mut names = vec!(0u8; size as usize);
+        let size = libc::listxattr(path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len());
+        if size <= 0 { return }
+        names.truncate(size as usize);
+
+        for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+            let name = match CString::new(name) { Ok(name) => name, Err(_) => continue };
+
+            let len = libc::getxattr(path.as_ptr(), name.as_ptr(), ::std::ptr::null_mut(), 0);
+            if len < 0 { continue }
+
+            let mut value = vec!(0u8; len as usize);
+            let len = libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len());
+            if len < 0 { continue }
+
+            libc::fsetxattr(file.as_raw_fd(), name.as_ptr(), value.as_ptr() as *const libc::c_void, len as usize, 0);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_xattrs(_original_path: &Path, _file: &File) {}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use file::*;
+    use test_dir::TestDir;
+
+    fn read(path: &PathBuf) -> Vec<u8> {
+        let mut content = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn truncates_and_backs_up() {
+        let dir = TestDir::new("truncate");
+        let path = dir.join("file");
+        File::create(&path).unwrap().write_all(b"a much longer content\n").unwrap();
+
+        assert_eq!(write_atomically(&path, b"short\n", true).unwrap(), 6);
+        assert_eq!(read(&path), b"short\n");
+        assert_eq!(read(&backup_path(&path)), b"a much longer content\n");
+    }
+
+    #[test]
+    fn canonical_paths() {
+        let dir = TestDir::new("canonical");
+        let real = fs::canonicalize(dir.path()).unwrap();
+        assert_eq!(canonical(&dir.path().join(".").join("missing")), real.join("missing"));
+        assert!(same_file(&dir.join("missing"), &real.join("..").join(real.file_name().unwrap()).join("missing")));
+    }
+
+    #[test]
+    fn pipes_to_command() {
+        let (status, output) = pipe_to_command("tr a-z A-Z; echo oops >&2; exit 3", b"hello\n".to_vec()).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(output, "HELLO\noops\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new("permissions");
+        let path = dir.join("file");
+        File::create(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_atomically(&path, b"content\n", false).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        // Created with them already, before anything is written.
+        let (tmp_path, _) = create_temporary(&path, 0o600).unwrap();
+        assert_eq!(fs::metadata(&tmp_path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+
+/// File types by file extension.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("hpp", "cpp"),
+    ("go", "go"),
+    ("py", "python"),
+    ("rb", "ruby"),
+    ("js", "javascript"),
+    ("ts", "typescript"),
+    ("java", "java"),
+    ("sh", "sh"),
+    ("bash", "sh"),
+    ("html", "html"),
+    ("htm", "html"),
+    ("css", "css"),
+    ("json", "json"),
+    ("toml", "toml"),
+    ("yml", "yaml"),
+    ("yaml", "yaml"),
+    ("md", "markdown"),
+    ("markdown", "markdown"),
+    ("vim", "vim"),
+];
+
+/// File types by file name, for the files without a meaningful extension.
+const NAMES: &[(&str, &str)] = &[
+    ("Makefile", "make"),
+    ("makefile", "make"),
+    ("mivrc", "vim"),
+    (".mivrc", "vim"),
+    (".vimrc", "vim"),
+];
+
+/// The language of the file at `path`, guessed from its name, e.g. `rust` for `main.rs`.
+/// Empty when unknown, like Vim's `filetype` option.
+pub fn detect(path: &str) -> String {
+    let path = Path::new(path);
+    let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    if let Some(&(_, filetype)) = NAMES.iter().find(|&&(known, _)| known == name) {
+        return filetype.into();
+    }
+
+    let extension = path.extension().map_or(String::new(), |extension| extension.to_string_lossy().to_lowercase());
+    EXTENSIONS.iter()
+        .find(|&&(known, _)| known == extension)
+        .map_or(String::new(), |&(_, filetype)| filetype.into())
+}
+
+#[cfg(test)]
+pub mod test {
+    use filetype::*;
+
+    #[test]
+    fn detect_filetype() {
+        assert_eq!(detect("src/main.rs"), "rust");
+        assert_eq!(detect("/tmp/README.MD"), "markdown");
+        assert_eq!(detect("Makefile"), "make");
+        assert_eq!(detect("/home/me/.mivrc"), "vim");
+        assert_eq!(detect("notes"), "");
+        assert_eq!(detect("archive.tar.xz"), "");
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A JSON value, as exchanged with language servers. See `lsp`.
+/// Objects keep their keys in order.
+#[derive(PartialEq,Debug,Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+static NULL: Json = Json::Null;
+
+impl Json {
+    /// Parse a JSON document.
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: text.chars().peekable() };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected {:?} after JSON value", c)),
+        }
+    }
+
+    /// The value of a key of an object, `Null` when there's no such key or this isn't an object.
+    pub fn get(&self, key: &str) -> &Json {
+        match *self {
+            Json::Object(ref pairs) => pairs.iter().find(|(known, _)| known == key).map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+
+    /// The items of an array, none when this isn't an array.
+    pub fn items(&self) -> &[Json] {
+        match *self {
+            Json::Array(ref items) => items,
+            _ => &[],
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value of a number that is a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+}
+
+/// Make an object from its keys and values, e.g. `object(vec!(("line", 3.into())))`.
+pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+    Json::Object(pairs.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json { Json::String(s.into()) }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json { Json::String(s) }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json { Json::Bool(b) }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json { Json::Number(n as f64) }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json { Json::Number(n as f64) }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Json { Json::Array(items) }
+}
+
+/// Serialize on a single line, e.g. `{"id":1,"method":"shutdown"}`.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 { write!(f, ",")? }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref pairs) => {
+                write!(f, "{{")?;
+                for (idx, (key, value)) in pairs.iter().enumerate() {
+                    if idx > 0 { write!(f, ",")? }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('n') => self.literal("null", Json::Null),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("Unexpected {:?} in JSON", c)),
+            None => Err("Unexpected end of JSON".into()),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("Expected {:?} in JSON, found {:?}", expected, c)),
+            None => Err("Unexpected end of JSON".into()),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for expected in word.chars() {
+            if self.chars.next() != Some(expected) {
+                return Err(format!("Invalid JSON literal, expected {}", word));
+            }
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut text = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_digit() || "+-.eE".contains(c)) { break }
+            text.push(c);
+            self.chars.next();
+        }
+        text.parse().map(Json::Number).map_err(|_| format!("Invalid JSON number: {}", text))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => s.push(self.unicode_escape()?),
+                    Some(c) => s.push(c),
+                    None => return Err("Unterminated JSON string".into()),
+                },
+                Some(c) => s.push(c),
+                None => return Err("Unterminated JSON string".into()),
+            }
+        }
+    }
+
+    /// The character of a `\uXXXX` escape, reading the second half of a surrogate pair if needed.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        if !(0xd800..=0xdbff).contains(&high) {
+            return Ok(::std::char::from_u32(high).unwrap_or('\u{fffd}'));
+        }
+        if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+            return Ok('\u{fffd}');
+        }
+        let low = self.hex4()?;
+        let c = 0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+        Ok(::std::char::from_u32(c).unwrap_or('\u{fffd}'))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits: String = self.chars.by_ref().take(4).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| format!("Invalid JSON escape: \\u{}", digits))
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err("Expected , or ] in JSON array".into()),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut pairs = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Json::Object(pairs));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            pairs.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(pairs)),
+                _ => return Err("Expected , or } in JSON object".into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use json::*;
+
+    #[test]
+    fn parse() {
+        let json = Json::parse(r#" {"id": 3, "result": [null, true, -1.5e2, "a\"é😀\n"], "empty": {}} "#).unwrap();
+        assert_eq!(json.get("id").as_u64(), Some(3));
+        assert_eq!(json.get("result").items(), &[Json::Null, Json::Bool(true), Json::Number(-150.0), "a\"é😀\n".into()]);
+        assert_eq!(json.get("empty"), &Json::Object(Vec::new()));
+        assert!(json.get("missing").get("deeper").is_null());
+
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("\"open").is_err());
+        assert!(Json::parse("1 2").is_err());
+    }
+
+    #[test]
+    fn serialize() {
+        let json = object(vec!(
+            ("method", "textDocument/hover".into()),
+            ("id", 7usize.into()),
+            ("params", vec!(Json::Null, false.into(), Json::Number(0.5), "tab\there \"quoted\"\u{1}".into()).into()),
+        ));
+        let text = json.to_string();
+        assert_eq!(text, r#"{"method":"textDocument/hover","id":7,"params":[null,false,0.5,"tab\there \"quoted\"\u0001"]}"#);
+        assert_eq!(Json::parse(&text), Ok(json));
+    }
+}
@@ -1,16 +1,20 @@
 extern crate rustbox;
 use rustbox::Key;
 use std::collections::HashMap;
-use std::mem;
 
+use notation;
 use state::Action;
 use state::Action::*;
 use point::Direction::*;
 
-#[derive(PartialEq,Eq,Debug)]
-pub enum KeyMap {
-    Node(HashMap<Key, KeyMap>),
-    Action(Action),
+/// A tree of key bindings. Each node can both run an action and be the prefix of longer bindings,
+/// like `g` and `gg`. See `KeyMatch::Ambiguous`.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct KeyMap {
+    action: Option<Action>,
+    children: HashMap<Key, KeyMap>,
+    /// What the keys leading here do, shown by the which-key popup. See `describe`.
+    description: Option<String>,
 }
 
 #[derive(PartialEq,Eq,Debug)]
@@ -18,61 +22,148 @@ pub enum KeyMatch {
     None,
     Partial,
     Action(Action),
+    /// The keys are bound to an action, but are also the beginning of longer bindings.
+    /// Which one is meant is known from the next key, or after `timeoutlen`.
+    Ambiguous(Action),
 }
 
 impl KeyMap {
     pub fn new() -> KeyMap {
-        KeyMap::Node(HashMap::new())
+        KeyMap { action: None, children: HashMap::new(), description: None }
     }
 
+    /// Bind keys to an action, replacing their previous action if any.
+    /// Longer and shorter bindings sharing the same keys are kept.
     pub fn bind(&mut self, keys: &[Key], action: Action) {
-        let new_map = match mem::replace(self, KeyMap::new()) {
-            KeyMap::Node(mut keymap) => {
-                if !keys.is_empty() {
-                    let (k, ks) = keys.split_first().unwrap();
-                    let mut km = keymap.remove(k).unwrap_or_else(KeyMap::new);
-                    km.bind(ks, action);
-                    keymap.insert(*k, km);
-                    KeyMap::Node(keymap)
-                } else if !keymap.is_empty() {
-                    KeyMap::Node(keymap)
-                } else {
-                    KeyMap::Action(action)
-                }
-            }
+        match keys.split_first() {
+            Some((key, rest)) => self.children.entry(*key).or_insert_with(KeyMap::new).bind(rest, action),
+            None => self.action = Some(action),
+        }
+    }
 
-            KeyMap::Action(_) => {
-                if keys.len() > 0 {
-                    let mut km = KeyMap::new();
-                    km.bind(keys, action);
-                    km
-                } else {
-                    KeyMap::Action(action)
-                }
-            }
+    /// Match typed keys against the bindings.
+    /// When they go past a binding without matching a longer one, that binding is the match.
+    pub fn match_keys(&self, keys: &[Key]) -> KeyMatch {
+        let (key, rest) = match keys.split_first() {
+            Some(split) => split,
+            None => return KeyMatch::Partial,
+        };
+
+        let child = match self.children.get(key) {
+            Some(child) => child,
+            None => return KeyMatch::None,
         };
 
-        *self = new_map;
+        if !rest.is_empty() {
+            return match (child.match_keys(rest), &child.action) {
+                (KeyMatch::None, Some(action)) => KeyMatch::Action(action.clone()),
+                (matched, _) => matched,
+            };
+        }
+
+        match child.action {
+            Some(ref action) if child.children.is_empty() => KeyMatch::Action(action.clone()),
+            Some(ref action) => KeyMatch::Ambiguous(action.clone()),
+            None => KeyMatch::Partial,
+        }
+    }
+
+    /// The action to run when no more keys were typed after `keys` for `timeoutlen`.
+    pub fn timed_out(&self, keys: &[Key]) -> Option<Action> {
+        match self.match_keys(keys) {
+            KeyMatch::Action(action) | KeyMatch::Ambiguous(action) => Some(action),
+            _ => None,
+        }
     }
 
-    pub fn match_keys(&self, keys: &[Key]) -> KeyMatch {
-        match *self {
-            KeyMap::Node(ref km) => {
-                match keys.len() {
-                    0 => KeyMatch::Partial,
-                    1 => match km.get(&keys[0]) {
-                        None => KeyMatch::None,
-                        Some(&KeyMap::Action(ref action)) => KeyMatch::Action(action.clone()),
-                        Some(&KeyMap::Node(_)) => KeyMatch::Partial,
-                    },
-                    _ => match km.get(&keys[0]) {
-                        None => KeyMatch::None,
-                        Some(&KeyMap::Action(ref action)) => KeyMatch::Action(action.clone()),
-                        Some(k @ &KeyMap::Node(_)) => k.match_keys(&keys[1..])
-                    }
-                }
+    /// Number of keys making up the binding matched by `match_keys`, the longest one they start with.
+    pub fn bound_len(&self, keys: &[Key]) -> Option<usize> {
+        let (key, rest) = keys.split_first()?;
+        let child = self.children.get(key)?;
+        child.bound_len(rest).map(|len| len + 1).or_else(|| child.action.as_ref().map(|_| 1))
+    }
+
+    /// Describe what some keys do, e.g. `+buffers` for a group of bindings.
+    /// Bindings are described by their action otherwise. See `Action::description`.
+    pub fn describe(&mut self, keys: &[Key], description: &str) {
+        match keys.split_first() {
+            Some((key, rest)) => self.children.entry(*key).or_insert_with(KeyMap::new).describe(rest, description),
+            None => self.description = Some(description.into()),
+        }
+    }
+
+    /// The keys that can follow `keys`, with what they do. Shown by the which-key popup.
+    pub fn continuations(&self, keys: &[Key]) -> Vec<(Key, String)> {
+        let node = match self.node(keys) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+
+        let mut continuations: Vec<(Key, String)> = node.children.iter()
+            .filter(|&(_, child)| !child.is_empty())
+            .map(|(key, child)| (*key, child.summary()))
+            .collect();
+        continuations.sort_by_key(|&(key, _)| notation::format_keys(&[key]));
+        continuations
+    }
+
+    fn node(&self, keys: &[Key]) -> Option<&KeyMap> {
+        match keys.split_first() {
+            Some((key, rest)) => self.children.get(key).and_then(|child| child.node(rest)),
+            None => Some(self),
+        }
+    }
+
+    /// Description of the keys leading to this node. Groups of bindings are prefixed with `+`.
+    fn summary(&self) -> String {
+        match (&self.description, &self.action) {
+            (Some(description), _) => description.clone(),
+            (None, Some(action)) => action.description(),
+            (None, None) => "+prefix".into(),
+        }
+    }
+
+    /// Remove the binding of exactly `keys`. Returns whether there was one.
+    pub fn unbind(&mut self, keys: &[Key]) -> bool {
+        let (key, rest) = match keys.split_first() {
+            Some(split) => split,
+            None => return self.action.take().is_some(),
+        };
+
+        let (found, prune) = match self.children.get_mut(key) {
+            Some(child) => {
+                let found = child.unbind(rest);
+                (found, child.is_empty())
             }
-            KeyMap::Action(ref action) => KeyMatch::Action(action.clone()),
+            None => (false, false),
+        };
+        if prune {
+            self.children.remove(key);
+        }
+        found
+    }
+
+    /// Whether there are no bindings at all.
+    pub fn is_empty(&self) -> bool {
+        self.action.is_none() && self.children.values().all(KeyMap::is_empty)
+    }
+
+    /// All the bindings, sorted by keys.
+    pub fn bindings(&self) -> Vec<(Vec<Key>, Action)> {
+        let mut bindings = Vec::new();
+        self.collect_bindings(&mut Vec::new(), &mut bindings);
+        bindings.sort_by_key(|(keys, _)| notation::format_keys(keys));
+        bindings
+    }
+
+    fn collect_bindings(&self, prefix: &mut Vec<Key>, bindings: &mut Vec<(Vec<Key>, Action)>) {
+        if let Some(ref action) = self.action {
+            bindings.push((prefix.clone(), action.clone()));
+        }
+        for (key, child) in &self.children {
+            prefix.push(*key);
+            child.collect_bindings(prefix, bindings);
+            prefix.pop();
         }
     }
 
@@ -84,15 +175,7 @@ impl KeyMap {
         self.bind(&[Key::Right], MoveCursor(Right));
         self.bind(&[Key::Ctrl('c')], Quit);
         self.bind(&[Key::Ctrl('x'), Key::Ctrl('c')], Quit); // Yay Emacs!
-    }
-}
-
-pub fn key_to_string(key: Key) -> Option<String> {
-    match key {
-        Key::Char(' ') => Some("SPC-".into()),
-        Key::Char(c) => Some(format!("{}-", c)),
-        Key::Ctrl(c) => Some(format!("C-{}-", c)),
-        _ => None,
+        self.describe(&[Key::Ctrl('x')], "+emacs");
     }
 }
 
@@ -119,4 +202,72 @@ pub mod test {
         assert_eq!(km.match_keys(&[Key::Char('d')]), KeyMatch::Partial);
         assert_eq!(km.match_keys(&[Key::Char('d'), Key::Char('d')]), KeyMatch::Action(Delete));
     }
+
+    #[test]
+    fn ambiguous() {
+        let mut km = KeyMap::new();
+        km.bind(&[Key::Char('g'), Key::Char('g')], Quit);
+        km.bind(&[Key::Char('g')], Delete);
+        assert_eq!(km.match_keys(&[Key::Char('g')]), KeyMatch::Ambiguous(Delete));
+        assert_eq!(km.match_keys(&[Key::Char('g'), Key::Char('g')]), KeyMatch::Action(Quit));
+        assert_eq!(km.match_keys(&[Key::Char('g'), Key::Char('x')]), KeyMatch::Action(Delete));
+        assert_eq!(km.timed_out(&[Key::Char('g')]), Some(Delete));
+        assert_eq!(km.bound_len(&[Key::Char('g'), Key::Char('g')]), Some(2));
+        assert_eq!(km.bound_len(&[Key::Char('g'), Key::Char('x')]), Some(1));
+
+        // Shorter bindings aren't dropped either.
+        km.bind(&[Key::Char('d')], Delete);
+        km.bind(&[Key::Char('d'), Key::Char('d')], DeleteLine);
+        assert_eq!(km.match_keys(&[Key::Char('d')]), KeyMatch::Ambiguous(Delete));
+        assert_eq!(km.match_keys(&[Key::Char('d'), Key::Char('d')]), KeyMatch::Action(DeleteLine));
+
+        assert!(km.unbind(&[Key::Char('g')]));
+        assert_eq!(km.match_keys(&[Key::Char('g')]), KeyMatch::Partial);
+        assert_eq!(km.timed_out(&[Key::Char('g')]), None);
+    }
+
+    #[test]
+    fn continuations() {
+        let mut km = KeyMap::new();
+        km.bind(&[Key::Char(' '), Key::Char('b'), Key::Char('n')], NextBuffer);
+        km.bind(&[Key::Char(' '), Key::Char('b'), Key::Char('p')], PrevBuffer);
+        km.bind(&[Key::Char(' '), Key::Char('q')], Quit);
+        km.bind(&[Key::Char(' '), Key::Char('f'), Key::Char('s')], Save);
+        km.describe(&[Key::Char(' '), Key::Char('b')], "+buffers");
+        km.describe(&[Key::Char(' '), Key::Char('q')], "quit miv");
+
+        assert_eq!(km.continuations(&[Key::Char(' ')]), vec!(
+            (Key::Char('b'), "+buffers".to_string()),
+            (Key::Char('f'), "+prefix".to_string()),
+            (Key::Char('q'), "quit miv".to_string()),
+        ));
+        assert_eq!(km.continuations(&[Key::Char(' '), Key::Char('b')]), vec!(
+            (Key::Char('n'), "next buffer".to_string()),
+            (Key::Char('p'), "previous buffer".to_string()),
+        ));
+        assert!(km.continuations(&[Key::Char('x')]).is_empty());
+    }
+
+    #[test]
+    fn unbind() {
+        let mut km = KeyMap::new();
+        km.bind(&[Key::Char('q')], Quit);
+        km.bind(&[Key::Char('d'), Key::Char('d')], Delete);
+        km.bind(&[Key::Char('d'), Key::Char('w')], Delete);
+        assert_eq!(km.bindings(), vec!(
+            (vec!(Key::Char('d'), Key::Char('d')), Delete),
+            (vec!(Key::Char('d'), Key::Char('w')), Delete),
+            (vec!(Key::Char('q')), Quit),
+        ));
+
+        assert!(!km.unbind(&[Key::Char('d')]));
+        assert!(!km.unbind(&[]));
+        assert!(!km.unbind(&[Key::Char('q'), Key::Char('q')]));
+        assert!(km.unbind(&[Key::Char('d'), Key::Char('d')]));
+        assert_eq!(km.match_keys(&[Key::Char('d'), Key::Char('d')]), KeyMatch::None);
+        assert!(km.unbind(&[Key::Char('d'), Key::Char('w')]));
+        assert_eq!(km.match_keys(&[Key::Char('d')]), KeyMatch::None);
+        assert!(km.unbind(&[Key::Char('q')]));
+        assert!(km.is_empty());
+    }
 }
@@ -0,0 +1,179 @@
+use std::cmp;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use options::Options;
+
+/// Minimum number of digits in the line number gutter, like Vim's default `numberwidth`.
+const GUTTER_MIN_DIGITS: usize = 3;
+
+/// Width of the line number gutter, including the separating space. 0 when hidden.
+/// Grows with the number of lines in the buffer.
+pub fn gutter_width(options: &Options, line_count: usize) -> usize {
+    if !options.number && !options.relativenumber {
+        return 0;
+    }
+
+    let digits = line_count.to_string().len();
+    cmp::max(digits, GUTTER_MIN_DIGITS) + 1
+}
+
+/// Number of screen columns available for the text, i.e. the window width minus the gutter.
+pub fn text_width(options: &Options, width: usize, line_count: usize) -> usize {
+    width.saturating_sub(gutter_width(options, line_count))
+}
+
+/// Number of screen columns taken by `showbreak` at the beginning of continuation rows.
+pub fn showbreak_width(options: &Options) -> usize {
+    UnicodeWidthStr::width(options.showbreak.as_str())
+}
+
+/// Number of terminal cells taken by a grapheme cluster.
+///
+/// Wide characters (CJK, emoji) take two cells. Combining marks are part of the
+/// grapheme they apply to, so a grapheme never takes less than one cell.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    cmp::max(UnicodeWidthStr::width(grapheme), 1)
+}
+
+/// Width in cells of every grapheme of a line.
+/// Tabs extend to the next multiple of `tabstop`.
+pub fn cell_widths(line: &str, tabstop: usize) -> Vec<usize> {
+    let mut col = 0;
+    line.graphemes(true).map(|grapheme| {
+        let width = if grapheme == "\t" { tabstop - col % tabstop } else { grapheme_width(grapheme) };
+        col += width;
+        width
+    }).collect()
+}
+
+/// Whitespace filling the screen columns from `from` to `to`.
+/// Made of spaces with `expandtab`, otherwise of as many tabs as possible.
+pub fn indent_string(options: &Options, from: usize, to: usize) -> String {
+    let mut indent = String::new();
+    let mut col = from;
+
+    if !options.expandtab {
+        while col + options.tabstop - col % options.tabstop <= to {
+            indent.push('\t');
+            col += options.tabstop - col % options.tabstop;
+        }
+    }
+
+    while col < to {
+        indent.push(' ');
+        col += 1;
+    }
+
+    indent
+}
+
+/// Screen column of the grapheme `x`, relative to the grapheme `start`.
+pub fn display_col(widths: &[usize], start: usize, x: usize) -> usize {
+    let x = cmp::min(x, widths.len());
+    widths[cmp::min(start, x)..x].iter().sum()
+}
+
+/// The grapheme covering the screen column `col`, relative to the grapheme `start`.
+/// Returns the last grapheme if the line is too short.
+pub fn grapheme_at_col(widths: &[usize], start: usize, col: usize) -> usize {
+    let mut used = 0;
+    for (x, width) in widths.iter().enumerate().skip(start) {
+        used += width;
+        if used > col { return x }
+    }
+    widths.len().saturating_sub(1)
+}
+
+/// Splits a line into screen rows fitting in `width` columns.
+/// Returns the grapheme at which each row starts. The first one is always 0.
+///
+/// Continuation rows leave room for `showbreak`. With `linebreak` set, rows are broken
+/// after the last whitespace that fits instead of in the middle of a word.
+pub fn wrap_line(line: &str, width: usize, options: &Options) -> Vec<usize> {
+    let graphemes: Vec<&str> = line.graphemes(true).collect();
+    let widths = cell_widths(line, options.tabstop);
+    // The line ending takes no room, a line exactly as wide as the window fits in one row.
+    let len = if graphemes.last() == Some(&"\n") { widths.len() - 1 } else { widths.len() };
+    let mut starts = vec!(0);
+    let mut start = 0;
+
+    loop {
+        let room = if start == 0 { width } else { width.saturating_sub(showbreak_width(options)) };
+
+        let mut end = start;
+        let mut used = 0;
+        while end < len && used + widths[end] <= room {
+            used += widths[end];
+            end += 1;
+        }
+
+        if end == len { break }
+        // Always make progress, even if a single wide character doesn't fit.
+        if end == start { end += 1 }
+
+        if options.linebreak {
+            let whitespace = |idx: &usize| graphemes[*idx - 1].chars().all(char::is_whitespace);
+            if let Some(idx) = (start + 1..end + 1).rev().find(whitespace) {
+                end = idx;
+            }
+        }
+
+        starts.push(end);
+        start = end;
+    }
+
+    starts
+}
+
+/// Index of the row containing the grapheme `x`, given the row starts returned by `wrap_line`.
+pub fn row_at(starts: &[usize], x: usize) -> usize {
+    starts.iter().rposition(|&start| start <= x).unwrap_or(0)
+}
+
+#[cfg(test)]
+pub mod test {
+    use layout::*;
+    use options::Options;
+
+    #[test]
+    fn wrap() {
+        let mut options = Options::new();
+        assert_eq!(wrap_line("hello\n", 10, &options), vec!(0));
+        assert_eq!(wrap_line("hello\n", 5, &options), vec!(0));
+        assert_eq!(wrap_line("hello world\n", 5, &options), vec!(0, 5, 10));
+
+        options.linebreak = true;
+        assert_eq!(wrap_line("hello world\n", 8, &options), vec!(0, 6));
+
+        options.showbreak = ">>".into();
+        assert_eq!(wrap_line("abcdefgh\n", 4, &options), vec!(0, 4, 6));
+        assert_eq!(row_at(&[0, 4, 6, 8], 5), 1);
+        assert_eq!(row_at(&[0, 4, 6, 8], 8), 3);
+    }
+
+    #[test]
+    fn wide_characters() {
+        let options = Options::new();
+        assert_eq!(cell_widths("a日e\u{301}\n", 8), vec!(1, 2, 1, 1));
+        assert_eq!(wrap_line("日本語\n", 5, &options), vec!(0, 2));
+
+        let widths = cell_widths("日本語\n", 8);
+        assert_eq!(display_col(&widths, 0, 2), 4);
+        assert_eq!(display_col(&widths, 1, 2), 2);
+        assert_eq!(grapheme_at_col(&widths, 0, 3), 1);
+        assert_eq!(grapheme_at_col(&widths, 0, 42), 3);
+    }
+
+    #[test]
+    fn tabs() {
+        let mut options = Options::new();
+        options.tabstop = 4;
+        assert_eq!(cell_widths("\tab\tc\n", 4), vec!(4, 1, 1, 2, 1, 1));
+        assert_eq!(indent_string(&options, 0, 10), "\t\t  ");
+        assert_eq!(indent_string(&options, 2, 5), "\t ");
+
+        options.expandtab = true;
+        assert_eq!(indent_string(&options, 1, 4), "   ");
+    }
+}
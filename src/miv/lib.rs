@@ -1,10 +1,30 @@
 #![feature(advanced_slice_patterns, slice_patterns)]
 
+extern crate libc;
 extern crate rustbox;
+extern crate unicode_normalization;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 
+pub mod autocmd;
 pub mod buffer;
+pub mod command;
+pub mod completion;
+pub mod config;
+pub mod encoding;
+pub mod file;
+pub mod filetype;
+pub mod json;
 pub mod keys;
+pub mod layout;
+pub mod lsp;
 pub mod mode;
+pub mod notation;
+pub mod options;
 pub mod point;
 pub mod state;
+pub mod swap;
+#[cfg(test)]
+pub mod test_dir;
+pub mod theme;
 pub mod view;
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{self, Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use buffer::{Buffer, Edit};
+use completion::Candidate;
+use file;
+use json::{Json, object};
+use point::Point;
+
+/// What a request was sent for, i.e. what to do with its response. See `Client::request`.
+#[derive(PartialEq,Debug,Clone)]
+pub enum Request {
+    Initialize,
+    Shutdown,
+    Definition,
+    References,
+    Hover,
+    /// Renaming in the document with this URI, as it was at this `Buffer::change_id`.
+    Rename(String, usize),
+    /// Listing the code actions of the document with this URI, as it was at this `Buffer::change_id`.
+    CodeAction(String, usize),
+    ExecuteCommand,
+    /// Formatting the document with this URI, as it was at this `Buffer::change_id`.
+    Formatting(String, usize),
+    /// Completing the text before a location of the document with this URI.
+    Completion(String, Point),
+}
+
+/// A message of the server. See `Client::poll`.
+#[derive(PartialEq,Debug,Clone)]
+pub enum Message {
+    /// The response to one of our requests: its result, or the error message.
+    Response(Request, Result<Json, String>),
+    /// A request of the server, with its id, method and parameters. Answered with `Client::respond`.
+    Request(Json, String, Json),
+    /// A notification of the server, e.g. `window/showMessage`, with its parameters.
+    Notification(String, Json),
+}
+
+/// A language server, started for the files of a type and speaking JSON-RPC on its standard input and output.
+///
+/// Messages are read by a thread and handled with `poll`, so that the editor never waits for the server.
+/// Those sent before the server answered `initialize` are queued until then.
+pub struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<Json>,
+    next_id: u64,
+    /// Requests waiting for a response, by id.
+    pending: HashMap<u64, Request>,
+    /// Messages to send once the server is initialized.
+    queue: Vec<Json>,
+    initialized: bool,
+    /// What the server supports, from its response to `initialize`.
+    pub capabilities: Json,
+    /// Versions of the open documents, by URI. Each change gives a new one.
+    versions: HashMap<String, u64>,
+}
+
+impl Client {
+    /// Start a server with a shell command, e.g. `rust-analyzer`, for the project in `root`.
+    pub fn start(command: &str, root: &Path) -> io::Result<Client> {
+        let mut child = Command::new("sh").arg("-c").arg(command)
+            .current_dir(root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            loop {
+                match read_message(&mut reader) {
+                    Ok(Some(message)) => if sender.send(message).is_err() { break },
+                    Err(ref error) if error.kind() == io::ErrorKind::InvalidData => continue,
+                    _ => break,
+                }
+            }
+        });
+
+        let mut client = Client {
+            child,
+            stdin,
+            messages: receiver,
+            next_id: 1,
+            pending: HashMap::new(),
+            queue: Vec::new(),
+            initialized: false,
+            capabilities: Json::Null,
+            versions: HashMap::new(),
+        };
+        let params = object(vec!(
+            ("processId", (process::id() as u64).into()),
+            ("rootUri", path_to_uri(root).into()),
+            ("capabilities", capabilities()),
+            ("clientInfo", object(vec!(("name", "miv".into())))),
+        ));
+        client.pending.insert(0, Request::Initialize);
+        write_message(&mut client.stdin, &message(Some(0), "initialize", params))?;
+        Ok(client)
+    }
+
+    /// Send a request. Its response comes back with `request` from `poll`.
+    pub fn request(&mut self, method: &str, params: Json, request: Request) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, request);
+        self.send(message(Some(id), method, params));
+        id
+    }
+
+    pub fn notify(&mut self, method: &str, params: Json) {
+        self.send(message(None, method, params));
+    }
+
+    /// Answer a request of the server with its result or an error message.
+    pub fn respond(&mut self, id: Json, result: Result<Json, String>) {
+        let outcome = match result {
+            Ok(result) => ("result", result),
+            Err(message) => ("error", object(vec!(("code", Json::Number(-32601.0)), ("message", message.into())))),
+        };
+        self.send(object(vec!(("jsonrpc", "2.0".into()), ("id", id), outcome)));
+    }
+
+    fn send(&mut self, message: Json) {
+        if self.initialized {
+            // A server that stopped is noticed by `poll`.
+            let _ = write_message(&mut self.stdin, &message);
+        } else {
+            self.queue.push(message);
+        }
+    }
+
+    /// Whether a document was opened with `did_open`.
+    pub fn is_open(&self, uri: &str) -> bool {
+        self.versions.contains_key(uri)
+    }
+
+    /// The version of an open document, as last sent to the server.
+    pub fn version(&self, uri: &str) -> Option<u64> {
+        self.versions.get(uri).cloned()
+    }
+
+    pub fn did_open(&mut self, uri: &str, language: &str, text: &str) {
+        self.versions.insert(uri.into(), 1);
+        let document = object(vec!(
+            ("uri", uri.into()),
+            ("languageId", language.into()),
+            ("version", 1usize.into()),
+            ("text", text.into()),
+        ));
+        self.notify("textDocument/didOpen", object(vec!(("textDocument", document))));
+    }
+
+    /// Send the edits made to a document since the last time. Servers that don't take them
+    /// one by one get the whole text instead.
+    pub fn did_change(&mut self, uri: &str, buffer: &Buffer, edits: &[Edit]) {
+        let version = match self.versions.get_mut(uri) {
+            Some(version) => { *version += 1; *version }
+            None => return,
+        };
+        let changes = match self.sync_kind() {
+            0 => return,
+            2 => edits.iter()
+                .map(|edit| object(vec!(("range", range(edit.start, edit.end)), ("text", edit.text.as_str().into()))))
+                .collect(),
+            _ => vec!(object(vec!(("text", buffer.text().into())))),
+        };
+        let document = object(vec!(("uri", uri.into()), ("version", version.into())));
+        self.notify("textDocument/didChange", object(vec!(("textDocument", document), ("contentChanges", changes.into()))));
+    }
+
+    pub fn did_save(&mut self, uri: &str) {
+        self.notify("textDocument/didSave", object(vec!(("textDocument", object(vec!(("uri", uri.into())))))));
+    }
+
+    pub fn did_close(&mut self, uri: &str) {
+        self.versions.remove(uri);
+        self.notify("textDocument/didClose", object(vec!(("textDocument", object(vec!(("uri", uri.into())))))));
+    }
+
+    /// How the server takes the changes: 0 not at all, 1 the whole text, 2 edit by edit.
+    /// The whole text until it tells.
+    fn sync_kind(&self) -> u64 {
+        if !self.initialized { return 1 }
+        let sync = self.capabilities.get("textDocumentSync");
+        sync.as_u64().or_else(|| sync.get("change").as_u64()).unwrap_or(0)
+    }
+
+    /// The messages received since the last call, without waiting. `None` once the server exited.
+    pub fn poll(&mut self) -> Option<Vec<Message>> {
+        let mut messages = Vec::new();
+        loop {
+            let received = match self.messages.try_recv() {
+                Ok(received) => received,
+                Err(TryRecvError::Empty) => return Some(messages),
+                Err(TryRecvError::Disconnected) => return if messages.is_empty() { None } else { Some(messages) },
+            };
+
+            let id = received.get("id").clone();
+            let params = received.get("params").clone();
+            match received.get("method").as_str() {
+                Some(method) if !id.is_null() => messages.push(Message::Request(id, method.into(), params)),
+                Some(method) => messages.push(Message::Notification(method.into(), params)),
+                None => {
+                    let request = match id.as_u64().and_then(|id| self.pending.remove(&id)) {
+                        Some(request) => request,
+                        None => continue,
+                    };
+                    let error = received.get("error");
+                    let result = if error.is_null() {
+                        Ok(received.get("result").clone())
+                    } else {
+                        Err(error.get("message").as_str().unwrap_or("Unknown error").to_string())
+                    };
+                    match (request, result) {
+                        (Request::Initialize, Ok(result)) => self.initialized(&result),
+                        (request, result) => messages.push(Message::Response(request, result)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take note of the server's capabilities and send it what was waiting.
+    fn initialized(&mut self, result: &Json) {
+        self.capabilities = result.get("capabilities").clone();
+        self.initialized = true;
+        self.notify("initialized", object(Vec::new()));
+        for message in ::std::mem::take(&mut self.queue) {
+            self.send(message);
+        }
+    }
+
+    /// Ask the server to exit, waiting a little for it. It's killed when the client is dropped otherwise.
+    pub fn stop(&mut self) {
+        if self.initialized {
+            let id = self.request("shutdown", Json::Null, Request::Shutdown);
+            let start = Instant::now();
+            let timeout = Duration::from_millis(500);
+            while let Some(left) = timeout.checked_sub(start.elapsed()) {
+                match self.messages.recv_timeout(left) {
+                    Ok(ref received) if received.get("id").as_u64() == Some(id) => break,
+                    Ok(_) => continue,
+                    Err(_) => break,
+                }
+            }
+            self.notify("exit", Json::Null);
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            match self.child.try_wait() {
+                Ok(None) => thread::sleep(Duration::from_millis(10)),
+                _ => break,
+            }
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+    }
+}
+
+/// What miv supports, sent with `initialize`.
+fn capabilities() -> Json {
+    let code_action_kinds: Vec<Json> = vec!("", "quickfix", "refactor", "source").into_iter().map(Json::from).collect();
+    let text_document = object(vec!(
+        ("synchronization", object(vec!(("didSave", true.into())))),
+        ("completion", object(vec!(("completionItem", object(vec!(("snippetSupport", false.into()))))))),
+        ("hover", object(vec!(("contentFormat", vec!(Json::from("plaintext"), Json::from("markdown")).into())))),
+        ("definition", object(vec!(("linkSupport", true.into())))),
+        ("references", object(Vec::new())),
+        ("rename", object(Vec::new())),
+        ("formatting", object(Vec::new())),
+        ("codeAction", object(vec!(("codeActionLiteralSupport", object(vec!(
+            ("codeActionKind", object(vec!(("valueSet", code_action_kinds.into())))),
+        )))))),
+    ));
+    let workspace = object(vec!(
+        ("applyEdit", true.into()),
+        ("workspaceEdit", object(vec!(("documentChanges", true.into())))),
+        ("configuration", true.into()),
+    ));
+    object(vec!(("textDocument", text_document), ("workspace", workspace)))
+}
+
+/// A request, or a notification without `id`.
+fn message(id: Option<u64>, method: &str, params: Json) -> Json {
+    let mut pairs = vec!(("jsonrpc", "2.0".into()));
+    if let Some(id) = id {
+        pairs.push(("id", id.into()));
+    }
+    pairs.push(("method", method.into()));
+    if !params.is_null() {
+        pairs.push(("params", params));
+    }
+    object(pairs)
+}
+
+/// Read a message framed with a `Content-Length` header. `None` at the end of the stream.
+/// Messages that aren't valid JSON give an `InvalidData` error, after which the next one can be read.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if length.is_some() { break } else { continue }
+        }
+        let mut header = line.splitn(2, ':');
+        if header.next().is_some_and(|name| name.trim().eq_ignore_ascii_case("content-length")) {
+            length = header.next().and_then(|value| value.trim().parse::<usize>().ok());
+        }
+    }
+
+    let mut body = vec!(0; length.unwrap_or(0));
+    reader.read_exact(&mut body)?;
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let text = String::from_utf8(body).map_err(|_| invalid("Message not in UTF-8".into()))?;
+    Json::parse(&text).map(Some).map_err(invalid)
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// The `file://` URI of a path, made absolute.
+pub fn path_to_uri(path: &Path) -> String {
+    let path = file::canonical(path);
+    let mut uri = String::from("file://");
+    for &byte in path.to_string_lossy().as_bytes() {
+        match byte {
+            b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            _ if (byte as char).is_ascii_alphanumeric() => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// The path of a `file://` URI. `None` for other URIs.
+pub fn uri_to_path(uri: &str) -> Option<String> {
+    if !uri.starts_with("file://") {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    let mut rest = uri[7..].bytes();
+    while let Some(byte) = rest.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex: Vec<u8> = rest.by_ref().take(2).collect();
+        match String::from_utf8(hex).ok().and_then(|hex| u8::from_str_radix(&hex, 16).ok()) {
+            Some(decoded) => bytes.push(decoded),
+            None => return None,
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// A position in a document: its line and UTF-16 column. See `Buffer::lsp_position`.
+pub fn position((line, character): (usize, usize)) -> Json {
+    object(vec!(("line", line.into()), ("character", character.into())))
+}
+
+pub fn range(start: (usize, usize), end: (usize, usize)) -> Json {
+    object(vec!(("start", position(start)), ("end", position(end))))
+}
+
+fn parse_position(position: &Json) -> (usize, usize) {
+    let number = |key| position.get(key).as_u64().unwrap_or(0) as usize;
+    (number("line"), number("character"))
+}
+
+/// The URI and start of a location, or of a location link, e.g. where something is defined.
+pub fn parse_location(location: &Json) -> Option<(String, (usize, usize))> {
+    let (uri, range) = match location.get("uri").as_str() {
+        Some(uri) => (uri, location.get("range")),
+        None => (location.get("targetUri").as_str()?, location.get("targetSelectionRange")),
+    };
+    Some((uri.into(), parse_position(range.get("start"))))
+}
+
+/// The locations of a response that may be a single one, a list or nothing.
+pub fn parse_locations(result: &Json) -> Vec<(String, (usize, usize))> {
+    match *result {
+        Json::Array(ref items) => items.iter().filter_map(parse_location).collect(),
+        _ => parse_location(result).into_iter().collect(),
+    }
+}
+
+pub fn parse_edits(edits: &Json) -> Vec<Edit> {
+    edits.items().iter().map(|edit| {
+        let range = edit.get("range");
+        Edit {
+            start: parse_position(range.get("start")),
+            end: parse_position(range.get("end")),
+            text: edit.get("newText").as_str().unwrap_or("").into(),
+        }
+    }).collect()
+}
+
+/// The text edits of a workspace edit, by document URI, from its `changes` or its `documentChanges`,
+/// with the version of the document they were made for when given. See `Client::version`.
+/// Creating, renaming and deleting files isn't supported.
+pub fn workspace_edits(edit: &Json) -> Vec<(String, Option<u64>, Vec<Edit>)> {
+    if let Json::Array(ref changes) = *edit.get("documentChanges") {
+        return changes.iter()
+            .filter_map(|change| {
+                let document = change.get("textDocument");
+                document.get("uri").as_str().map(|uri| (uri.into(), document.get("version").as_u64(), parse_edits(change.get("edits"))))
+            })
+            .collect();
+    }
+    match *edit.get("changes") {
+        Json::Object(ref changes) => changes.iter().map(|(uri, edits)| (uri.clone(), None, parse_edits(edits))).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The text of hover information, given as a string, a marked string, markup content or a list of them.
+pub fn hover_text(contents: &Json) -> String {
+    match *contents {
+        Json::String(ref text) => text.clone(),
+        Json::Array(ref items) => items.iter().map(hover_text).collect::<Vec<String>>().join("\n\n"),
+        _ => contents.get("value").as_str().unwrap_or("").into(),
+    }
+}
+
+/// Names of the kinds of completion items, from 1.
+const COMPLETION_KINDS: &[&str] = &[
+    "text", "method", "function", "constructor", "field", "variable", "class", "interface", "module",
+    "property", "unit", "value", "enum", "keyword", "snippet", "color", "file", "reference", "folder",
+    "enum member", "constant", "struct", "event", "operator", "type parameter",
+];
+
+/// The completion items of a response, with their details or kinds as menu.
+pub fn completion_candidates(result: &Json) -> Vec<Candidate> {
+    let items = match *result {
+        Json::Array(ref items) => items,
+        _ => result.get("items").items(),
+    };
+    items.iter().map(|item| {
+        let label = item.get("label").as_str().unwrap_or("");
+        let text = item.get("textEdit").get("newText").as_str()
+            .or_else(|| item.get("insertText").as_str())
+            .unwrap_or(label);
+        let kind = item.get("kind").as_u64()
+            .and_then(|kind| (kind as usize).checked_sub(1))
+            .and_then(|idx| COMPLETION_KINDS.get(idx))
+            .cloned();
+        let menu = item.get("detail").as_str().or(kind).unwrap_or("");
+        Candidate::new(text, menu)
+    }).collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::env;
+    use std::io::BufReader;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+    use buffer::Buffer;
+    use json::{Json, object};
+    use lsp::*;
+    use point::Point;
+    use test_dir::TestDir;
+
+    /// The fake server of `examples/fake_lsp.rs`, built by `cargo test`.
+    pub fn fake_server() -> PathBuf {
+        let exe = env::current_exe().unwrap();
+        let path = exe.parent().unwrap().parent().unwrap().join("examples").join("fake_lsp");
+        assert!(path.exists(), "{} is missing, build it with cargo build --examples", path.display());
+        path
+    }
+
+    /// Poll until a response comes, for a few seconds at most.
+    fn response(client: &mut Client) -> (Request, Result<Json, String>) {
+        for _ in 0..500 {
+            for message in client.poll().expect("the server exited") {
+                if let Message::Response(request, result) = message {
+                    return (request, result);
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no response from the server");
+    }
+
+    #[test]
+    fn framing() {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &object(vec!(("id", 1usize.into())))).unwrap();
+        bytes.extend_from_slice(b"Content-Type: utf-8\r\nContent-Length: 5\r\n\r\n{oops");
+        write_message(&mut bytes, &Json::Null).unwrap();
+
+        let mut reader = BufReader::new(&bytes[..]);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(object(vec!(("id", 1usize.into())))));
+        assert!(read_message(&mut reader).is_err());
+        assert_eq!(read_message(&mut reader).unwrap(), Some(Json::Null));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn uris() {
+        let uri = path_to_uri(Path::new("/tmp/a dir/é.rs"));
+        assert_eq!(uri, "file:///tmp/a%20dir/%C3%A9.rs");
+        assert_eq!(uri_to_path(&uri), Some("/tmp/a dir/é.rs".into()));
+        assert_eq!(uri_to_path("https://example.com"), None);
+    }
+
+    #[test]
+    fn fake_server_session() {
+        let dir = TestDir::new("lsp");
+        let path = dir.join("main.txt");
+        let uri = path_to_uri(&path);
+        let mut client = Client::start(&fake_server().to_string_lossy(), dir.path()).unwrap();
+
+        let mut buffer = Buffer::new();
+        buffer.data = vec!("let café = 1;\n".into(), "café + 2\n".into());
+        client.did_open(&uri, "text", &buffer.text());
+        buffer.journal = Some(Vec::new());
+        buffer.insert_text(Point::new(0, 1), "😀 ".into());
+        buffer.split_line(Point::new(8, 1));
+        let edits = buffer.journal.replace(Vec::new()).unwrap();
+        client.did_change(&uri, &buffer, &edits);
+
+        // The fake server hovers with the line, as it sees it.
+        let params = object(vec!(("textDocument", object(vec!(("uri", uri.as_str().into())))), ("position", position((1, 0)))));
+        client.request("textDocument/hover", params, Request::Hover);
+        let (request, result) = response(&mut client);
+        assert_eq!(request, Request::Hover);
+        assert_eq!(hover_text(result.unwrap().get("contents")), "😀 café +");
+
+        let params = object(vec!(("textDocument", object(vec!(("uri", uri.as_str().into())))), ("position", position((0, 4)))));
+        client.request("textDocument/references", params, Request::References);
+        let (_, result) = response(&mut client);
+        assert_eq!(parse_locations(&result.unwrap()), vec!((uri.clone(), (0, 4)), (uri.clone(), (1, 3))));
+
+        client.request("unknown/method", Json::Null, Request::Hover);
+        assert!(response(&mut client).1.is_err());
+        client.stop();
+    }
+}
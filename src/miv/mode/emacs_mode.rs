@@ -0,0 +1,99 @@
+extern crate rustbox;
+use rustbox::Key;
+use keys::{KeyMap,KeyMatch};
+use mode::Mode;
+use point::Direction::*;
+use state::Action;
+use state::Action::*;
+
+/// Modeless editing with Emacs key bindings, used instead of Normal mode with `:set emacs`.
+/// Meta keys are typed with Esc first, or with Alt in terminals sending Esc for it.
+pub struct EmacsMode {
+    keymap: KeyMap,
+}
+
+impl EmacsMode {
+    pub fn new() -> EmacsMode {
+        let mut mode = EmacsMode {
+            keymap: KeyMap::new(),
+        };
+        mode.bind_defaults();
+        mode
+    }
+
+    fn bind_defaults(&mut self) {
+        let km = &mut self.keymap;
+        km.bind_defaults();
+        km.bind(&[Key::Ctrl('f')], MoveCursor(Right));
+        km.bind(&[Key::Ctrl('b')], MoveCursor(Left));
+        km.bind(&[Key::Ctrl('n')], MoveCursor(Down));
+        km.bind(&[Key::Ctrl('p')], MoveCursor(Up));
+        km.bind(&[Key::Ctrl('a')], MoveCursor(BeginningOfLine));
+        km.bind(&[Key::Ctrl('e')], MoveCursor(EndOfLine));
+        km.bind(&[Key::Esc, Key::Char('f')], MoveCursor(ForwardWord));
+        km.bind(&[Key::Esc, Key::Char('b')], MoveCursor(BackwardWord));
+        km.bind(&[Key::Esc, Key::Char('<')], MoveCursor(BeginningOfFile));
+        km.bind(&[Key::Esc, Key::Char('>')], MoveCursor(EndOfFile));
+
+        km.bind(&[Key::Backspace], BackwardDelete);
+        km.bind(&[Key::Ctrl('d')], Delete);
+        km.bind(&[Key::Enter], NewLineAtPoint);
+        km.bind(&[Key::Tab], Tab);
+        km.bind(&[Key::Ctrl('g')], Cancel);
+
+        km.bind(&[Key::Ctrl('k')], KillLine);
+        km.bind(&[Key::Ctrl('y')], Yank);
+        km.bind(&[Key::Esc, Key::Char('y')], YankPop);
+        km.bind(&[Key::Char('\0')], SetMark); // C-Space
+        km.bind(&[Key::Ctrl('w')], KillRegion);
+        km.bind(&[Key::Esc, Key::Char('w')], CopyRegion);
+        km.bind(&[Key::Ctrl('s')], Isearch);
+
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('s')], Save);
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('f')], Prompt("e ".into()));
+        km.describe(&[Key::Ctrl('x'), Key::Ctrl('f')], "find file");
+        km.bind(&[Key::Ctrl('x'), Key::Char('b')], Prompt("b ".into()));
+        km.describe(&[Key::Ctrl('x'), Key::Char('b')], "switch to buffer");
+        km.bind(&[Key::Esc, Key::Char('x')], Prompt(String::new()));
+        km.describe(&[Key::Esc, Key::Char('x')], "command");
+        km.describe(&[Key::Esc], "+meta");
+    }
+
+    fn default_action(&self, key: Key) -> Option<Action> {
+        match key {
+            Key::Char(c) => Some(Action::Insert(c)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for EmacsMode {
+    fn default() -> EmacsMode {
+        EmacsMode::new()
+    }
+}
+
+impl Mode for EmacsMode {
+    fn color(&self) -> Option<u16> { Some(98) }
+    fn display(&self) -> &'static str { "Emacs" }
+
+    fn keys_pressed(&mut self, keys: &[rustbox::Key]) -> Option<Action> {
+        match self.keymap.match_keys(keys) {
+            KeyMatch::Action(action) => Some(action),
+            KeyMatch::Partial | KeyMatch::Ambiguous(_) => Some(Action::PartialKey),
+            KeyMatch::None => self.default_action(keys[0]),
+        }
+    }
+
+    fn continuations(&self, keys: &[rustbox::Key]) -> Vec<(Key, String)> {
+        self.keymap.continuations(keys)
+    }
+
+    fn keys_timed_out(&mut self, keys: &[rustbox::Key]) -> Option<Action> {
+        self.keymap.timed_out(keys)
+    }
+
+    fn bound_len(&self, keys: &[rustbox::Key]) -> usize {
+        self.keymap.bound_len(keys).unwrap_or(keys.len())
+    }
+}
@@ -1,7 +1,9 @@
 extern crate rustbox;
 use rustbox::Key;
+use completion::Source;
 use keys::{KeyMap,KeyMatch};
 use mode::Mode;
+use point::Direction::*;
 use state::Action;
 use state::Action::*;
 
@@ -21,9 +23,27 @@ impl InsertMode {
     fn bind_defaults(&mut self) {
         let ref mut km = self.keymap;
         km.bind_defaults();
-        km.bind(&[Key::Char('d'), Key::Char('d')], DeleteLine);
         km.bind(&[Key::Backspace], BackwardDelete);
+        km.bind(&[Key::Ctrl('h')], BackwardDelete);
+        km.bind(&[Key::Delete], Delete);
         km.bind(&[Key::Enter], NewLineAtPoint);
+        km.bind(&[Key::Tab], Tab);
+        km.bind(&[Key::Home], MoveCursor(BeginningOfLine));
+        km.bind(&[Key::End], MoveCursor(EndOfLine));
+        km.bind(&[Key::Ctrl('w')], BackwardDeleteWord);
+        km.bind(&[Key::Ctrl('u')], BackwardDeleteLine);
+        km.bind(&[Key::Ctrl('t')], Indent);
+        km.bind(&[Key::Ctrl('d')], Dedent);
+        km.bind(&[Key::Ctrl('o')], NormalCommand);
+        km.bind(&[Key::Ctrl('n')], Complete(Source::Keyword, true));
+        km.bind(&[Key::Ctrl('p')], Complete(Source::Keyword, false));
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('n')], Complete(Source::Keyword, true));
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('p')], Complete(Source::Keyword, false));
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('l')], Complete(Source::Line, true));
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('f')], Complete(Source::Path, true));
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('k')], Complete(Source::Dictionary, true));
+        km.bind(&[Key::Ctrl('x'), Key::Ctrl('o')], Complete(Source::Server, true));
+        km.describe(&[Key::Ctrl('x')], "+complete");
     }
 
     fn default_action(&self, key: Key) -> Option<Action> {
@@ -33,6 +53,30 @@ impl InsertMode {
             None
         }
     }
+
+    /// The action of the keys starting with Ctrl-r or Ctrl-v, which are followed by any key.
+    fn prefixed_action(&self, keys: &[Key]) -> Option<Action> {
+        match *keys {
+            [_] => Some(PartialKey),
+            [Key::Ctrl('r'), Key::Char(register)] => Some(PasteRegister(register)),
+            [Key::Ctrl('v'), key] => literal(key).map(Insert),
+            _ => None,
+        }
+    }
+}
+
+/// The character inserted for a key typed after Ctrl-v, e.g. a tab even with `expandtab`.
+fn literal(key: Key) -> Option<char> {
+    match key {
+        Key::Char(c) => Some(c),
+        Key::Tab => Some('\t'),
+        Key::Enter => Some('\r'),
+        Key::Esc => Some('\x1b'),
+        Key::Backspace => Some('\x7f'),
+        Key::Ctrl(c) if c.is_ascii_lowercase() => Some((c as u8 - b'a' + 1) as char),
+        Key::Ctrl(c) => "\\]6/".find(c).map(|idx| (0x1c + idx as u8) as char),
+        _ => None,
+    }
 }
 
 impl Mode for InsertMode {
@@ -40,10 +84,26 @@ impl Mode for InsertMode {
     fn display(&self) -> &'static str { "Insert" }
 
     fn keys_pressed(&mut self, keys: &[rustbox::Key]) -> Option<Action> {
+        if keys[0] == Key::Ctrl('r') || keys[0] == Key::Ctrl('v') {
+            return self.prefixed_action(keys);
+        }
+
         match self.keymap.match_keys(keys) {
             KeyMatch::Action(action) => Some(action),
-            KeyMatch::Partial => Some(Action::PartialKey),
+            KeyMatch::Partial | KeyMatch::Ambiguous(_) => Some(Action::PartialKey),
             KeyMatch::None => self.default_action(keys[0]),
         }
     }
+
+    fn continuations(&self, keys: &[rustbox::Key]) -> Vec<(Key, String)> {
+        self.keymap.continuations(keys)
+    }
+
+    fn keys_timed_out(&mut self, keys: &[rustbox::Key]) -> Option<Action> {
+        self.keymap.timed_out(keys)
+    }
+
+    fn bound_len(&self, keys: &[rustbox::Key]) -> usize {
+        self.keymap.bound_len(keys).unwrap_or(keys.len())
+    }
 }
@@ -3,10 +3,12 @@ extern crate rustbox;
 use rustbox::Key;
 use state::Action;
 
+pub use self::emacs_mode::EmacsMode;
 pub use self::insert_mode::InsertMode;
 pub use self::normal_mode::NormalMode;
 pub use self::replace_mode::ReplaceMode;
 
+mod emacs_mode;
 mod insert_mode;
 mod normal_mode;
 mod replace_mode;
@@ -15,12 +17,21 @@ mod replace_mode;
 pub enum ModeType {
     Insert,
     Normal,
-    Replace
+    Replace,
+    Emacs,
 }
 
 pub trait Mode {
     /// The meat of the mode. Defines how to react to key presses.
     fn keys_pressed(&mut self, keys: &[rustbox::Key]) -> Option<Action>;
+    /// Action to run when no more keys were typed for `timeoutlen`,
+    /// e.g. `g` when both `g` and `gg` are bound. See `KeyMatch::Ambiguous`.
+    fn keys_timed_out(&mut self, _keys: &[rustbox::Key]) -> Option<Action> { None }
+    /// Number of keys taken by the action `keys_pressed` returned, the longest binding they start with.
+    /// The keys typed after them are handled next, e.g. `a` after Esc when only Esc is bound.
+    fn bound_len(&self, keys: &[rustbox::Key]) -> usize { keys.len() }
+    /// The keys that can follow the ones typed, with what they do. See `KeyMap::continuations`.
+    fn continuations(&self, _keys: &[rustbox::Key]) -> Vec<(Key, String)> { Vec::new() }
     /// Default action in case nothing matches in the mode keymap.
     fn default_action(&self, Key) -> Option<Action> { None }
     /// Action to run when the mode is replace by another.
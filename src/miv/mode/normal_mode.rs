@@ -24,7 +24,7 @@ impl Mode for NormalMode {
 
         match self.keymap.match_keys(keys) {
             KeyMatch::Action(action) => self.maybe_repeat(action),
-            KeyMatch::Partial => Some(Action::PartialKey),
+            KeyMatch::Partial | KeyMatch::Ambiguous(_) => Some(Action::PartialKey),
             KeyMatch::None => {
                 match keys[0] {
                     Key::Char(c) if c.is_digit(10) => {
@@ -36,6 +36,18 @@ impl Mode for NormalMode {
             }
         }
     }
+
+    fn continuations(&self, keys: &[rustbox::Key]) -> Vec<(Key, String)> {
+        self.keymap.continuations(keys)
+    }
+
+    fn keys_timed_out(&mut self, keys: &[rustbox::Key]) -> Option<Action> {
+        self.keymap.timed_out(keys).and_then(|action| self.maybe_repeat(action))
+    }
+
+    fn bound_len(&self, keys: &[rustbox::Key]) -> usize {
+        self.keymap.bound_len(keys).unwrap_or(keys.len())
+    }
 }
 
 impl NormalMode {
@@ -66,27 +78,47 @@ impl NormalMode {
         km.bind(&[Key::Char('$')], MoveCursor(EndOfLine));
         km.bind(&[Key::Char('G')], MoveCursor(EndOfFile));
         km.bind(&[Key::Char('g'), Key::Char('g')], MoveCursor(BeginningOfFile));
+        km.bind(&[Key::Char('g'), Key::Char('j')], MoveCursor(ScreenDown));
+        km.bind(&[Key::Char('g'), Key::Char('k')], MoveCursor(ScreenUp));
         km.bind(&[Key::Char('y'), Key::Char('y')], YankLine);
         km.bind(&[Key::Char('d'), Key::Char('d')], DeleteLine);
+        km.bind(&[Key::Char('>'), Key::Char('>')], Indent);
+        km.bind(&[Key::Char('<'), Key::Char('<')], Dedent);
 
         km.bind(&[Key::Char(' '), Key::Char('b'), Key::Char('n')], NextBuffer);
         km.bind(&[Key::Char(' '), Key::Char('b'), Key::Char('p')], PrevBuffer);
+        km.bind(&[Key::Ctrl('6')], AlternateBuffer); // Ctrl-^
+        km.bind(&[Key::Char('g'), Key::Char('d')], GotoDefinition);
+        km.bind(&[Key::Char('g'), Key::Char('r')], References);
+        km.bind(&[Key::Char('K')], Hover);
+        km.bind(&[Key::Char(' '), Key::Char('l'), Key::Char('r')], Prompt("LspRename ".into()));
+        km.describe(&[Key::Char(' '), Key::Char('l'), Key::Char('r')], "rename");
+        km.bind(&[Key::Char(' '), Key::Char('l'), Key::Char('a')], CodeActions);
+        km.bind(&[Key::Char(' '), Key::Char('l'), Key::Char('f')], Format);
+        km.describe(&[Key::Char(' ')], "+spacemacs");
+        km.describe(&[Key::Char(' '), Key::Char('b')], "+buffers");
+        km.describe(&[Key::Char(' '), Key::Char('l')], "+lsp");
+        km.describe(&[Key::Char('g')], "+goto");
+        km.describe(&[Key::Char('O')], "new line above");
 
         km.bind(&[Key::Char('A')], Multi(vec!(
             MoveCursor(EndOfLine),
             ChangeMode(ModeType::Insert),
             MoveCursor(Right),
         )));
+        km.describe(&[Key::Char('A')], "append at end of line");
 
         km.bind(&[Key::Char('I')], Multi(vec!(
             MoveCursor(BeginningOfLine),
             ChangeMode(ModeType::Insert),
         )));
+        km.describe(&[Key::Char('I')], "insert at beginning of line");
 
         km.bind(&[Key::Char('a')], Multi(vec!(
             MoveCursor(Right),
             ChangeMode(ModeType::Insert),
         )));
+        km.describe(&[Key::Char('a')], "append");
     }
 
     fn maybe_repeat(&mut self, action: Action) -> Option<Action> {
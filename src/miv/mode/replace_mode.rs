@@ -42,8 +42,20 @@ impl Mode for ReplaceMode {
     fn keys_pressed(&mut self, keys: &[rustbox::Key]) -> Option<Action> {
         match self.keymap.match_keys(keys) {
             KeyMatch::Action(action) => Some(action),
-            KeyMatch::Partial => Some(Action::PartialKey),
+            KeyMatch::Partial | KeyMatch::Ambiguous(_) => Some(Action::PartialKey),
             KeyMatch::None => self.default_action(keys[0]),
         }
     }
+
+    fn continuations(&self, keys: &[rustbox::Key]) -> Vec<(Key, String)> {
+        self.keymap.continuations(keys)
+    }
+
+    fn keys_timed_out(&mut self, keys: &[rustbox::Key]) -> Option<Action> {
+        self.keymap.timed_out(keys)
+    }
+
+    fn bound_len(&self, keys: &[rustbox::Key]) -> usize {
+        self.keymap.bound_len(keys).unwrap_or(keys.len())
+    }
 }
@@ -0,0 +1,293 @@
+//! Vim's key notation, e.g. `<C-s>:w<CR>`, read by the mapping commands and used to display keys.
+//!
+//! Terminals send some keys as sequences starting with Esc, and so does rustbox:
+//! `<M-x>` (Alt-x) is Esc then `x`, and keys with modifiers like `<C-Left>` or `<S-Tab>`
+//! are xterm sequences, e.g. Esc `[1;5D`. They are parsed into those sequences and displayed back by name.
+//! Esc then another key is displayed as Alt with it, e.g. `<M-x>`, which is written the same.
+
+use rustbox::Key;
+
+/// Names of the special keys, e.g. `<Esc>`. The first name of a key is used to display it.
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("Esc", Key::Esc),
+    ("CR", Key::Enter),
+    ("Enter", Key::Enter),
+    ("Return", Key::Enter),
+    ("Tab", Key::Tab),
+    ("BS", Key::Backspace),
+    ("Space", Key::Char(' ')),
+    ("lt", Key::Char('<')),
+    ("Bslash", Key::Char('\\')),
+    ("Bar", Key::Char('|')),
+    ("Up", Key::Up),
+    ("Down", Key::Down),
+    ("Left", Key::Left),
+    ("Right", Key::Right),
+    ("Del", Key::Delete),
+    ("Insert", Key::Insert),
+    ("Home", Key::Home),
+    ("End", Key::End),
+    ("PageUp", Key::PageUp),
+    ("PageDown", Key::PageDown),
+    // Sent by Ctrl-Space and Ctrl-@.
+    ("Nul", Key::Char('\0')),
+];
+
+/// Modifiers of the xterm sequences, e.g. `5` in Esc `[1;5D` for `<C-Left>`, in their notation order.
+const MODIFIERS: &[(&str, char)] = &[
+    ("S-", '2'),
+    ("M-", '3'),
+    ("M-S-", '4'),
+    ("C-", '5'),
+    ("C-S-", '6'),
+    ("C-M-", '7'),
+    ("C-M-S-", '8'),
+];
+
+/// Parse keys written in Vim's key notation, e.g. `jk` or `<C-s>:w<CR>`.
+///
+/// Special keys are written between angle brackets, see `KEY_NAMES`, and `<F1>` to `<F12>`.
+/// They can be prefixed by the modifiers `C-` (Ctrl), `S-` (Shift) and `M-` or `A-` (Alt),
+/// e.g. `<C-x>`, `<M-x>`, `<S-Tab>`, `<C-Left>`. Names are case insensitive.
+/// A `<` that doesn't start one of those is taken literally.
+pub fn parse_keys(text: &str) -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let special = if c == '<' { rest.find('>').and_then(|end| special_keys(&rest[1..end]).map(|keys| (keys, end + 1))) } else { None };
+        match special {
+            Some((special, len)) => { keys.extend(special); rest = &rest[len..]; }
+            None => { keys.push(Key::Char(c)); rest = &rest[c.len_utf8()..]; }
+        }
+    }
+
+    if keys.is_empty() {
+        return Err("Missing keys".into());
+    }
+    Ok(keys)
+}
+
+/// Replace `<Leader>` and `<LocalLeader>` with the keys they stand for, e.g. `<Space>` for `:set mapleader=<Space>`.
+/// Names are case insensitive like the other special keys.
+pub fn expand_leaders(text: &str, leader: &str, localleader: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let starts_with = |name: &str| rest.get(..name.len()).is_some_and(|start| start.eq_ignore_ascii_case(name));
+        if starts_with("<leader>") {
+            expanded.push_str(leader);
+            rest = &rest[8..];
+        } else if starts_with("<localleader>") {
+            expanded.push_str(localleader);
+            rest = &rest[13..];
+        } else {
+            expanded.push('<');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// The keys sent for what's between angle brackets, e.g. `C-Left`.
+fn special_keys(name: &str) -> Option<Vec<Key>> {
+    let (mut ctrl, mut shift, mut alt) = (false, false, false);
+    let mut base = name;
+    while base.len() > 2 && base.as_bytes()[1] == b'-' {
+        match base.as_bytes()[0].to_ascii_lowercase() {
+            b'c' => ctrl = true,
+            b's' => shift = true,
+            b'm' | b'a' => alt = true,
+            _ => return None,
+        }
+        base = &base[2..];
+    }
+
+    let key = base_key(base)?;
+    match key {
+        Key::Char(' ') | Key::Char('@') if ctrl && !shift && !alt => Some(vec!(Key::Char('\0'))),
+        Key::Char(c) if !shift || c.is_alphabetic() => {
+            let key = if ctrl { Key::Ctrl(c.to_ascii_lowercase()) } else if shift { Key::Char(c.to_ascii_uppercase()) } else { key };
+            Some(if alt { vec!(Key::Esc, key) } else { vec!(key) })
+        }
+        Key::Tab if shift && !ctrl && !alt => Some(vec!(Key::Esc, Key::Char('['), Key::Char('Z'))),
+        _ if !ctrl && !shift && !alt => Some(vec!(key)),
+        _ => {
+            let prefix = format!("{}{}{}", if ctrl { "C-" } else { "" }, if alt { "M-" } else { "" }, if shift { "S-" } else { "" });
+            let modifier = MODIFIERS.iter().find(|&&(known, _)| known == prefix).map(|&(_, modifier)| modifier);
+            match modifier.and_then(|modifier| csi_sequence(key, modifier)) {
+                Some(sequence) => Some(Some(Key::Esc).into_iter().chain(sequence.chars().map(Key::Char)).collect()),
+                None if alt && !ctrl && !shift => Some(vec!(Key::Esc, key)),
+                None => None,
+            }
+        }
+    }
+}
+
+/// The key called `name` without modifiers, e.g. `Esc`, `F5` or `x`.
+fn base_key(name: &str) -> Option<Key> {
+    let lower = name.to_lowercase();
+    if let Some(&(_, key)) = KEY_NAMES.iter().find(|&&(known, _)| known.to_lowercase() == lower) {
+        return Some(key);
+    }
+
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(Key::Char(c)),
+        (Some('f'), Some(_)) | (Some('F'), Some(_)) => name[1..].parse().ok().filter(|n| (1..=12).contains(n)).map(Key::F),
+        _ => None,
+    }
+}
+
+/// What follows Esc in the xterm sequence of a key with a modifier, e.g. `[1;5D` for `<C-Left>`.
+fn csi_sequence(key: Key, modifier: char) -> Option<String> {
+    let (number, last) = match key {
+        Key::Up => (1, 'A'),
+        Key::Down => (1, 'B'),
+        Key::Right => (1, 'C'),
+        Key::Left => (1, 'D'),
+        Key::End => (1, 'F'),
+        Key::Home => (1, 'H'),
+        Key::F(n) if (1..=4).contains(&n) => (1, ['P', 'Q', 'R', 'S'][n as usize - 1]),
+        Key::F(n) if (5..=12).contains(&n) => ([15, 17, 18, 19, 20, 21, 23, 24][n as usize - 5], '~'),
+        Key::Insert => (2, '~'),
+        Key::Delete => (3, '~'),
+        Key::PageUp => (5, '~'),
+        Key::PageDown => (6, '~'),
+        _ => return None,
+    };
+    Some(format!("[{};{}{}", number, modifier, last))
+}
+
+/// Keys that can be sent with a modifier. See `csi_sequence`.
+const CSI_KEYS: &[Key] = &[
+    Key::Up, Key::Down, Key::Right, Key::Left, Key::End, Key::Home, Key::Insert, Key::Delete, Key::PageUp, Key::PageDown,
+    Key::F(1), Key::F(2), Key::F(3), Key::F(4), Key::F(5), Key::F(6),
+    Key::F(7), Key::F(8), Key::F(9), Key::F(10), Key::F(11), Key::F(12),
+];
+
+/// The first key of `keys` as written in the notation, without angle brackets, e.g. `C-Left`,
+/// and the number of keys it stands for. Whether it's a special key, written between angle brackets.
+fn first_key(keys: &[Key]) -> (String, usize, bool) {
+    match keys {
+        [Key::Esc, Key::Char('['), Key::Char('Z'), ..] => return ("S-Tab".into(), 3, true),
+        [Key::Esc, Key::Char('['), ..] => {
+            let sequence: String = keys[1..].iter().take(7).map(|&key| if let Key::Char(c) = key { c } else { '\0' }).collect();
+            for &key in CSI_KEYS {
+                for &(prefix, modifier) in MODIFIERS {
+                    let expected = csi_sequence(key, modifier).unwrap();
+                    if sequence.starts_with(&expected) {
+                        let (name, _, _) = first_key(&[key]);
+                        return (format!("{}{}", prefix, name), 1 + expected.len(), true);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    match *keys {
+        // Not an unknown sequence, nor what can't be written with `M-`, e.g. `<Esc><F5>`.
+        [Key::Esc, key, ..] if key != Key::Esc && key != Key::Char('[') => {
+            let (name, len, _) = first_key(&keys[1..]);
+            let name = match name.strip_prefix("C-") {
+                Some(name) => format!("C-M-{}", name),
+                None => format!("M-{}", name),
+            };
+            if parse_keys(&format!("<{}>", name)).ok().as_deref() == Some(&keys[..1 + len]) {
+                return (name, 1 + len, true);
+            }
+            ("Esc".into(), 1, true)
+        }
+        [Key::Char('\0'), ..] => ("C-Space".into(), 1, true),
+        [Key::Char(c), ..] if !KEY_NAMES.iter().any(|&(_, known)| known == Key::Char(c)) || c == '\\' => (c.to_string(), 1, false),
+        [Key::Ctrl(c), ..] => (format!("C-{}", c), 1, true),
+        [Key::F(n), ..] => (format!("F{}", n), 1, true),
+        [key, ..] => {
+            let name = KEY_NAMES.iter().find(|&&(_, known)| known == key).map_or("Unknown", |&(name, _)| name);
+            (name.into(), 1, true)
+        }
+        [] => (String::new(), 0, false),
+    }
+}
+
+/// Write keys in the notation read by `parse_keys`, e.g. to list the mappings.
+pub fn format_keys(keys: &[Key]) -> String {
+    let mut text = String::new();
+    let mut rest = keys;
+    while !rest.is_empty() {
+        let (name, len, special) = first_key(rest);
+        if special { text.push('<'); }
+        text.push_str(&name);
+        if special { text.push('>'); }
+        rest = &rest[len..];
+    }
+    text
+}
+
+/// Write keys being typed for the bottom bar, Spacemacs style, e.g. `SPC-b-` or `C-x-`.
+pub fn format_pending(keys: &[Key]) -> String {
+    let mut text = String::new();
+    let mut rest = keys;
+    while !rest.is_empty() {
+        let (name, len, _) = match rest[0] {
+            Key::Char(' ') => ("SPC".into(), 1, true),
+            Key::Char(c) if c != '\0' => (c.to_string(), 1, false),
+            _ => first_key(rest),
+        };
+        text.push_str(&name);
+        text.push('-');
+        rest = &rest[len..];
+    }
+    text
+}
+
+#[cfg(test)]
+pub mod test {
+    use rustbox::Key;
+    use notation::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_keys("jk"), Ok(vec!(Key::Char('j'), Key::Char('k'))));
+        assert_eq!(parse_keys("<C-s>:w<CR>"), Ok(vec!(Key::Ctrl('s'), Key::Char(':'), Key::Char('w'), Key::Enter)));
+        assert_eq!(parse_keys("<esc><lt>"), Ok(vec!(Key::Esc, Key::Char('<'))));
+        assert_eq!(parse_keys("a<b"), Ok(vec!(Key::Char('a'), Key::Char('<'), Key::Char('b'))));
+        assert_eq!(parse_keys("<F5><f12><F13>"), Ok(vec!(Key::F(5), Key::F(12), Key::Char('<'), Key::Char('F'), Key::Char('1'), Key::Char('3'), Key::Char('>'))));
+        assert_eq!(parse_keys("<M-x><A-CR>"), Ok(vec!(Key::Esc, Key::Char('x'), Key::Esc, Key::Enter)));
+        assert_eq!(parse_keys("<S-a><C-S-x>"), Ok(vec!(Key::Char('A'), Key::Ctrl('x'))));
+        assert_eq!(parse_keys("<C-Space><C-@><Nul>"), Ok(vec!(Key::Char('\0'), Key::Char('\0'), Key::Char('\0'))));
+        assert_eq!(parse_keys("<S-Tab>"), parse_keys("<Esc>[Z"));
+        assert_eq!(parse_keys("<C-Left>"), parse_keys("<Esc>[1;5D"));
+        assert_eq!(parse_keys("<M-Left><M-Esc>"), parse_keys("<Esc>[1;3D<Esc><Esc>"));
+        assert_eq!(parse_keys("<M-S-F5>"), parse_keys("<Esc>[15;4~"));
+        assert_eq!(parse_keys("<X-a>"), parse_keys("<lt>X-a>"));
+        assert!(parse_keys("").is_err());
+    }
+
+    #[test]
+    fn leaders() {
+        assert_eq!(expand_leaders("<Leader>w<lt><leader>", "<Space>", ","), "<Space>w<lt><Space>");
+        assert_eq!(expand_leaders("<LocalLeader>r<localleader", "\\", ","), ",r<localleader");
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_keys(&parse_keys("<C-s>:w<CR>").unwrap()), "<C-s>:w<CR>");
+        assert_eq!(format_keys(&parse_keys("<lt> <Esc>\\<F2>").unwrap()), "<lt><Space><M-\\><F2>");
+        assert_eq!(format_keys(&parse_keys("<M-x><S-Tab><C-Left><C-M-Del><M-C-x><Esc>").unwrap()), "<M-x><S-Tab><C-Left><C-M-Del><C-M-x><Esc>");
+        assert_eq!(format_keys(&parse_keys("<M-lt><M-CR><Esc><F5><Esc><Esc>").unwrap()), "<M-lt><M-CR><Esc><F5><Esc><Esc>");
+        assert_eq!(format_keys(&parse_keys("<Esc>[1;9D").unwrap()), "<Esc>[1;9D");
+    }
+
+    #[test]
+    fn pending() {
+        assert_eq!(format_pending(&parse_keys("<Space>b").unwrap()), "SPC-b-");
+        assert_eq!(format_pending(&parse_keys("<C-x><Esc><C-Up>|").unwrap()), "C-x-Esc-C-Up-|-");
+        assert_eq!(format_pending(&parse_keys("<M-x><S-Tab>").unwrap()), "M-x-S-Tab-");
+        assert_eq!(format_pending(&parse_keys("<C-Space>:").unwrap()), "C-Space-:-");
+    }
+}
@@ -0,0 +1,265 @@
+use notation;
+
+/// Editor wide settings. Changed at runtime with `:set`.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct Options {
+    /// Display the absolute line numbers in the gutter.
+    pub number: bool,
+    /// Display the line numbers relative to the cursor in the gutter.
+    /// When combined with `number` the current line shows its absolute number instead of 0.
+    pub relativenumber: bool,
+    /// Wrap the lines longer than the window instead of scrolling horizontally.
+    pub wrap: bool,
+    /// When wrapping, break the lines at whitespace rather than in the middle of a word.
+    pub linebreak: bool,
+    /// String displayed at the beginning of wrapped lines.
+    pub showbreak: String,
+    /// Number of columns a tab counts for.
+    pub tabstop: usize,
+    /// Number of columns used by each step of indentation. Uses `tabstop` when 0.
+    pub shiftwidth: usize,
+    /// Insert spaces instead of tabs.
+    pub expandtab: bool,
+    /// Number of columns inserted by the Tab key, and removed by Backspace.
+    /// Uses `tabstop` when 0.
+    pub softtabstop: usize,
+    /// Always terminate the last line when saving, even if the file didn't have a final new line.
+    /// See `Buffer::endofline`.
+    pub fixendofline: bool,
+    /// Keep a copy of the previous version of a file when saving it, suffixed with `~`.
+    pub backup: bool,
+    /// Keep a swap file next to the edited files, to recover the unsaved changes after a crash.
+    pub swapfile: bool,
+    /// Number of keys typed after which the swap files are written. 0 to only write them when idle.
+    pub updatecount: usize,
+    /// Milliseconds without typing after which the swap files are written.
+    /// Files changed by other programs are also noticed then.
+    pub updatetime: usize,
+    /// Reload the unmodified buffers whose file was changed by another program, without asking.
+    pub autoread: bool,
+    /// Wait at most `timeoutlen` for the rest of a mapping or key binding, when the keys typed
+    /// may be the beginning of a longer one. Waits as long as it takes otherwise.
+    pub timeout: bool,
+    /// Milliseconds to wait for the rest of a mapping or key binding, e.g. the second `g` of `gg`.
+    /// The keys typed so far are then taken as they are.
+    pub timeoutlen: usize,
+    /// Edit without modes, with Emacs key bindings, instead of starting in Normal mode. See `EmacsMode`.
+    pub emacs: bool,
+    /// Show the keys that can follow the ones typed, and what they do, e.g. after `SPC b`.
+    pub whichkey: bool,
+    /// Milliseconds to wait for the next key before showing the which-key popup.
+    pub whichkeydelay: usize,
+    /// Keys written `<Leader>` in mappings, in key notation, e.g. `<Space>`. Replaced when mappings are defined.
+    pub mapleader: String,
+    /// Keys written `<LocalLeader>` in mappings, usually for those of a file type. See `mapleader`.
+    pub maplocalleader: String,
+    /// File of words completed with Ctrl-x Ctrl-k in Insert mode.
+    pub dictionary: String,
+    /// Show the keyword completion popup while typing a word, without selecting a candidate yet.
+    pub autocomplete: bool,
+    /// Maximum number of candidates shown at once by the completion popup.
+    pub pumheight: usize,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            number: false,
+            relativenumber: false,
+            wrap: false,
+            linebreak: false,
+            showbreak: String::new(),
+            tabstop: 8,
+            shiftwidth: 8,
+            expandtab: false,
+            softtabstop: 0,
+            fixendofline: false,
+            backup: false,
+            swapfile: true,
+            updatecount: 200,
+            updatetime: 4000,
+            autoread: false,
+            timeout: true,
+            timeoutlen: 1000,
+            emacs: false,
+            whichkey: true,
+            whichkeydelay: 500,
+            mapleader: "\\".into(),
+            maplocalleader: "\\".into(),
+            dictionary: String::new(),
+            autocomplete: false,
+            pumheight: 10,
+        }
+    }
+
+    /// The effective indentation width. See `shiftwidth`.
+    pub fn shift_width(&self) -> usize {
+        if self.shiftwidth == 0 { self.tabstop } else { self.shiftwidth }
+    }
+
+    /// Apply a single `:set` argument.
+    ///
+    /// Supports `name` and `noname` to enable or disable a flag, `name!` to toggle it,
+    /// and `name=value` for options taking a value.
+    pub fn set(&mut self, arg: &str) -> Result<(), String> {
+        if let Some(idx) = arg.find('=') {
+            let (name, value) = (&arg[..idx], &arg[idx + 1..]);
+            return self.set_value(name, value);
+        }
+
+        if let Some(name) = arg.strip_suffix('!') {
+            let flag = self.flag_mut(name);
+            return flag.map(|f| *f = !*f).ok_or_else(|| unknown_option(arg));
+        }
+
+        if let Some(flag) = self.flag_mut(arg) {
+            *flag = true;
+            return Ok(());
+        }
+
+        if let Some(name) = arg.strip_prefix("no") {
+            if let Some(flag) = self.flag_mut(name) {
+                *flag = false;
+                return Ok(());
+            }
+        }
+
+        Err(unknown_option(arg))
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "number" | "nu" => Some(&mut self.number),
+            "relativenumber" | "rnu" => Some(&mut self.relativenumber),
+            "wrap" => Some(&mut self.wrap),
+            "linebreak" | "lbr" => Some(&mut self.linebreak),
+            "expandtab" | "et" => Some(&mut self.expandtab),
+            "fixendofline" | "fixeol" => Some(&mut self.fixendofline),
+            "backup" | "bk" => Some(&mut self.backup),
+            "swapfile" | "swf" => Some(&mut self.swapfile),
+            "autoread" | "ar" => Some(&mut self.autoread),
+            "timeout" | "to" => Some(&mut self.timeout),
+            "emacs" => Some(&mut self.emacs),
+            "whichkey" | "wk" => Some(&mut self.whichkey),
+            "autocomplete" | "acp" => Some(&mut self.autocomplete),
+            _ => None,
+        }
+    }
+
+    fn set_value(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "showbreak" | "sbr" => self.showbreak = value.into(),
+            "dictionary" | "dict" => self.dictionary = value.into(),
+            "tabstop" | "ts" => match parse_number(name, value)? {
+                0 => return Err(invalid_value(name, value)),
+                n => self.tabstop = n,
+            },
+            "shiftwidth" | "sw" => self.shiftwidth = parse_number(name, value)?,
+            "softtabstop" | "sts" => self.softtabstop = parse_number(name, value)?,
+            "updatecount" | "uc" => self.updatecount = parse_number(name, value)?,
+            "updatetime" | "ut" => match parse_number(name, value)? {
+                0 => return Err(invalid_value(name, value)),
+                n => self.updatetime = n,
+            },
+            "timeoutlen" | "tm" => self.timeoutlen = parse_number(name, value)?,
+            "whichkeydelay" | "wkd" => self.whichkeydelay = parse_number(name, value)?,
+            "pumheight" | "ph" => match parse_number(name, value)? {
+                0 => return Err(invalid_value(name, value)),
+                n => self.pumheight = n,
+            },
+            "mapleader" => {
+                notation::parse_keys(value).map_err(|_| invalid_value(name, value))?;
+                self.mapleader = value.into();
+            }
+            "maplocalleader" => {
+                notation::parse_keys(value).map_err(|_| invalid_value(name, value))?;
+                self.maplocalleader = value.into();
+            }
+            _ => return Err(unknown_option(name)),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+fn unknown_option(name: &str) -> String {
+    format!("Unknown option: {}", name)
+}
+
+/// Apply a `name`, `noname` or `name!` argument to `flag`, if `name` is one of `names`.
+/// Returns whether the argument was applied.
+pub fn set_flag(flag: &mut bool, arg: &str, names: &[&str]) -> bool {
+    if names.contains(&arg) {
+        *flag = true;
+    } else if arg.ends_with('!') && names.contains(&&arg[..arg.len() - 1]) {
+        *flag = !*flag;
+    } else if arg.starts_with("no") && names.contains(&&arg[2..]) {
+        *flag = false;
+    } else {
+        return false;
+    }
+    true
+}
+
+/// The value of a `name=value` argument, if `name` is one of `names`.
+pub fn value_of<'a>(arg: &'a str, names: &[&str]) -> Option<&'a str> {
+    arg.find('=')
+        .and_then(|idx| if names.contains(&&arg[..idx]) { Some(&arg[idx + 1..]) } else { None })
+}
+
+pub fn invalid_value(name: &str, value: &str) -> String {
+    format!("Invalid value for {}: {}", name, value)
+}
+
+fn parse_number(name: &str, value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| invalid_value(name, value))
+}
+
+#[cfg(test)]
+pub mod test {
+    use options::*;
+
+    #[test]
+    fn set_flags() {
+        let mut options = Options::new();
+        assert_eq!(options.set("number"), Ok(()));
+        assert!(options.number);
+        assert_eq!(options.set("nonu"), Ok(()));
+        assert!(!options.number);
+        assert_eq!(options.set("rnu!"), Ok(()));
+        assert!(options.relativenumber);
+        assert_eq!(options.set("relativenumber!"), Ok(()));
+        assert!(!options.relativenumber);
+        assert!(options.set("nope").is_err());
+        assert!(options.set("number=3").is_err());
+    }
+
+    #[test]
+    fn set_values() {
+        let mut options = Options::new();
+        assert_eq!(options.set("showbreak=>>"), Ok(()));
+        assert_eq!(options.showbreak, ">>");
+        assert_eq!(options.set("sbr="), Ok(()));
+        assert_eq!(options.showbreak, "");
+
+        assert_eq!(options.set("ts=4"), Ok(()));
+        assert_eq!(options.tabstop, 4);
+        assert_eq!(options.set("sw=0"), Ok(()));
+        assert_eq!(options.shift_width(), 4);
+        assert!(options.set("tabstop=0").is_err());
+        assert!(options.set("softtabstop=two").is_err());
+
+        assert_eq!(options.set("mapleader=<Space>"), Ok(()));
+        assert_eq!(options.mapleader, "<Space>");
+        assert!(options.set("maplocalleader=").is_err());
+
+        assert_eq!(options.set("ph=5"), Ok(()));
+        assert_eq!(options.pumheight, 5);
+        assert!(options.set("pumheight=0").is_err());
+    }
+}
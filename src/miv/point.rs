@@ -1,8 +1,11 @@
 use std::cmp;
 
+/// A location in a buffer.
 #[derive(PartialEq,Debug,Copy,Clone)]
 pub struct Point {
+    /// Column, in grapheme clusters. See `Buffer`.
     pub x: usize,
+    /// Line
     pub y: usize
 }
 
@@ -50,4 +53,12 @@ pub enum Direction {
     EndOfLine,
     BeginningOfFile,
     EndOfFile,
+    /// Up one screen row. Different from `Up` when lines are wrapped.
+    ScreenUp,
+    /// Down one screen row. Different from `Down` when lines are wrapped.
+    ScreenDown,
+    /// To the end of the next word, like Emacs' `M-f`.
+    ForwardWord,
+    /// To the beginning of the previous word, like Emacs' `M-b`.
+    BackwardWord,
 }
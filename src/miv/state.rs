@@ -2,13 +2,43 @@ extern crate rustbox;
 
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp;
+use std::env;
 use std::usize;
+use std::mem;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use rustbox::Key;
-use buffer::Buffer;
-use mode::{Mode,ModeType,NormalMode,InsertMode,ReplaceMode};
+use unicode_segmentation::UnicodeSegmentation;
+use autocmd::{Autocmd, Event};
+use buffer::{self, Buffer, DiskChange, Edit};
+use command::{self, LineRange};
+use completion::{self, Candidate, Completion, Source};
+use config;
+use encoding::Encoding;
+use file;
+use filetype;
+use json::{Json, object};
+use keys::{KeyMap, KeyMatch};
+use layout;
+use lsp::{self, Client, Message, Request};
+use mode::{Mode,ModeType,NormalMode,InsertMode,ReplaceMode,EmacsMode};
+use notation;
+use options::{self, Options};
 use point::{Direction,Point};
 use point::Direction::*;
+use swap::{self, Swap, SwapFile};
+use theme::Theme;
+
+/// Number of mappings run in a row before giving up, e.g. with `:nmap a ab`. See Vim's `maxmapdepth`.
+const MAX_MAP_DEPTH: usize = 1000;
+
+/// The registers listed by `:registers`, in order. See `State::register`.
+const REGISTERS: &str = "\"0123456789-%#";
+
+const NO_LANGUAGE_SERVER: &str = "No language server for this buffer, see :LspServer";
 
 #[derive(Eq,PartialEq,Debug,Clone)]
 pub enum Action {
@@ -17,6 +47,13 @@ pub enum Action {
     ChangeMode(ModeType),
     Delete,
     DeleteLine,
+    /// Type keys, e.g. the right-hand side of a mapping.
+    /// They are mapped again if the flag is set, i.e. for `:map` but not for `:noremap`.
+    Feed(Vec<Key>, bool),
+    /// Remove one level of indentation from the current line. See `shiftwidth`.
+    Dedent,
+    /// Add one level of indentation to the current line. See `shiftwidth`.
+    Indent,
     Insert(char),
     NewLine,
     NewLineAtPoint,
@@ -28,24 +65,199 @@ pub enum Action {
     PartialKey,
     Paste,
     PrevBuffer,
+    /// Go back to the previously edited buffer.
+    AlternateBuffer,
     RepeatPrevious,
     Replace(char),
     Save,
+    /// Insert a tab, or spaces depending on `expandtab` and `softtabstop`.
+    Tab,
     Quit,
     YankLine,
+    /// Kill the rest of the line, or the line ending at the end of a line. Consecutive kills add up.
+    KillLine,
+    /// Kill the text between the mark and the cursor. See `SetMark`.
+    KillRegion,
+    /// Copy the text between the mark and the cursor to the kill ring.
+    CopyRegion,
+    /// Set the mark at the cursor. The region goes from there to the cursor.
+    SetMark,
+    /// Insert the last killed text at the cursor.
+    Yank,
+    /// Replace the text just yanked with the previous one in the kill ring.
+    YankPop,
+    /// Search forward as the text is typed. See `MicroState::Isearch`.
+    Isearch,
+    /// Open the minibuffer with the beginning of a command, e.g. `e ` to open a file.
+    Prompt(String),
+    /// Delete the word before the cursor, like Ctrl-w in Insert mode.
+    BackwardDeleteWord,
+    /// Delete from the cursor back to the indentation, or to the beginning of the line from there.
+    BackwardDeleteLine,
+    /// Run a single Normal mode command, then go back to Insert mode.
+    NormalCommand,
+    /// Insert the content of a register. See `register`.
+    PasteRegister(char),
+    /// Complete the text before the cursor, selecting the first candidate found from the source,
+    /// or the last one if not set. Shows the completion popup. See `Completion`.
+    Complete(Source, bool),
+    /// Select the next candidate of the completion popup, or the previous one if not set.
+    SelectCompletion(bool),
+    /// Keep the selected candidate and close the completion popup.
+    AcceptCompletion,
+    /// Go back to the text typed and close the completion popup.
+    CancelCompletion,
+    /// Go to where the symbol under the cursor is defined, according to the language server. See `lsp`.
+    GotoDefinition,
+    /// Show information about the symbol under the cursor in a popup.
+    Hover,
+    /// List the references to the symbol under the cursor in the quickfix list.
+    References,
+    /// List the code actions offered at the cursor, applied with `:LspCodeAction N`.
+    CodeActions,
+    /// Format the current buffer with the language server.
+    Format,
     /// Multiple actions in a row
     Multi(Vec<Action>),
     /// The same action `n` times
     Repeat(Box<Action>, usize)
 }
 
+impl Action {
+    /// The actions that can be mapped to with `<Plug>(name)`, e.g. `:nmap <C-n> <Plug>(next-buffer)`.
+    pub fn named() -> Vec<(&'static str, Action)> {
+        vec!(
+            ("cancel", Action::Cancel),
+            ("delete", Action::Delete),
+            ("backward-delete", Action::BackwardDelete),
+            ("delete-line", Action::DeleteLine),
+            ("yank-line", Action::YankLine),
+            ("paste", Action::Paste),
+            ("indent", Action::Indent),
+            ("dedent", Action::Dedent),
+            ("new-line", Action::NewLine),
+            ("split-line", Action::NewLineAtPoint),
+            ("tab", Action::Tab),
+            ("repeat", Action::RepeatPrevious),
+            ("up", Action::MoveCursor(Up)),
+            ("down", Action::MoveCursor(Down)),
+            ("left", Action::MoveCursor(Left)),
+            ("right", Action::MoveCursor(Right)),
+            ("screen-up", Action::MoveCursor(ScreenUp)),
+            ("screen-down", Action::MoveCursor(ScreenDown)),
+            ("beginning-of-line", Action::MoveCursor(BeginningOfLine)),
+            ("end-of-line", Action::MoveCursor(EndOfLine)),
+            ("beginning-of-file", Action::MoveCursor(BeginningOfFile)),
+            ("end-of-file", Action::MoveCursor(EndOfFile)),
+            ("forward-word", Action::MoveCursor(ForwardWord)),
+            ("backward-word", Action::MoveCursor(BackwardWord)),
+            ("kill-line", Action::KillLine),
+            ("kill-region", Action::KillRegion),
+            ("copy-region", Action::CopyRegion),
+            ("set-mark", Action::SetMark),
+            ("yank", Action::Yank),
+            ("yank-pop", Action::YankPop),
+            ("isearch-forward", Action::Isearch),
+            ("backward-delete-word", Action::BackwardDeleteWord),
+            ("backward-delete-line", Action::BackwardDeleteLine),
+            ("normal-command", Action::NormalCommand),
+            ("complete-next", Action::Complete(Source::Keyword, true)),
+            ("complete-previous", Action::Complete(Source::Keyword, false)),
+            ("complete-line", Action::Complete(Source::Line, true)),
+            ("complete-path", Action::Complete(Source::Path, true)),
+            ("complete-dictionary", Action::Complete(Source::Dictionary, true)),
+            ("accept-completion", Action::AcceptCompletion),
+            ("cancel-completion", Action::CancelCompletion),
+            ("complete-server", Action::Complete(Source::Server, true)),
+            ("goto-definition", Action::GotoDefinition),
+            ("hover", Action::Hover),
+            ("references", Action::References),
+            ("code-actions", Action::CodeActions),
+            ("format", Action::Format),
+            ("normal-mode", Action::ChangeMode(ModeType::Normal)),
+            ("insert-mode", Action::ChangeMode(ModeType::Insert)),
+            ("replace-mode", Action::ChangeMode(ModeType::Replace)),
+            ("emacs-mode", Action::ChangeMode(ModeType::Emacs)),
+            ("new-buffer", Action::NewBuffer),
+            ("next-buffer", Action::NextBuffer),
+            ("prev-buffer", Action::PrevBuffer),
+            ("alternate-buffer", Action::AlternateBuffer),
+            ("save", Action::Save),
+            ("quit", Action::Quit),
+        )
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::named().into_iter().find(|&(known, _)| known == name).map(|(_, action)| action)
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        Action::named().into_iter().find(|(_, action)| action == self).map(|(name, _)| name)
+    }
+
+    /// What the action does, shown by the which-key popup.
+    pub fn description(&self) -> String {
+        match *self {
+            Action::PrevBuffer => "previous buffer".into(),
+            Action::Insert(c) => format!("insert {}", c),
+            Action::Replace(c) => format!("replace with {}", c),
+            Action::Feed(ref keys, _) => notation::format_keys(keys),
+            Action::Prompt(ref command) => format!(":{}", command),
+            Action::PasteRegister(register) => format!("paste register {}", register),
+            Action::Multi(ref actions) => actions.iter().map(Action::description).collect::<Vec<String>>().join(", "),
+            Action::Repeat(ref action, times) => format!("{} {} times", action.description(), times),
+            Action::SelectCompletion(true) => "next candidate".into(),
+            Action::SelectCompletion(false) => "previous candidate".into(),
+            Action::PartialKey => "more keys".into(),
+            Action::Complete(source, forward) if self.name().is_none() => {
+                let source = match source {
+                    Source::Keyword => "keyword",
+                    Source::Line => "line",
+                    Source::Path => "path",
+                    Source::Dictionary => "dictionary",
+                    Source::Server => "server",
+                };
+                format!("complete {} {}", source, if forward { "next" } else { "previous" })
+            }
+            _ => self.name().unwrap_or("unnamed action").replace('-', " "),
+        }
+    }
+}
+
 #[derive(Eq,PartialEq,Debug,Clone)]
 pub enum MicroState {
     /// The default microstate. Most events are delegated to the current mode.
     Mode,
     /// When entering data in the minibuffer.
     /// Keystrokes are stored in `minibuffer` until enter or esc is pressed.
-    MiniBuffer
+    MiniBuffer,
+    /// When asking what to do with the swap file found for the file just opened.
+    /// See `swap_prompt`.
+    SwapPrompt,
+    /// When asking whether to reload a file changed by another program.
+    /// See `check_external_changes`.
+    ReloadPrompt,
+    /// When searching as the text is typed, in `minibuffer`. See `Action::Isearch`.
+    Isearch,
+}
+
+/// An incremental search in progress. See `MicroState::Isearch`.
+struct Isearch {
+    /// Position of the cursor when the search started, restored when it's cancelled.
+    origin: Point,
+    /// Beginning of the current match, or where the search started if there's none yet.
+    start: Point,
+    /// Whether the text typed wasn't found.
+    failing: bool,
+}
+
+/// An entry of the quickfix list, e.g. a reference found by the language server. See `:cnext`.
+struct QuickfixEntry {
+    path: String,
+    /// Line and UTF-16 column, as given by the language server.
+    position: (usize, usize),
+    /// The line found, shown when going to it.
+    text: String,
 }
 
 pub struct State<'a> {
@@ -69,6 +281,10 @@ pub struct State<'a> {
     pub minibuffer: String,
     /// Used for instance when entering data in the minibuffer.
     pub microstate: MicroState,
+    /// Editor settings, changed with `:set`.
+    pub options: Options,
+    /// Colors of the editor, changed with `:colorscheme`.
+    pub theme: Theme,
 
     /// Vector of all the open buffers.
     pub buffers: Vec<Rc<RefCell<Buffer>>>,
@@ -78,12 +294,77 @@ pub struct State<'a> {
     // used to cycle through the buffers
     buffer_idx: usize,
     yanked: VecDeque<String>,
+    /// Register `0`, the text last yanked. See `register`.
+    yank_register: Option<String>,
+    /// Registers `1` to `9`, the last deleted texts containing a line break, the last first.
+    delete_registers: VecDeque<String>,
+    /// Register `-`, the text last deleted within a line.
+    small_delete_register: Option<String>,
     previous_action: Option<Action>,
+    /// Existing swap file of the file just opened, and its content if it could be read.
+    swap_prompt: Option<(PathBuf, Option<Swap>)>,
+    /// Keys typed since the swap files were last written. See `updatecount`.
+    keys_since_swap: usize,
+    /// Number of the last buffer created. See `Buffer::number`.
+    last_buffer_number: usize,
+    /// Number of the previously edited buffer. See `AlternateBuffer`.
+    alternate: Option<usize>,
+    /// Cursor positions of the closed buffers, by canonical path. Restored when opening them again.
+    closed_cursors: HashMap<PathBuf, Point>,
+    /// Key mappings defined with `:nmap`, `:inoremap`... by mode. See `Action::Feed`.
+    mappings: HashMap<ModeType, KeyMap>,
+    /// Mappings defined with `<buffer>`, by buffer number then mode. They take precedence in their buffer.
+    buffer_mappings: HashMap<usize, HashMap<ModeType, KeyMap>>,
+    /// Keys waiting to be handled, with whether they can be mapped.
+    /// Typed keys stay there while they may be the beginning of a mapping, and mappings insert their keys in front.
+    typeahead: VecDeque<(Key, bool)>,
+    /// Number of mappings run since the typeahead was last empty. See `MAX_MAP_DEPTH`.
+    map_depth: usize,
+    /// When the last key was typed. See `pending_timeout`.
+    last_key: Instant,
+    /// Whether `timeoutlen` passed since the last key, i.e. the keys pending are taken as they are.
+    timed_out: bool,
+    /// Insert mode abbreviations defined with `:iabbrev`, expanded after a whole word is typed.
+    abbreviations: HashMap<String, String>,
+    /// Commands run on events, defined with `:autocmd`.
+    autocmds: Vec<Autocmd>,
+    /// Whether autocommands are running. Their commands don't trigger others.
+    in_autocmd: bool,
+    /// Where the region begins, set with `SetMark`. See `region`.
+    mark: Option<Point>,
+    /// Start of the text just yanked, and its index in the kill ring. See `YankPop`.
+    last_yank: Option<(Point, usize)>,
+    /// The incremental search in progress, if any.
+    isearch: Option<Isearch>,
+    /// Whether Normal mode was entered for a single command with Ctrl-o. See `NormalCommand`.
+    normal_command: bool,
+    /// The completion in progress, if any, shown as a popup. Closed by any other action.
+    pub completion: Option<Completion>,
+    /// Keys handled by the completion popup. See `completion::popup_keys`.
+    popup_keys: KeyMap,
+    /// Commands starting the language servers, by file type, set with `:LspServer`.
+    lsp_servers: HashMap<String, String>,
+    /// The language servers running, by file type.
+    lsp_clients: HashMap<String, Client>,
+    /// File type and URI of the buffers open in a language server, by buffer number.
+    lsp_documents: HashMap<usize, (String, String)>,
+    /// Completion items just received from the language server. See `Source::Server`.
+    server_candidates: Option<Vec<Candidate>>,
+    /// Code actions last offered by the language server, applied with `:LspCodeAction N`.
+    code_actions: Vec<Json>,
+    /// The document the code actions are for, and its `Buffer::change_id` then. See `document_changed`.
+    code_actions_document: Option<(String, usize)>,
+    /// Locations gone through with `:cnext` and `:cprevious`, e.g. the references to a symbol.
+    quickfix: Vec<QuickfixEntry>,
+    quickfix_idx: usize,
+    /// Lines shown in a popup at the cursor until the next key, e.g. hover information.
+    pub popup: Option<Vec<String>>,
 }
 
 impl<'a> State<'a> {
     pub fn new(width: usize, height: usize) -> State<'a> {
-        let buffer = Buffer::new();
+        let mut buffer = Buffer::new();
+        buffer.number = 1;
         let mut buffers = Vec::new();
         buffers.push(Rc::new(RefCell::new(buffer)));
 
@@ -100,225 +381,3226 @@ impl<'a> State<'a> {
             keystrokes: Vec::new(),
             minibuffer: String::new(),
             microstate: MicroState::Mode,
+            options: Options::new(),
+            theme: Theme::new(),
             yanked: VecDeque::new(),
+            yank_register: None,
+            delete_registers: VecDeque::new(),
+            small_delete_register: None,
             previous_action: None,
+            swap_prompt: None,
+            keys_since_swap: 0,
+            last_buffer_number: 1,
+            alternate: None,
+            closed_cursors: HashMap::new(),
+            mappings: HashMap::new(),
+            buffer_mappings: HashMap::new(),
+            typeahead: VecDeque::new(),
+            map_depth: 0,
+            last_key: Instant::now(),
+            timed_out: false,
+            abbreviations: HashMap::new(),
+            autocmds: Vec::new(),
+            in_autocmd: false,
+            mark: None,
+            last_yank: None,
+            isearch: None,
+            normal_command: false,
+            completion: None,
+            popup_keys: completion::popup_keys(),
+            lsp_servers: HashMap::new(),
+            lsp_clients: HashMap::new(),
+            lsp_documents: HashMap::new(),
+            server_candidates: None,
+            code_actions: Vec::new(),
+            code_actions_document: None,
+            quickfix: Vec::new(),
+            quickfix_idx: 0,
+            popup: None,
         }
     }
 
-    pub fn handle_key(&mut self, key: rustbox::Key) -> bool {
-        self.status = None;
+    /// Load a file in the current buffer, reporting errors and anything unusual in the status bar.
+    /// The buffer is left untouched if the file can't be read.
+    pub fn load_file(&mut self, path: String) {
+        let result = self.buffer.borrow_mut().load_file(path.clone());
+        self.check_loaded_file(&path, result);
+    }
 
-        if self.microstate == MicroState::MiniBuffer {
-            return self.handle_minibuffer_input(key);
+    /// Open a file in a new buffer, optionally forcing its encoding.
+    /// No buffer is created if the file can't be read.
+    /// A file that is already open is switched to instead, reloaded with the encoding if given.
+    fn open_file(&mut self, path: String, encoding: Option<Encoding>) {
+        if let Some(idx) = self.open_buffer_index(&path) {
+            self.switch_to(idx);
+            if encoding.is_some() && self.buffer.borrow().modified() {
+                self.status = Some("No write since last change (add ! to override)".into());
+            } else if encoding.is_some() {
+                self.reload(encoding);
+            }
+            return;
         }
 
-        match key {
-            Key::Char(':') if self.microstate == MicroState::Mode => {
-                self.microstate = MicroState::MiniBuffer;
-                false
+        let mut buffer = Buffer::new();
+        let result = buffer.load_file_with_encoding(path.clone(), encoding);
+        if result.is_ok() {
+            if let Some(cursor) = self.closed_cursors.remove(&file::canonical(Path::new(&path))) {
+                buffer.last_cursor = cursor;
             }
-            _ => {
-                self.keystrokes.push(key);
-                match self.mode.keys_pressed(self.keystrokes.as_slice()) {
-                    Some(Action::PartialKey) => false,
-                    Some(action) => self.execute_action(action),
-                    None => { self.keystrokes = Vec::new(); false }
-                }
+
+            // Like Vim, reuse the empty buffer miv starts with rather than keeping it around.
+            let pristine = {
+                let current = self.buffer.borrow();
+                current.filepath.is_none() && !current.modified() && current.data == vec!("\n")
+            };
+            if pristine {
+                buffer.number = self.buffer.borrow().number;
+                self.cursor = buffer.last_cursor;
+                *self.buffer.borrow_mut() = buffer;
+                self.clamp_cursor();
+            } else {
+                self.add_buffer(buffer);
             }
         }
+        self.check_loaded_file(&path, result);
+    }
 
+    /// Index of the buffer editing a file, if any.
+    fn open_buffer_index(&self, path: &str) -> Option<usize> {
+        self.buffers.iter().position(|buffer| {
+            buffer.borrow().filepath.as_ref().is_some_and(|open| file::same_file(Path::new(open), Path::new(path)))
+        })
     }
 
-    fn execute_action(&mut self, action: Action) -> bool {
-        match action {
-            Action::RepeatPrevious => {
-                if let Some(action) = self.previous_action.clone() {
-                    self.execute_action(action);
+    /// Load the current file again, dropping the unsaved changes, like `:e!`.
+    /// The encoding is detected again unless given, e.g. with `:e ++enc=latin1`.
+    fn reload(&mut self, encoding: Option<Encoding>) {
+        let path = match self.buffer.borrow().filepath.clone() {
+            Some(path) => path,
+            None => { self.status = Some("No file name".into()); return }
+        };
+
+        let result = self.buffer.borrow_mut().load_file_with_encoding(path.clone(), encoding);
+        if result.is_ok() {
+            self.clamp_cursor();
+        }
+        self.check_loaded_file(&path, result);
+    }
+
+    /// Look for changes made to the current buffer's file by other programs, e.g. `git checkout`.
+    /// With `autoread` unmodified buffers are reloaded, otherwise asks what to do.
+    pub fn check_external_changes(&mut self) {
+        // Don't get in the way of a command or another question.
+        if self.microstate != MicroState::Mode { return }
+
+        let change = self.buffer.borrow_mut().check_disk();
+        let name = self.buffer.borrow().name();
+        let modified = self.buffer.borrow().modified();
+
+        match change {
+            DiskChange::Unchanged => {}
+            DiskChange::Deleted => {
+                {
+                    let mut buffer = self.buffer.borrow_mut();
+                    buffer.disk = None;
+                    buffer.new_file = true;
                 }
+                self.status = Some(format!("\"{}\" was deleted, :w to write it again", name));
             }
-            Action::NewLineAtPoint => {
-                self.buffer.borrow_mut().split_line(self.cursor);
-                self.move_cursor(Down);
-                self.move_cursor(BeginningOfLine);
-            }
-            Action::NewLine => {
-                self.buffer.borrow_mut().new_line(self.cursor);
-                self.move_cursor(Down);
-                self.move_cursor(BeginningOfLine);
-                self.switch_mode(ModeType::Insert);
-            }
-            Action::Insert(c) => {
-                self.buffer.borrow_mut().insert(self.cursor, c);
-                self.move_cursor(Right);
+            DiskChange::Changed if self.options.autoread && !modified => {
+                self.reload(None);
+                self.status = Some(format!("\"{}\" changed on disk, reloaded", name));
             }
-            Action::Replace(c) => {
-                self.buffer.borrow_mut().upsert(self.cursor, c);
-                self.move_cursor(Right);
+            DiskChange::Changed => {
+                self.microstate = MicroState::ReloadPrompt;
+                self.status = Some(self.reload_prompt_message());
             }
-            Action::Delete => {
-                let character = self.buffer.borrow_mut().delete(self.cursor);
-                self.yanked.push_front(character.to_string());
+        }
+    }
+
+    fn reload_prompt_message(&self) -> String {
+        let buffer = self.buffer.borrow();
+        let ours = if buffer.modified() { "keep our changes" } else { "keep our version" };
+        format!("\"{}\" changed on disk: [r]eload it, [k]eep {}", buffer.name(), ours)
+    }
+
+    /// Handle the answer to the reload prompt. See `check_external_changes`.
+    fn handle_reload_prompt(&mut self, key: Key) -> bool {
+        match key {
+            Key::Char('r') => {
+                self.microstate = MicroState::Mode;
+                self.reload(None);
             }
-            Action::DeleteLine => {
-                let line = self.buffer.borrow_mut().delete_line(self.cursor);
-                self.yanked.push_front(line);
-                self.move_cursor(BeginningOfLine);
+            Key::Char('k') | Key::Esc => {
+                // Don't ask again until the file changes again. Saving over it won't warn either.
+                self.microstate = MicroState::Mode;
+                let mut buffer = self.buffer.borrow_mut();
+                buffer.disk = buffer.filepath.as_ref().and_then(|path| file::stamp(Path::new(path)).ok());
             }
-            Action::BackwardDelete => {
-                self.move_cursor(Left);
-                self.buffer.borrow_mut().delete(self.cursor);
+            _ => self.status = Some(self.reload_prompt_message()),
+        }
+        false
+    }
+
+    fn check_loaded_file(&mut self, path: &str, result: Result<(), buffer::Error>) {
+        if let Err(error) = result {
+            self.status = Some(format!("\"{}\": {}", path, error));
+            return;
+        }
+
+        let new_file = self.buffer.borrow().new_file;
+        {
+            let buffer = self.buffer.borrow();
+            if buffer.new_file {
+                self.status = Some(format!("\"{}\" [New File]", path));
+            } else if buffer.mixed_line_endings {
+                let status = format!("Mixed line endings, saving as {}", buffer.fileformat.name());
+                self.status = Some(status);
             }
-            Action::Paste => {
-                if self.yanked.is_empty() {
-                    self.status = Some("Nothing to paste!".to_string());
-                } else {
-                    self.paste();
+        }
+
+        self.set_filetype(filetype::detect(path));
+        self.fire(if new_file { Event::BufNewFile } else { Event::BufRead }, Some(path.into()));
+        self.check_swap_file();
+    }
+
+    /// Look for the swap file of the file just loaded in the current buffer.
+    /// Asks what to do if there is one, left by a crash or by another miv editing the same file.
+    /// Otherwise creates ours.
+    fn check_swap_file(&mut self) {
+        if !self.options.swapfile { return }
+
+        let swap_path = match self.buffer.borrow().filepath {
+            Some(ref path) => swap::swap_path(Path::new(path)),
+            None => return,
+        };
+
+        // The same file open twice in this process shares the first buffer's swap file.
+        let owned = |buffer: &Rc<RefCell<Buffer>>| buffer.borrow().swap.as_ref().is_some_and(|swap| swap.path == swap_path);
+        if self.buffers.iter().any(owned) { return }
+
+        if swap_path.exists() {
+            match Swap::read(&swap_path) {
+                // Nothing to recover, just a leftover.
+                Ok(ref swap) if !swap.modified && !swap.in_use() => {}
+                swap => {
+                    self.swap_prompt = Some((swap_path, swap.ok()));
+                    self.microstate = MicroState::SwapPrompt;
+                    self.status = Some(self.swap_prompt_message());
+                    return;
                 }
             }
-            Action::MoveCursor(direction) => {
-                self.move_cursor(direction);
+        }
+
+        self.create_swap_file(swap_path);
+    }
+
+    fn create_swap_file(&mut self, swap_path: PathBuf) {
+        let mut buffer = self.buffer.borrow_mut();
+        let modified = buffer.modified();
+        match SwapFile::create(swap_path, &buffer.data, modified, buffer.change_id) {
+            Ok(swap) => buffer.swap = Some(swap),
+            Err(error) => self.status = Some(format!("Can't create the swap file, recovery impossible: {}", error)),
+        }
+    }
+
+    fn swap_prompt_message(&self) -> String {
+        match self.swap_prompt {
+            Some((_, Some(ref swap))) if swap.in_use() => {
+                let name = self.buffer.borrow().name();
+                format!("\"{}\" is already open in another miv (pid {}): [o]pen read-only, [e]dit anyway, [q]uit", name, swap.pid)
             }
-            Action::ChangeMode(mode_type) => {
-                self.switch_mode(mode_type);
+            Some((ref path, Some(_))) => {
+                format!("Swap file \"{}\" found: [r]ecover, [o]pen read-only, [d]elete it, [e]dit anyway, [q]uit", path.display())
             }
-            Action::Cancel => {
-                self.keystrokes = Vec::new();
-                self.execute_action(Action::ChangeMode(ModeType::Normal));
+            Some((ref path, None)) => {
+                format!("Unreadable swap file \"{}\" found: [o]pen read-only, [d]elete it, [e]dit anyway, [q]uit", path.display())
             }
-            Action::Save => {
-                let bytes = self.buffer.borrow_mut().save_file();
-                if bytes > 0 {
-                    let path = self.buffer.borrow_mut().filepath.clone().unwrap(); // We know the filepath is set
-                    let status = format!("Saved \"{}\" ({} bytes)", path, bytes);
-                    self.status = Some(status);
-                }
+            None => String::new(),
+        }
+    }
+
+    /// Handle the answer to the swap file prompt. See `check_swap_file`.
+    fn handle_swap_prompt(&mut self, key: Key) -> bool {
+        let (swap_path, swap) = self.swap_prompt.take().unwrap();
+        let in_use = swap.as_ref().is_some_and(Swap::in_use);
+
+        match key {
+            Key::Char('r') if swap.is_some() && !in_use => {
+                self.buffer.borrow_mut().set_lines(swap.unwrap().lines);
+                self.create_swap_file(swap_path);
+                self.status = Some("Recovered the unsaved changes, :w to keep them".into());
             }
-            Action::YankLine => {
-                if let Some(line) = self.buffer.borrow_mut().line_at(self.cursor.y) {
-                    self.yanked.push_front(line.clone());
-                }
+            Key::Char('o') => {
+                self.buffer.borrow_mut().readonly = true;
             }
-            Action::NewBuffer => {
-                let buffer = Buffer::new();
-                self.buffer = Rc::new(RefCell::new(buffer));
-                self.buffers.push(self.buffer.clone());
-                self.buffer_idx += 1;
+            Key::Char('d') if !in_use => {
+                let _ = fs::remove_file(&swap_path);
+                self.create_swap_file(swap_path);
             }
-            Action::NextBuffer => {
-                self.buffer_idx = (self.buffer_idx + 1) % self.buffers.len();
-                self.buffer = self.buffers[self.buffer_idx].clone();
+            Key::Char('e') => {
+                self.status = Some("Editing without a swap file".into());
             }
-            Action::PrevBuffer => {
-                self.buffer_idx = (self.buffer_idx.wrapping_sub(1)) % self.buffers.len();
-                self.buffer = self.buffers[self.buffer_idx].clone();
+            Key::Char('q') | Key::Esc => {
+                self.abandon_buffer();
             }
-            Action::Multi(ref actions) => {
-                let mut result = false;
-                for action in actions { result = self.execute_action(action.clone()); }
-                return result
+            _ => {
+                self.swap_prompt = Some((swap_path, swap));
+                self.status = Some(self.swap_prompt_message());
+                return false;
             }
-            Action::Repeat(ref action, times) => {
-                for _ in 0..times { self.execute_action(*action.clone()); }
+        }
+
+        self.microstate = MicroState::Mode;
+        false
+    }
+
+    /// Close the current buffer without saving it, leaving an empty one if it's the last one.
+    fn abandon_buffer(&mut self) {
+        let idx = self.buffer_idx;
+        self.delete_buffer(idx, true);
+    }
+
+    /// Write the swap files of the buffers changed since the last time.
+    /// Called after `updatecount` keys and after `updatetime` milliseconds without typing.
+    pub fn update_swap_files(&mut self) {
+        self.keys_since_swap = 0;
+        if !self.options.swapfile {
+            return self.remove_swap_files();
+        }
+
+        for buffer in &self.buffers {
+            if let Err(error) = buffer.borrow_mut().update_swap() {
+                self.status = Some(format!("Can't write the swap file: {}", error));
             }
-            Action::Quit => { return true },
-            _ => {},
         }
+    }
 
-        if action != Action::RepeatPrevious && action != Action::Cancel {
-            self.previous_action = Some(action);
+    /// Delete the swap files of all the buffers, when quitting.
+    pub fn remove_swap_files(&mut self) {
+        for buffer in &self.buffers {
+            if let Some(swap) = buffer.borrow_mut().swap.take() {
+                swap.remove();
+            }
         }
+    }
 
-        self.keystrokes = Vec::new();
-        false
+    /// Give a buffer a number and make it the current one.
+    fn add_buffer(&mut self, mut buffer: Buffer) {
+        self.last_buffer_number += 1;
+        buffer.number = self.last_buffer_number;
+        self.buffers.push(Rc::new(RefCell::new(buffer)));
+        let idx = self.buffers.len() - 1;
+        self.switch_to(idx);
     }
 
-    fn switch_mode(&mut self, mode_type: ModeType) {
-        if let Some(action) = self.mode.on_exit() {
-            self.execute_action(action);
+    /// Make the buffer at `idx` the current one.
+    /// The cursor position is saved in the buffer we leave, which becomes the alternate one.
+    fn switch_to(&mut self, idx: usize) {
+        if Rc::ptr_eq(&self.buffer, &self.buffers[idx]) { return }
+
+        self.buffer.borrow_mut().last_cursor = self.cursor;
+        self.alternate = Some(self.buffer.borrow().number);
+        self.buffer_idx = idx;
+        self.buffer = self.buffers[idx].clone();
+        self.cursor = self.buffer.borrow().last_cursor;
+        self.mark = None;
+        self.clamp_cursor();
+        self.check_external_changes();
+
+        let path = self.buffer.borrow().filepath.clone();
+        self.fire(Event::BufEnter, path);
+    }
+
+    /// Go back to the previous buffer, like `:e #` or Ctrl-^.
+    fn switch_to_alternate(&mut self) {
+        match self.alternate.and_then(|number| self.buffer_index(number)) {
+            Some(idx) => self.switch_to(idx),
+            None => self.status = Some("No alternate file".into()),
         }
+    }
 
-        self.mode_type = mode_type;
-        self.mode = match mode_type {
-            ModeType::Insert =>  Box::new(InsertMode::new()) as Box<Mode>,
-            ModeType::Normal =>  Box::new(NormalMode::new()) as Box<Mode>,
-            ModeType::Replace => Box::new(ReplaceMode::new()) as Box<Mode>,
+    fn buffer_index(&self, number: usize) -> Option<usize> {
+        self.buffers.iter().position(|buffer| buffer.borrow().number == number)
+    }
+
+    /// Find a buffer from its number, a unique part of its name, or `#` for the alternate one, like `:b`.
+    fn find_buffer(&mut self, arg: &str) -> Option<usize> {
+        let found = if arg == "#" || arg == "%" {
+            let number = if arg == "#" { self.alternate } else { Some(self.buffer.borrow().number) };
+            number.and_then(|number| self.buffer_index(number)).ok_or_else(|| "No alternate file".to_string())
+        } else if let Ok(number) = arg.parse() {
+            self.buffer_index(number).ok_or_else(|| format!("Buffer {} does not exist", number))
+        } else {
+            let names: Vec<String> = self.buffers.iter().map(|buffer| buffer.borrow().name()).collect();
+            let partial: Vec<usize> = (0..names.len()).filter(|&idx| names[idx].contains(arg)).collect();
+            match names.iter().position(|name| name == arg) {
+                Some(idx) => Ok(idx),
+                None if partial.len() == 1 => Ok(partial[0]),
+                None if partial.is_empty() => Err(format!("No matching buffer for {}", arg)),
+                None => Err(format!("More than one match for {}", arg)),
+            }
         };
+
+        found.map_err(|message| self.status = Some(message)).ok()
     }
 
-    fn move_cursor(&mut self, direction: Direction) {
-        let mut cur = self.cursor.with_direction(direction);
+    /// Close a buffer, like `:bd`. Its cursor position is remembered for when its file is opened
+    /// again, unless `wipe` (`:bw`). Closing the last buffer leaves an empty one.
+    fn delete_buffer(&mut self, idx: usize, wipe: bool) {
+        let buffer = self.buffers.remove(idx);
+        self.detach_language_server(&buffer);
+        let current = Rc::ptr_eq(&buffer, &self.buffer);
+        {
+            let mut buffer = buffer.borrow_mut();
+            if let Some(swap) = buffer.swap.take() {
+                swap.remove();
+            }
+            if let Some(path) = buffer.filepath.clone() {
+                let cursor = if current { self.cursor } else { buffer.last_cursor };
+                if !wipe {
+                    self.closed_cursors.insert(file::canonical(Path::new(&path)), cursor);
+                }
+            }
+            if self.alternate == Some(buffer.number) {
+                self.alternate = None;
+            }
+            self.buffer_mappings.remove(&buffer.number);
+        }
 
-        match direction {
-            EndOfLine => { cur.x = usize::max_value() } // This is so ugly...
-            EndOfFile => { cur.y = self.buffer.borrow_mut().line_len() - 1 }
-            _ => {}
+        if self.buffers.is_empty() {
+            self.last_buffer_number += 1;
+            let mut empty = Buffer::new();
+            empty.number = self.last_buffer_number;
+            self.buffers.push(Rc::new(RefCell::new(empty)));
         }
 
-        let max_x = self.buffer.borrow_mut().last_non_empty_col(cur);
-        let max_y = self.buffer.borrow_mut().line_len() - 1;
-        cur.clamp_by(max_x, max_y);
+        if current {
+            // Like Vim, go to the alternate buffer if any, which then has no alternate itself.
+            let next = self.alternate.and_then(|number| self.buffer_index(number))
+                .unwrap_or(cmp::min(idx, self.buffers.len() - 1));
+            self.alternate = None;
+            self.buffer_idx = next;
+            self.buffer = self.buffers[next].clone();
+            self.cursor = self.buffer.borrow().last_cursor;
+            self.clamp_cursor();
+            self.check_external_changes();
+        } else if idx < self.buffer_idx {
+            self.buffer_idx -= 1;
+        }
+    }
 
-        self.cursor = cur;
+    /// Handle `:bd`, `:bw` and their `!` variants, for the current buffer or the one given.
+    fn delete_buffer_command(&mut self, command: &str, arg: Option<&str>) {
+        let idx = match arg {
+            Some(arg) => match self.find_buffer(arg) { Some(idx) => idx, None => return },
+            None => self.buffer_idx,
+        };
+
+        let force = command.ends_with('!');
+        let wipe = command.starts_with("bw");
+        if self.buffers[idx].borrow().modified() && !force {
+            let number = self.buffers[idx].borrow().number;
+            self.status = Some(format!("No write since last change for buffer {} (add ! to override)", number));
+            return;
+        }
+        self.delete_buffer(idx, wipe);
     }
 
-    fn paste(&mut self) {
-        let mut yanked = self.yanked.front().unwrap().clone();
+    /// Show the open buffers, like `:ls`, with their number, their name and the line the cursor is on.
+    /// Flags: `%` current buffer, `#` alternate buffer, `=` read-only, `+` modified.
+    fn list_buffers(&mut self) {
+        let list: Vec<String> = self.buffers.iter().map(|buffer| {
+            let current = Rc::ptr_eq(buffer, &self.buffer);
+            let buffer = buffer.borrow();
+            let mut flags = String::new();
+            if current {
+                flags.push('%');
+            } else if self.alternate == Some(buffer.number) {
+                flags.push('#');
+            }
+            if buffer.readonly { flags.push('=') }
+            if buffer.modified() { flags.push('+') }
 
-        // Pasting a new line
-        if let Some(_) = yanked.rfind('\n') {
-            self.buffer.borrow_mut().new_line(self.cursor);
-            self.move_cursor(Down);
-            self.move_cursor(BeginningOfLine);
-            yanked.pop(); // remove the last \n
-        } else {
-            self.move_cursor(Right);
+            let line = if current { self.cursor.y } else { buffer.last_cursor.y } + 1;
+            format!("{}{} \"{}\" line {}", buffer.number, flags, buffer.name(), line)
+        }).collect();
+        self.status = Some(list.join(" | "));
+    }
+
+    /// Save the current buffer, returning whether it succeeded.
+    /// `force` allows saving read-only buffers, like `:w!`. Asks for a path if the buffer has none.
+    fn save(&mut self, force: bool) -> bool {
+        if self.buffer.borrow().filepath.is_none() {
+            self.prompt("w ");
+            return false;
         }
 
-        self.buffer.borrow_mut().insert_text(self.cursor, yanked);
+        let buffer = self.buffer.clone();
+        self.save_buffer(&buffer, force)
     }
 
-    fn handle_minibuffer_input(&mut self, key: Key) -> bool {
-        match key {
-            Key::Char(c) => {
-                self.minibuffer.push(c);
+    /// Save every modified buffer, like `:wa`. Returns whether they all were saved.
+    fn save_all(&mut self, force: bool) -> bool {
+        let buffers = self.buffers.clone();
+        let mut failure = None;
+        for buffer in buffers.iter().filter(|buffer| buffer.borrow().modified()) {
+            if !self.save_buffer(buffer, force) && failure.is_none() {
+                failure = self.status.clone();
             }
-            Key::Backspace => {
-                self.minibuffer.pop();
-            }
-            Key::Enter => {
-                return self.handle_minibuffer_command()
+        }
+
+        // Show the first error rather than whatever was saved last.
+        if failure.is_some() {
+            self.status = failure;
+            return false;
+        }
+        true
+    }
+
+    /// Save a buffer, returning whether it succeeded.
+    /// The buffer is kept as is on failure, and the error is shown in the status bar.
+    fn save_buffer(&mut self, buffer: &Rc<RefCell<Buffer>>, force: bool) -> bool {
+        if self.options.fixendofline {
+            buffer.borrow_mut().endofline = true;
+        }
+
+        let path = buffer.borrow().filepath.clone();
+        self.fire(Event::BufWritePre, path.clone());
+
+        let new_file = buffer.borrow().new_file;
+        let result = buffer.borrow_mut().save_file(self.options.backup, force);
+        let name = buffer.borrow().name();
+        match result {
+            Ok(bytes) => {
+                let new = if new_file { " [New]" } else { "" };
+                self.status = Some(format!("Saved \"{}\"{} ({} bytes)", name, new, bytes));
+                self.did_save(buffer);
+                self.fire(Event::BufWritePost, path);
+                true
             }
-            Key::Esc => {
-                self.microstate = MicroState::Mode;
-                self.minibuffer = String::new();
+            Err(error) => {
+                self.status = Some(format!("\"{}\" not saved: {}", name, error));
+                false
             }
-            _ => {}
         }
-        false
     }
 
-    // TODO: Extract this. I think this deserves its own module.
-    fn handle_minibuffer_command(&mut self) -> bool {
-        let result = match self.minibuffer.clone().split_whitespace().collect::<Vec<&str>>().as_slice() {
-            &["w"] => self.execute_action(Action::Save),
-            &["q"] => self.execute_action(Action::Quit),
-            &["wq"] => self.execute_action(Action::Multi(vec!(Action::Save, Action::Quit))),
-            &["new"] => self.execute_action(Action::NewBuffer),
-            &["bn"] => self.execute_action(Action::NextBuffer),
-            &["bp"] => self.execute_action(Action::PrevBuffer),
-            &["e", path] => {
-                self.execute_action(Action::NewBuffer);
-                self.buffer.borrow_mut().load_file(path.into());
-                false
+    /// Whether the editor can quit, like `:q`. Buffers with unsaved changes prevent it unless forced.
+    fn quit(&mut self, force: bool) -> bool {
+        if force { return true }
+
+        if self.buffer.borrow().modified() {
+            self.status = Some("No write since last change (add ! to override)".into());
+            return false;
+        }
+
+        if let Some(idx) = self.buffers.iter().position(|buffer| buffer.borrow().modified()) {
+            let name = self.buffers[idx].borrow().name();
+            self.status = Some(format!("No write since last change for buffer {} \"{}\" (add ! to override)", idx + 1, name));
+            return false;
+        }
+
+        true
+    }
+
+    /// Give the current buffer another file and save it there, like `:saveas path`.
+    /// An existing file is only overwritten when forced.
+    fn save_as(&mut self, path: &str, force: bool) -> bool {
+        let exists = Path::new(path).exists();
+        let own_file = self.buffer.borrow().filepath.as_ref().is_some_and(|own| file::same_file(Path::new(own), Path::new(path)));
+        if exists && !own_file && !force {
+            self.status = Some(format!("\"{}\": File exists (add ! to override)", path));
+            return false;
+        }
+
+        // The buffer only takes the new name once it has been saved there.
+        let previous = {
+            let mut buffer = self.buffer.borrow_mut();
+            let previous = (buffer.filepath.take(), buffer.new_file, buffer.readonly);
+            buffer.filepath = Some(path.into());
+            buffer.new_file = !exists;
+            buffer.readonly = false;
+            previous
+        };
+        let saved = self.save(force);
+        if !saved {
+            let mut buffer = self.buffer.borrow_mut();
+            let (filepath, new_file, readonly) = previous;
+            buffer.filepath = filepath;
+            buffer.new_file = new_file;
+            buffer.readonly = readonly;
+        }
+
+        // The swap file follows the buffer, unless someone else already has one for the new file.
+        if saved && !own_file {
+            if let Some(swap) = self.buffer.borrow_mut().swap.take() {
+                swap.remove();
             }
-            _ => {
-                self.status = Some(format!("Not a valid command: {}", self.minibuffer));
-                false
+            let swap_path = swap::swap_path(Path::new(path));
+            if self.options.swapfile && !swap_path.exists() {
+                self.create_swap_file(swap_path);
             }
+        }
+        saved
+    }
+
+    /// Write the current buffer, or some of its lines, like `:w`, `:w path` or `:1,3w >> path`.
+    ///
+    /// Writing the whole buffer to its own file saves it. An unnamed buffer takes the name of
+    /// the file it's written to. Existing files other than the buffer's own, and parts of the
+    /// buffer over its own file, are only written when forced.
+    fn write(&mut self, range: Option<LineRange>, path: Option<&str>, append: bool, force: bool) -> bool {
+        let own_path = self.buffer.borrow().filepath.clone();
+        let path: String = match path.map(String::from).or_else(|| own_path.clone()) {
+            Some(path) => path,
+            None => { self.prompt("w "); return false }
         };
+        let own_file = own_path.as_ref().is_some_and(|own| file::same_file(Path::new(own), Path::new(&path)));
 
-        self.microstate = MicroState::Mode;
-        self.minibuffer = String::new();
-        result
+        if !append {
+            if range.is_none() && own_file {
+                return self.save(force);
+            }
+            if range.is_none() && own_path.is_none() {
+                return self.save_as(&path, force);
+            }
+            if own_file && !force {
+                self.status = Some("Use ! to write part of a buffer to its own file".into());
+                return false;
+            }
+            if !own_file && !force && Path::new(&path).exists() {
+                self.status = Some(format!("\"{}\": File exists (add ! to override)", path));
+                return false;
+            }
+        }
+
+        let last = self.buffer.borrow().line_len() - 1;
+        let range = range.unwrap_or(LineRange { start: 0, end: last });
+        let result = self.buffer.borrow().write_lines(Path::new(&path), range.start, range.end, append, self.options.backup);
+        match result {
+            Ok(bytes) => {
+                let verb = if append { "Appended to" } else { "Written" };
+                self.status = Some(format!("{} \"{}\" ({} bytes)", verb, path, bytes));
+                true
+            }
+            Err(error) => {
+                self.status = Some(format!("\"{}\" not written: {}", path, error));
+                false
+            }
+        }
+    }
+
+    /// Pipe the current buffer, or some of its lines, to a shell command, like `:w !wc -l`.
+    /// What the command prints is shown in the status bar.
+    fn write_to_command(&mut self, range: Option<LineRange>, command: &str) {
+        let last = self.buffer.borrow().line_len() - 1;
+        let range = range.unwrap_or(LineRange { start: 0, end: last });
+        let bytes = match self.buffer.borrow().encode_lines(range.start, range.end, false) {
+            Ok(bytes) => bytes,
+            Err(error) => { self.status = Some(error.to_string()); return }
+        };
+
+        self.status = Some(match file::pipe_to_command(command, bytes) {
+            Ok((status, output)) => {
+                let output = output.trim_end().replace('\n', "  ");
+                match status.code() {
+                    Some(0) => output,
+                    Some(code) => format!("{}  [shell returned {}]", output, code).trim_start().into(),
+                    None => format!("{}  [shell killed]", output).trim_start().into(),
+                }
+            }
+            Err(error) => format!("Can't run the command: {}", error),
+        });
+    }
+
+    /// Open the minibuffer with some text already typed, e.g. to ask for a file name.
+    fn prompt(&mut self, text: &str) {
+        self.microstate = MicroState::MiniBuffer;
+        self.minibuffer = text.into();
+    }
+
+    pub fn handle_key(&mut self, key: rustbox::Key) -> bool {
+        self.status = None;
+        self.popup = None;
+
+        self.keys_since_swap += 1;
+        if self.options.updatecount > 0 && self.keys_since_swap >= self.options.updatecount {
+            self.update_swap_files();
+        }
+
+        self.last_key = Instant::now();
+        self.timed_out = false;
+        self.typeahead.push_back((key, true));
+        self.run_typeahead()
+    }
+
+    /// Time left before the keys that may be the beginning of a longer mapping or key binding
+    /// are taken as they are, then `handle_timeout` should be called. `None` when there are no such keys.
+    pub fn pending_timeout(&self) -> Option<Duration> {
+        let pending = !self.typeahead.is_empty() || !self.keystrokes.is_empty();
+        if !pending || self.timed_out || !self.options.timeout {
+            return None;
+        }
+
+        let timeoutlen = Duration::from_millis(self.options.timeoutlen as u64);
+        Some(timeoutlen.checked_sub(self.last_key.elapsed()).unwrap_or(Duration::from_secs(0)))
+    }
+
+    /// Keys typed that aren't handled yet: the beginning of a key binding, then of a mapping.
+    pub fn pending_keys(&self) -> Vec<Key> {
+        self.keystrokes.iter().cloned().chain(self.typeahead.iter().map(|&(key, _)| key)).collect()
+    }
+
+    /// Time left before the which-key popup shows, `None` when it won't or already does.
+    pub fn which_key_timeout(&self) -> Option<Duration> {
+        if !self.options.whichkey || self.pending_continuations().is_empty() {
+            return None;
+        }
+
+        let delay = Duration::from_millis(self.options.whichkeydelay as u64);
+        delay.checked_sub(self.last_key.elapsed()).filter(|left| *left > Duration::from_secs(0))
+    }
+
+    /// The keys that can follow the ones typed, with what they do,
+    /// once no key was typed for `whichkeydelay`. Shown in a popup by the view.
+    pub fn which_key(&self) -> Option<Vec<(Key, String)>> {
+        let delay = Duration::from_millis(self.options.whichkeydelay as u64);
+        if !self.options.whichkey || self.last_key.elapsed() < delay {
+            return None;
+        }
+
+        let continuations = self.pending_continuations();
+        if continuations.is_empty() { None } else { Some(continuations) }
+    }
+
+    /// The keys that can follow the beginning of a mapping, or else of a binding of the mode.
+    fn pending_continuations(&self) -> Vec<(Key, String)> {
+        if self.microstate != MicroState::Mode {
+            return Vec::new();
+        }
+
+        if !self.typeahead.is_empty() {
+            let keys: Vec<Key> = self.typeahead.iter().map(|&(key, _)| key).collect();
+            let mut continuations: Vec<(Key, String)> = Vec::new();
+            for mappings in self.current_mappings() {
+                for (key, description) in mappings.continuations(&keys) {
+                    if !continuations.iter().any(|&(known, _)| known == key) {
+                        continuations.push((key, description));
+                    }
+                }
+            }
+            continuations.sort_by_key(|&(key, _)| notation::format_keys(&[key]));
+            continuations
+        } else if !self.keystrokes.is_empty() {
+            self.mode.continuations(&self.keystrokes)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Run the shorter mapping or key binding when no more keys were typed for `timeoutlen`,
+    /// e.g. `g` when both `g` and `gg` are bound. The keys that only begin a mapping are handled unmapped.
+    pub fn handle_timeout(&mut self) -> bool {
+        self.timed_out = true;
+        if self.run_typeahead() { return true }
+
+        if self.keystrokes.is_empty() { return false }
+        let keystrokes = self.keystrokes.clone();
+        if self.completion.is_some() {
+            if let Some(action) = self.popup_keys.timed_out(&keystrokes) {
+                return self.execute_action(action);
+            }
+        }
+        match self.mode.keys_timed_out(&keystrokes) {
+            Some(action) => self.execute_action(action),
+            None => false,
+        }
+    }
+
+    /// Handle the keys waiting in `typeahead`, running the mappings they match.
+    /// Stops early when the next keys may be the beginning of a mapping, until more are typed.
+    fn run_typeahead(&mut self) -> bool {
+        while let Some(&(key, _)) = self.typeahead.front() {
+            let keys: Vec<Key> = if self.microstate == MicroState::Mode {
+                self.typeahead.iter().take_while(|&&(_, remap)| remap).map(|&(key, _)| key).collect()
+            } else {
+                Vec::new()
+            };
+            let (matched, len) = if keys.is_empty() { (KeyMatch::None, 0) } else { self.match_mappings(&keys) };
+
+            match matched {
+                // Keys that can't be mapped can't complete a mapping either.
+                KeyMatch::Partial | KeyMatch::Ambiguous(_) if keys.len() == self.typeahead.len() && !self.timed_out => {
+                    self.map_depth = 0;
+                    return false;
+                }
+                KeyMatch::Action(action) | KeyMatch::Ambiguous(action) => {
+                    self.typeahead.drain(..len);
+                    self.map_depth += 1;
+                    if self.map_depth > MAX_MAP_DEPTH {
+                        self.typeahead.clear();
+                        self.map_depth = 0;
+                        self.status = Some("Recursive mapping".into());
+                        return false;
+                    }
+                    if self.execute_action(action) { return true }
+                }
+                _ => {
+                    self.typeahead.pop_front();
+                    if self.handle_unmapped_key(key) { return true }
+                }
+            }
+        }
+
+        self.map_depth = 0;
+        false
+    }
+
+    /// The mappings of the current mode, those of the current buffer first. See `buffer_mappings`.
+    fn current_mappings(&self) -> Vec<&KeyMap> {
+        let number = self.buffer.borrow().number;
+        let local = self.buffer_mappings.get(&number).and_then(|modes| modes.get(&self.mode_type));
+        local.into_iter().chain(self.mappings.get(&self.mode_type)).collect()
+    }
+
+    /// The mapping some keys match, with the number of keys it takes.
+    /// The mappings of the buffer win over the global ones.
+    fn match_mappings(&self, keys: &[Key]) -> (KeyMatch, usize) {
+        let mut partial = false;
+        for mappings in self.current_mappings() {
+            match mappings.match_keys(keys) {
+                KeyMatch::None => {}
+                KeyMatch::Partial => partial = true,
+                // A longer mapping of the buffer may still match.
+                KeyMatch::Action(action) if partial => return (KeyMatch::Ambiguous(action), mapping_len(mappings, keys)),
+                matched => return (matched, mapping_len(mappings, keys)),
+            }
+        }
+        (if partial { KeyMatch::Partial } else { KeyMatch::None }, 0)
+    }
+
+    fn handle_unmapped_key(&mut self, key: Key) -> bool {
+        if self.microstate == MicroState::MiniBuffer {
+            return self.handle_minibuffer_input(key);
+        }
+
+        if self.microstate == MicroState::SwapPrompt {
+            return self.handle_swap_prompt(key);
+        }
+
+        if self.microstate == MicroState::ReloadPrompt {
+            return self.handle_reload_prompt(key);
+        }
+
+        if self.microstate == MicroState::Isearch {
+            return self.handle_isearch_key(key);
+        }
+
+        self.handle_mode_key(key)
+    }
+
+    /// Handle a key with the current mode's own bindings.
+    fn handle_mode_key(&mut self, key: Key) -> bool {
+        match key {
+            Key::Char(':') if self.mode_type == ModeType::Normal => {
+                self.microstate = MicroState::MiniBuffer;
+                false
+            }
+            _ => {
+                self.keystrokes.push(key);
+                if let Some(selected) = self.completion.as_ref().map(|completion| completion.selected) {
+                    let keys = self.keystrokes.clone();
+                    match self.popup_keys.match_keys(&keys) {
+                        // Without a selected candidate, Enter starts a new line as usual.
+                        KeyMatch::Action(Action::AcceptCompletion) if key == Key::Enter && selected.is_none() => {
+                            self.completion = None;
+                        }
+                        KeyMatch::Partial | KeyMatch::Ambiguous(_) => return false,
+                        KeyMatch::Action(action) => {
+                            // Keys typed past a shorter binding, e.g. Esc then `x`, are handled after it.
+                            let len = mapping_len(&self.popup_keys, &keys);
+                            for &key in keys[len..].iter().rev() {
+                                self.typeahead.push_front((key, false));
+                            }
+                            return self.execute_action(action);
+                        }
+                        KeyMatch::None => self.completion = None,
+                    }
+                }
+                match self.mode.keys_pressed(self.keystrokes.as_slice()) {
+                    Some(Action::PartialKey) => false,
+                    Some(action) => {
+                        // Keys typed past a shorter binding, e.g. Esc then `a` in Emacs mode, are handled after it.
+                        let len = self.mode.bound_len(&self.keystrokes);
+                        for &key in self.keystrokes[len..].iter().rev() {
+                            self.typeahead.push_front((key, false));
+                        }
+                        let normal_command = self.normal_command && self.mode_type == ModeType::Normal;
+                        let quit = self.execute_action(action);
+                        if normal_command { self.end_normal_command() }
+                        quit
+                    }
+                    None => { self.keystrokes = Vec::new(); false }
+                }
+            }
+        }
+    }
+
+    fn execute_action(&mut self, action: Action) -> bool {
+        if (self.mode_type == ModeType::Insert || self.mode_type == ModeType::Emacs) && ends_word(&action) {
+            self.expand_abbreviation();
+        }
+
+        match action {
+            Action::Complete(..) | Action::SelectCompletion(_) | Action::AcceptCompletion | Action::CancelCompletion => {}
+            Action::Feed(..) | Action::PartialKey => {}
+            _ => self.completion = None,
+        }
+
+        match action {
+            Action::Feed(ref keys, remap) => {
+                // Handled next by `run_typeahead`, before the keys typed after them.
+                for &key in keys.iter().rev() {
+                    self.typeahead.push_front((key, remap));
+                }
+                return false;
+            }
+            Action::RepeatPrevious => {
+                if let Some(action) = self.previous_action.clone() {
+                    self.execute_action(action);
+                }
+            }
+            Action::NewLineAtPoint => {
+                self.buffer.borrow_mut().split_line(self.cursor);
+                self.move_cursor(Down);
+                self.move_cursor(BeginningOfLine);
+            }
+            Action::NewLine => {
+                self.buffer.borrow_mut().new_line(self.cursor);
+                self.move_cursor(Down);
+                self.move_cursor(BeginningOfLine);
+                self.switch_mode(ModeType::Insert);
+            }
+            Action::Insert(c) => {
+                // A combining character merges with the previous grapheme and doesn't move the cursor.
+                let before = self.buffer.borrow().grapheme_len(self.cursor.y);
+                self.buffer.borrow_mut().insert(self.cursor, c);
+                if self.buffer.borrow().grapheme_len(self.cursor.y) > before {
+                    self.move_cursor(Right);
+                }
+                if self.options.autocomplete && self.mode_type == ModeType::Insert && completion::is_keyword_char(c) {
+                    self.autocomplete();
+                }
+            }
+            Action::Replace(c) => {
+                self.buffer.borrow_mut().upsert(self.cursor, c);
+                self.move_cursor(Right);
+            }
+            Action::Delete => {
+                let (at_line_end, last_line) = {
+                    let buffer = self.buffer.borrow();
+                    let at_line_end = buffer.data[self.cursor.y].graphemes(true).nth(self.cursor.x) == Some("\n");
+                    (at_line_end, self.cursor.y + 1 == buffer.line_len())
+                };
+                if !at_line_end {
+                    let character = self.buffer.borrow_mut().delete(self.cursor);
+                    self.store_delete(character);
+                } else if !last_line && self.mode_type != ModeType::Normal {
+                    // Deleting the line ending joins the next line, except in Normal mode like Vim's `x`.
+                    let end = Point::new(0, self.cursor.y + 1);
+                    let newline = self.buffer.borrow_mut().delete_range(self.cursor, end);
+                    self.yanked.push_front(newline);
+                }
+            }
+            Action::DeleteLine => {
+                let line = self.buffer.borrow_mut().delete_line(self.cursor);
+                self.store_delete(line);
+                self.move_cursor(BeginningOfLine);
+            }
+            Action::BackwardDelete => {
+                for _ in 0..self.backward_delete_len() {
+                    self.move_cursor(Left);
+                    self.buffer.borrow_mut().delete(self.cursor);
+                }
+            }
+            Action::Tab => {
+                self.insert_tab();
+            }
+            Action::Indent => {
+                self.shift_line(true);
+            }
+            Action::Dedent => {
+                self.shift_line(false);
+            }
+            Action::Paste => {
+                if self.yanked.is_empty() {
+                    self.status = Some("Nothing to paste!".to_string());
+                } else {
+                    self.paste();
+                }
+            }
+            Action::MoveCursor(direction) => {
+                self.move_cursor(direction);
+            }
+            Action::ChangeMode(mode_type) => {
+                self.switch_mode(mode_type);
+            }
+            Action::Cancel => {
+                self.keystrokes = Vec::new();
+                self.mark = None;
+                let mode_type = self.base_mode();
+                self.execute_action(Action::ChangeMode(mode_type));
+            }
+            Action::Save => {
+                self.save(false);
+            }
+            Action::YankLine => {
+                if let Some(line) = self.buffer.borrow_mut().line_at(self.cursor.y) {
+                    self.yank_register = Some(line.clone());
+                    self.yanked.push_front(line.clone());
+                }
+            }
+            Action::NewBuffer => {
+                self.add_buffer(Buffer::new());
+            }
+            Action::NextBuffer => {
+                let idx = (self.buffer_idx + 1) % self.buffers.len();
+                self.switch_to(idx);
+            }
+            Action::PrevBuffer => {
+                let idx = (self.buffer_idx + self.buffers.len() - 1) % self.buffers.len();
+                self.switch_to(idx);
+            }
+            Action::AlternateBuffer => {
+                self.switch_to_alternate();
+            }
+            Action::KillLine => {
+                self.kill_line();
+            }
+            Action::KillRegion => {
+                self.kill_region(true);
+            }
+            Action::CopyRegion => {
+                self.kill_region(false);
+            }
+            Action::SetMark => {
+                self.mark = Some(self.cursor);
+                self.status = Some("Mark set".into());
+            }
+            Action::Yank => {
+                self.yank(0);
+            }
+            Action::YankPop => {
+                self.yank_pop();
+            }
+            Action::Isearch => {
+                self.microstate = MicroState::Isearch;
+                self.minibuffer = String::new();
+                self.isearch = Some(Isearch { origin: self.cursor, start: self.cursor, failing: false });
+            }
+            Action::Prompt(ref command) => {
+                self.prompt(command);
+            }
+            Action::BackwardDeleteWord => {
+                self.backward_delete_word();
+            }
+            Action::BackwardDeleteLine => {
+                self.backward_delete_line();
+            }
+            Action::NormalCommand => {
+                self.switch_mode(ModeType::Normal);
+                self.normal_command = true;
+            }
+            Action::PasteRegister(register) => {
+                match self.register(register) {
+                    Some(text) => self.cursor = self.buffer.borrow_mut().insert_lines(self.cursor, &text),
+                    // TODO: named registers, e.g. `"ayy` then `Ctrl-r a`.
+                    None if register.is_ascii_alphabetic() => self.status = Some("Named registers are not supported yet".into()),
+                    None => self.status = Some(format!("Nothing in register {}", register)),
+                }
+            }
+            Action::Complete(source, forward) => {
+                self.complete(source, forward);
+            }
+            Action::SelectCompletion(forward) => {
+                self.select_completion(forward);
+            }
+            Action::AcceptCompletion => {
+                self.completion = None;
+            }
+            Action::CancelCompletion => {
+                if let Some(ref mut completion) = self.completion {
+                    completion.selected = None;
+                }
+                self.replace_completed_text();
+                self.completion = None;
+            }
+            Action::GotoDefinition => {
+                self.lsp_request("textDocument/definition", Vec::new(), Request::Definition);
+            }
+            Action::Hover => {
+                self.lsp_request("textDocument/hover", Vec::new(), Request::Hover);
+            }
+            Action::References => {
+                let context = object(vec!(("includeDeclaration", true.into())));
+                self.lsp_request("textDocument/references", vec!(("context", context)), Request::References);
+            }
+            Action::CodeActions => {
+                self.request_code_actions();
+            }
+            Action::Format => {
+                self.format();
+            }
+            Action::Multi(ref actions) => {
+                let mut result = false;
+                for action in actions { result = self.execute_action(action.clone()); }
+                return result
+            }
+            Action::Repeat(ref action, times) => {
+                for _ in 0..times { self.execute_action(*action.clone()); }
+            }
+            Action::Quit => { return self.quit(false) },
+            _ => {},
+        }
+
+        if action != Action::RepeatPrevious && action != Action::Cancel {
+            self.previous_action = Some(action);
+        }
+
+        self.keystrokes = Vec::new();
+        false
+    }
+
+    fn switch_mode(&mut self, mode_type: ModeType) {
+        if let Some(action) = self.mode.on_exit() {
+            self.execute_action(action);
+        }
+
+        self.mode_type = mode_type;
+        self.mode = match mode_type {
+            ModeType::Insert =>  Box::new(InsertMode::new()) as Box<Mode>,
+            ModeType::Normal =>  Box::new(NormalMode::new()) as Box<Mode>,
+            ModeType::Replace => Box::new(ReplaceMode::new()) as Box<Mode>,
+            ModeType::Emacs => Box::new(EmacsMode::new()) as Box<Mode>,
+        };
+    }
+
+    /// Go back to Insert mode after the command typed after Ctrl-o. See `NormalCommand`.
+    fn end_normal_command(&mut self) {
+        if !self.normal_command { return }
+        self.normal_command = false;
+        if self.mode_type == ModeType::Normal {
+            self.switch_mode(ModeType::Insert);
+        }
+    }
+
+    /// The mode to go back to when escaping from the others: Emacs with `:set emacs`, Normal otherwise.
+    fn base_mode(&self) -> ModeType {
+        if self.options.emacs { ModeType::Emacs } else { ModeType::Normal }
+    }
+
+    fn move_cursor(&mut self, direction: Direction) {
+        if direction == ScreenUp || direction == ScreenDown {
+            return self.move_cursor_on_screen(direction);
+        }
+        if direction == ForwardWord || direction == BackwardWord {
+            return self.move_cursor_by_word(direction);
+        }
+
+        let mut cur = self.cursor.with_direction(direction);
+
+        match direction {
+            EndOfLine => { cur.x = usize::max_value() } // This is so ugly...
+            EndOfFile => { cur.y = self.buffer.borrow_mut().line_len() - 1 }
+            _ => {}
+        }
+
+        let max_x = self.buffer.borrow_mut().last_non_empty_col(cur);
+        let max_y = self.buffer.borrow_mut().line_len() - 1;
+        cur.clamp_by(max_x, max_y);
+
+        self.cursor = cur;
+    }
+
+    /// Moves the cursor by one screen row, keeping the same screen column when possible.
+    /// Without wrapping this is the same as moving up or down.
+    fn move_cursor_on_screen(&mut self, direction: Direction) {
+        if !self.options.wrap {
+            return self.move_cursor(if direction == ScreenUp { Up } else { Down });
+        }
+
+        let line_len = self.buffer.borrow().line_len();
+        let width = layout::text_width(&self.options, self.width, line_len);
+        let showbreak = layout::showbreak_width(&self.options);
+        let starts_at = |y: usize| layout::wrap_line(&self.buffer.borrow().data[y], width, &self.options);
+        let widths_at = |y: usize| layout::cell_widths(&self.buffer.borrow().data[y], self.options.tabstop);
+
+        // Screen column of the cursor within its row
+        let starts = starts_at(self.cursor.y);
+        let row = layout::row_at(&starts, self.cursor.x);
+        let col = layout::display_col(&widths_at(self.cursor.y), starts[row], self.cursor.x)
+            + if row > 0 { showbreak } else { 0 };
+
+        let (y, starts, row) = match direction {
+            ScreenDown if row + 1 < starts.len() => (self.cursor.y, starts, row + 1),
+            ScreenDown if self.cursor.y + 1 < line_len => (self.cursor.y + 1, starts_at(self.cursor.y + 1), 0),
+            ScreenUp if row > 0 => (self.cursor.y, starts, row - 1),
+            ScreenUp if self.cursor.y > 0 => {
+                let starts = starts_at(self.cursor.y - 1);
+                let last = starts.len() - 1;
+                (self.cursor.y - 1, starts, last)
+            }
+            _ => return,
+        };
+
+        let col = col.saturating_sub(if row > 0 { showbreak } else { 0 });
+        let mut x = layout::grapheme_at_col(&widths_at(y), starts[row], col);
+        if let Some(&next) = starts.get(row + 1) {
+            x = cmp::min(x, next - 1);
+        }
+
+        let mut cur = Point::new(x, y);
+        let max_x = self.buffer.borrow_mut().last_non_empty_col(cur);
+        cur.clamp_by(max_x, line_len - 1);
+        self.cursor = cur;
+    }
+
+    /// Moves the cursor past the end of the next word, or to the beginning of the previous one, across lines.
+    fn move_cursor_by_word(&mut self, direction: Direction) {
+        let buffer = self.buffer.borrow();
+        let lines: Vec<Vec<&str>> = buffer.data.iter().map(|line| line.graphemes(true).collect()).collect();
+        let is_word = |point: Point| lines[point.y].get(point.x).is_some_and(|grapheme| is_keyword(grapheme));
+        let next = |point: Point| if point.x + 1 < lines[point.y].len() {
+            Some(Point::new(point.x + 1, point.y))
+        } else if point.y + 1 < lines.len() {
+            Some(Point::new(0, point.y + 1))
+        } else {
+            None
+        };
+        let previous = |point: Point| if point.x > 0 {
+            Some(Point::new(point.x - 1, point.y))
+        } else if point.y > 0 {
+            Some(Point::new(lines[point.y - 1].len().saturating_sub(1), point.y - 1))
+        } else {
+            None
+        };
+
+        let mut cur = self.cursor;
+        if direction == ForwardWord {
+            while !is_word(cur) {
+                match next(cur) { Some(point) => cur = point, None => break }
+            }
+            while is_word(cur) {
+                match next(cur) { Some(point) => cur = point, None => break }
+            }
+        } else {
+            while let Some(point) = previous(cur).filter(|&point| !is_word(point)) { cur = point }
+            while let Some(point) = previous(cur).filter(|&point| is_word(point)) { cur = point }
+        }
+        self.cursor = cur;
+    }
+
+    /// Move the cursor back inside the buffer, e.g. after it was reloaded.
+    fn clamp_cursor(&mut self) {
+        let max_y = self.buffer.borrow().line_len() - 1;
+        let y = cmp::min(self.cursor.y, max_y);
+        let max_x = self.buffer.borrow_mut().last_non_empty_col(Point::new(0, y));
+        self.cursor.clamp_by(max_x, max_y);
+    }
+
+    /// Screen column of the cursor, i.e. taking wide characters and tabs into account.
+    fn cursor_col(&self) -> usize {
+        let widths = layout::cell_widths(&self.buffer.borrow().data[self.cursor.y], self.options.tabstop);
+        layout::display_col(&widths, 0, self.cursor.x)
+    }
+
+    /// Inserts whitespace up to the next multiple of `softtabstop`, or `tabstop` if not set.
+    fn insert_tab(&mut self) {
+        let col = self.cursor_col();
+        let step = if self.options.softtabstop > 0 { self.options.softtabstop } else { self.options.tabstop };
+        let whitespace = layout::indent_string(&self.options, col, (col / step + 1) * step);
+        let len = whitespace.chars().count();
+
+        self.buffer.borrow_mut().insert_text(self.cursor, whitespace);
+        self.cursor.x += len;
+    }
+
+    /// Number of characters removed by a backspace.
+    /// With `softtabstop`, spaces are removed up to the previous stop as if they were a tab.
+    fn backward_delete_len(&self) -> usize {
+        let step = self.options.softtabstop;
+        if step == 0 || self.cursor.x == 0 { return 1 }
+
+        let buffer = self.buffer.borrow();
+        let before: Vec<&str> = buffer.data[self.cursor.y].graphemes(true).take(self.cursor.x).collect();
+        let mut col = self.cursor_col();
+        let stop = (col - 1) / step * step;
+
+        let mut len = 0;
+        while len < before.len() && before[before.len() - 1 - len] == " " && col > stop {
+            len += 1;
+            col -= 1;
+        }
+
+        cmp::max(len, 1)
+    }
+
+    /// Adds or removes `shiftwidth` columns of indentation to the current line,
+    /// then moves the cursor to the first non blank character. Empty lines are left alone.
+    /// When typing, e.g. with Ctrl-t in Insert mode, the cursor stays on the same character instead,
+    /// and empty lines are indented too.
+    fn shift_line(&mut self, indent: bool) {
+        let y = self.cursor.y;
+        let typing = self.mode_type != ModeType::Normal;
+        let (leading, width) = {
+            let buffer = self.buffer.borrow();
+            let line = &buffer.data[y];
+            if line == "\n" && !typing { return }
+
+            let leading = line.graphemes(true).take_while(|&g| g == " " || g == "\t").count();
+            let widths = layout::cell_widths(line, self.options.tabstop);
+            (leading, layout::display_col(&widths, 0, leading))
+        };
+
+        let shift = self.options.shift_width();
+        let target = if indent { width + shift } else { width.saturating_sub(shift) };
+        let whitespace = layout::indent_string(&self.options, 0, target);
+        let len = whitespace.chars().count();
+
+        let mut buffer = self.buffer.borrow_mut();
+        for _ in 0..leading {
+            buffer.delete(Point::new(0, y));
+        }
+        buffer.insert_text(Point::new(0, y), whitespace);
+        self.cursor.x = if typing && self.cursor.x > leading { self.cursor.x - leading + len } else { len };
+        self.cursor.x = cmp::min(self.cursor.x, buffer.last_non_empty_col(self.cursor));
+    }
+
+    /// Delete the word before the cursor and the blanks after it, within the line.
+    /// A word is made of keyword characters, or of other non blank ones.
+    fn backward_delete_word(&mut self) {
+        let start = {
+            let buffer = self.buffer.borrow();
+            let before: Vec<&str> = buffer.data[self.cursor.y].graphemes(true).take(self.cursor.x).collect();
+            let mut x = before.len();
+            while x > 0 && is_blank(before[x - 1]) { x -= 1 }
+            if x > 0 {
+                let keyword = is_keyword(before[x - 1]);
+                while x > 0 && !is_blank(before[x - 1]) && is_keyword(before[x - 1]) == keyword { x -= 1 }
+            }
+            x
+        };
+
+        let start = Point::new(start, self.cursor.y);
+        self.buffer.borrow_mut().delete_range(start, self.cursor);
+        self.cursor = start;
+    }
+
+    /// Delete back to the indentation, like Ctrl-u in Insert mode, or to the beginning of the line from there.
+    fn backward_delete_line(&mut self) {
+        let indent = self.buffer.borrow().data[self.cursor.y].graphemes(true).take_while(|grapheme| is_blank(grapheme)).count();
+        let start = Point::new(if self.cursor.x > indent { indent } else { 0 }, self.cursor.y);
+        self.buffer.borrow_mut().delete_range(start, self.cursor);
+        self.cursor = start;
+    }
+
+    /// The content of a register, for `PasteRegister`, as in Vim: `"` is the text last yanked or deleted,
+    /// `0` the text last yanked, `1` to `9` and `-` the texts deleted, see `store_delete`,
+    /// `%` the file name and `#` the alternate one. There are no named registers yet.
+    fn register(&self, register: char) -> Option<String> {
+        match register {
+            '"' => self.yanked.front().cloned(),
+            '0' => self.yank_register.clone(),
+            '1'..='9' => self.delete_registers.get(register as usize - '1' as usize).cloned(),
+            '-' => self.small_delete_register.clone(),
+            '%' => self.buffer.borrow().filepath.clone(),
+            '#' => self.buffers.iter()
+                .find(|buffer| Some(buffer.borrow().number) == self.alternate)
+                .and_then(|buffer| buffer.borrow().filepath.clone()),
+            _ => None,
+        }
+    }
+
+    /// Keep deleted text in the kill ring and the registers, like Vim: in `1` when it contains a line break,
+    /// the previous ones going to `2` and so on up to `9`, in `-` otherwise.
+    fn store_delete(&mut self, text: String) {
+        if text.contains('\n') {
+            self.delete_registers.push_front(text.clone());
+            self.delete_registers.truncate(9);
+        } else {
+            self.small_delete_register = Some(text.clone());
+        }
+        self.yanked.push_front(text);
+    }
+
+    /// Show the content of the registers in `names` that aren't empty in a popup, like `:registers`.
+    /// It's written in key notation, e.g. `<CR>` for a line break.
+    fn list_registers(&mut self, names: &str) {
+        let lines: Vec<String> = names.chars().filter_map(|name| {
+            let keys: Vec<Key> = self.register(name)?.chars().map(|c| match c {
+                '\n' => Key::Enter,
+                '\t' => Key::Tab,
+                c => Key::Char(c),
+            }).collect();
+            Some(format!("\"{}   {}", name, notation::format_keys(&keys)))
+        }).collect();
+        if lines.is_empty() {
+            self.status = Some("Nothing in the registers".into());
+        } else {
+            self.popup = Some(lines);
+        }
+    }
+
+    /// Complete the text before the cursor from `source`, like Ctrl-n, Ctrl-p or Ctrl-x Ctrl-f in Insert mode,
+    /// and select the first candidate, or the last one when not `forward`.
+    /// Completing from the language server sends it a request, then completes with the items received.
+    fn complete(&mut self, source: Source, forward: bool) {
+        if source == Source::Server && self.server_candidates.is_none() {
+            let cursor = self.cursor;
+            let uri = self.lsp_documents.get(&self.buffer.borrow().number).map(|(_, uri)| uri.clone());
+            if let Some(uri) = uri {
+                self.lsp_request("textDocument/completion", Vec::new(), Request::Completion(uri, cursor));
+            } else {
+                self.status = Some(NO_LANGUAGE_SERVER.into());
+            }
+            return;
+        }
+
+        match self.new_completion(source, forward) {
+            Ok(completion) => {
+                self.completion = Some(completion);
+                self.select_completion(forward);
+            }
+            Err(message) => {
+                self.completion = None;
+                self.status = Some(message);
+            }
+        }
+    }
+
+    /// Show the keywords completing the word being typed, without selecting one. See `autocomplete`.
+    fn autocomplete(&mut self) {
+        if self.word_before_cursor().graphemes(true).count() >= 2 {
+            self.completion = self.new_completion(Source::Keyword, true).ok();
+        }
+    }
+
+    /// The completion of the text before the cursor, with the candidates found from `source`.
+    fn new_completion(&self, source: Source, forward: bool) -> Result<Completion, String> {
+        let prefix = match source {
+            Source::Keyword | Source::Dictionary | Source::Server => self.word_before_cursor(),
+            Source::Line => self.line_before_cursor(),
+            Source::Path => self.path_before_cursor(),
+        };
+        let mut candidates = match source {
+            Source::Keyword | Source::Line => completion::rank(&prefix, self.buffer_candidates(source, forward)),
+            Source::Path => completion::path_candidates(&prefix),
+            Source::Dictionary => {
+                if self.options.dictionary.is_empty() {
+                    return Err("No dictionary, see :set dictionary".into());
+                }
+                let words = completion::dictionary_words(Path::new(&file::expand_home(&self.options.dictionary)))?;
+                completion::rank(&prefix, words)
+            }
+            Source::Server => completion::rank(&prefix, self.server_candidates.clone().unwrap_or_default()),
+        };
+        if candidates.is_empty() {
+            return Err("Pattern not found".into());
+        }
+
+        if !forward {
+            // The best last, i.e. selected first going backward.
+            candidates.reverse();
+        }
+        let start = Point::new(self.cursor.x - prefix.graphemes(true).count(), self.cursor.y);
+        Ok(Completion::new(start, prefix, candidates))
+    }
+
+    /// Select the next or previous candidate of the completion in progress, replacing the completed text with it.
+    fn select_completion(&mut self, forward: bool) {
+        let status = match self.completion {
+            Some(ref mut completion) => {
+                completion.select(forward);
+                match completion.selected {
+                    Some(idx) => format!("match {} of {}", idx + 1, completion.candidates.len()),
+                    None => "Back at original".into(),
+                }
+            }
+            None => return,
+        };
+        self.replace_completed_text();
+        self.status = Some(status);
+    }
+
+    /// Replace the text from the start of the completion to the cursor with the selected candidate.
+    fn replace_completed_text(&mut self) {
+        let (start, text) = match self.completion {
+            Some(ref completion) => (completion.start, completion.text().to_string()),
+            None => return,
+        };
+        self.buffer.borrow_mut().delete_range(start, self.cursor);
+        self.cursor = self.buffer.borrow_mut().insert_lines(start, &text);
+    }
+
+    /// The keywords or lines of the buffers, the closest to the cursor first when searching `forward`,
+    /// or last otherwise. Those of the current buffer come before those of the others.
+    fn buffer_candidates(&self, source: Source, forward: bool) -> Vec<Candidate> {
+        let collect: fn(&str, &str, &mut HashSet<String>, &mut Vec<Candidate>) = match source {
+            Source::Line => completion::collect_lines,
+            _ => completion::collect_keywords,
+        };
+        let (mut seen, mut candidates) = (HashSet::new(), Vec::new());
+        {
+            let buffer = self.buffer.borrow();
+            let (y, data) = (self.cursor.y, &buffer.data);
+            // The line being completed isn't a candidate, but its words are.
+            let (before, after) = if source == Source::Line {
+                (String::new(), String::new())
+            } else {
+                let line: Vec<&str> = data[y].graphemes(true).collect();
+                (line[..self.cursor.x].concat(), line[self.cursor.x..].concat())
+            };
+            let mut texts: Vec<&str> = Some(after.as_str()).into_iter()
+                .chain(data[y + 1..].iter().map(String::as_str))
+                .chain(data[..y].iter().map(String::as_str))
+                .chain(Some(before.as_str()))
+                .collect();
+            if !forward {
+                // Searching backward from the cursor, i.e. the current line first.
+                texts.reverse();
+            }
+            for text in texts {
+                let mut found = Vec::new();
+                collect(text, "", &mut seen, &mut found);
+                if !forward { found.reverse() }
+                candidates.append(&mut found);
+            }
+        }
+
+        for buffer in self.buffers.iter().filter(|buffer| !Rc::ptr_eq(buffer, &self.buffer)) {
+            let buffer = buffer.borrow();
+            let name = buffer.name();
+            let menu = Path::new(&name).file_name().map_or(name.clone(), |name| name.to_string_lossy().into_owned());
+            for line in &buffer.data {
+                collect(line, &menu, &mut seen, &mut candidates);
+            }
+        }
+        candidates
+    }
+
+    /// The text of the current line before the cursor, without its indentation. Completed with Ctrl-x Ctrl-l.
+    fn line_before_cursor(&self) -> String {
+        let buffer = self.buffer.borrow();
+        let before: Vec<&str> = buffer.data[self.cursor.y].graphemes(true).take(self.cursor.x).collect();
+        let indent = before.iter().take_while(|grapheme| is_blank(grapheme)).count();
+        before[indent..].concat()
+    }
+
+    /// The file name before the cursor, completed with Ctrl-x Ctrl-f. See `completion::is_path_char`.
+    fn path_before_cursor(&self) -> String {
+        let buffer = self.buffer.borrow();
+        let before: Vec<&str> = buffer.data[self.cursor.y].graphemes(true).take(self.cursor.x).collect();
+        let len = before.iter().rev().take_while(|grapheme| grapheme.chars().all(completion::is_path_char)).count();
+        before[before.len() - len..].concat()
+    }
+
+    /// The keyword characters right before the cursor, e.g. the word being typed.
+    fn word_before_cursor(&self) -> String {
+        let buffer = self.buffer.borrow();
+        let before: Vec<&str> = buffer.data[self.cursor.y].graphemes(true).take(self.cursor.x).collect();
+        let len = before.iter().rev().take_while(|grapheme| is_keyword(grapheme)).count();
+        before[before.len() - len..].concat()
+    }
+
+    fn paste(&mut self) {
+        let mut yanked = self.yanked.front().unwrap().clone();
+
+        // Pasting a new line
+        if let Some(_) = yanked.rfind('\n') {
+            self.buffer.borrow_mut().new_line(self.cursor);
+            self.move_cursor(Down);
+            self.move_cursor(BeginningOfLine);
+            yanked.pop(); // remove the last \n
+        } else {
+            self.move_cursor(Right);
+        }
+
+        self.buffer.borrow_mut().insert_text(self.cursor, yanked);
+    }
+
+    /// The text between the mark and the cursor, as its start and end, when the mark is set.
+    pub fn region(&self) -> Option<(Point, Point)> {
+        let buffer = self.buffer.borrow();
+        self.mark.map(|mark| {
+            // The text may have changed since the mark was set.
+            let y = cmp::min(mark.y, buffer.line_len() - 1);
+            let mark = Point::new(cmp::min(mark.x, buffer.grapheme_len(y).saturating_sub(1)), y);
+            if (mark.y, mark.x) <= (self.cursor.y, self.cursor.x) { (mark, self.cursor) } else { (self.cursor, mark) }
+        })
+    }
+
+    /// Kill the rest of the line, or join the next one at the end of a line, like Emacs' `C-k`.
+    /// Consecutive kills go to the same entry of the kill ring, i.e. `yanked`.
+    fn kill_line(&mut self) {
+        let len = self.buffer.borrow().grapheme_len(self.cursor.y);
+        let end = if self.cursor.x + 1 < len {
+            Point::new(len - 1, self.cursor.y)
+        } else if self.cursor.y + 1 < self.buffer.borrow().line_len() {
+            Point::new(0, self.cursor.y + 1)
+        } else {
+            return;
+        };
+
+        let text = self.buffer.borrow_mut().delete_range(self.cursor, end);
+        if self.previous_action == Some(Action::KillLine) && !self.yanked.is_empty() {
+            self.yanked[0].push_str(&text);
+        } else {
+            self.yanked.push_front(text);
+        }
+    }
+
+    /// Kill or copy the region to the kill ring, like Emacs' `C-w` and `M-w`. The mark is unset.
+    fn kill_region(&mut self, delete: bool) {
+        let (start, end) = match self.region() {
+            Some(region) => region,
+            None => { self.status = Some("The mark is not set now, so there is no region".into()); return }
+        };
+
+        let text = if delete {
+            self.cursor = start;
+            self.buffer.borrow_mut().delete_range(start, end)
+        } else {
+            self.buffer.borrow().text_range(start, end)
+        };
+        self.yanked.push_front(text);
+        self.mark = None;
+    }
+
+    /// Insert an entry of the kill ring at the cursor, like Emacs' `C-y`, and move after it.
+    fn yank(&mut self, idx: usize) {
+        let text = match self.yanked.get(idx) {
+            Some(text) => text.clone(),
+            None => { self.status = Some("Kill ring is empty".into()); return }
+        };
+        let start = self.cursor;
+        self.cursor = self.buffer.borrow_mut().insert_lines(start, &text);
+        self.last_yank = Some((start, idx));
+    }
+
+    /// Replace the text just yanked with the previous entry of the kill ring, like Emacs' `M-y`.
+    fn yank_pop(&mut self) {
+        let (start, idx) = match (&self.previous_action, self.last_yank) {
+            (&Some(Action::Yank), Some(yank)) | (&Some(Action::YankPop), Some(yank)) => yank,
+            _ => { self.status = Some("Previous command was not a yank".into()); return }
+        };
+        self.buffer.borrow_mut().delete_range(start, self.cursor);
+        self.cursor = start;
+        let len = self.yanked.len();
+        self.yank((idx + 1) % len);
+    }
+
+    /// What the minibuffer shows during an incremental search, e.g. `I-search: text`.
+    pub fn isearch_prompt(&self) -> String {
+        let failing = self.isearch.as_ref().is_some_and(|isearch| isearch.failing);
+        format!("{}I-search: {}", if failing { "Failing " } else { "" }, self.minibuffer)
+    }
+
+    /// Handle a key during an incremental search. The text is searched as it's typed, `C-s` finds
+    /// the next match, Enter stops there and `C-g` goes back to where the search started.
+    /// Other keys stop the search and are then handled as usual.
+    fn handle_isearch_key(&mut self, key: Key) -> bool {
+        let (origin, start) = match self.isearch {
+            Some(ref isearch) => (isearch.origin, isearch.start),
+            None => return false,
+        };
+
+        match key {
+            Key::Char(c) => {
+                self.minibuffer.push(c);
+                self.isearch_from(start);
+            }
+            Key::Backspace => {
+                self.minibuffer.pop();
+                self.isearch_from(origin);
+            }
+            Key::Ctrl('s') => self.isearch_from(start.offset(1, 0)),
+            Key::Ctrl('g') => {
+                self.cursor = origin;
+                self.stop_isearch();
+            }
+            Key::Enter => self.stop_isearch(),
+            _ => {
+                self.stop_isearch();
+                return self.handle_unmapped_key(key);
+            }
+        }
+        false
+    }
+
+    /// Move the cursor after the next match of the text searched, starting at `location`.
+    fn isearch_from(&mut self, location: Point) {
+        let found = if self.minibuffer.is_empty() {
+            self.isearch.as_ref().map(|isearch| isearch.origin)
+        } else {
+            self.buffer.borrow().find(&self.minibuffer, location)
+        };
+
+        if let Some(ref mut isearch) = self.isearch {
+            match found {
+                Some(start) => {
+                    isearch.start = start;
+                    isearch.failing = false;
+                    self.cursor = Point::new(start.x + self.minibuffer.graphemes(true).count(), start.y);
+                }
+                None => isearch.failing = true,
+            }
+        }
+    }
+
+    fn stop_isearch(&mut self) {
+        self.microstate = MicroState::Mode;
+        self.minibuffer = String::new();
+        self.isearch = None;
+    }
+
+    fn handle_minibuffer_input(&mut self, key: Key) -> bool {
+        match key {
+            Key::Char(c) => {
+                self.minibuffer.push(c);
+            }
+            Key::Backspace => {
+                self.minibuffer.pop();
+            }
+            Key::Enter => {
+                return self.handle_minibuffer_command()
+            }
+            Key::Esc => {
+                self.microstate = MicroState::Mode;
+                self.minibuffer = String::new();
+                self.end_normal_command();
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn handle_minibuffer_command(&mut self) -> bool {
+        // Reset first, commands can prompt for more input.
+        let minibuffer = mem::take(&mut self.minibuffer);
+        self.microstate = MicroState::Mode;
+        let quit = self.execute_command(&minibuffer);
+        if self.microstate == MicroState::Mode {
+            self.end_normal_command();
+        }
+        quit
+    }
+
+    // TODO: Extract this. I think this deserves its own module.
+    /// Run a command line, as typed after `:`. Returns whether the editor should quit.
+    fn execute_command(&mut self, minibuffer: &str) -> bool {
+        let last = self.buffer.borrow().line_len() - 1;
+        let (range, command) = match command::parse_range(minibuffer, self.cursor.y, last) {
+            Ok(parsed) => parsed,
+            Err(message) => { self.status = Some(message); return false }
+        };
+        let words = command.split_whitespace().collect::<Vec<&str>>();
+        let args = command.trim_start().split_once(char::is_whitespace).map_or("", |(_, args)| args.trim());
+        let unnamed = self.buffer.borrow().filepath.is_none();
+        let modified = self.buffer.borrow().modified();
+
+        if range.is_some() && !(words.is_empty() || words[0] == "w" || words[0] == "w!") {
+            self.status = Some(format!("No range allowed: {}", minibuffer));
+            return false;
+        }
+
+        if let Some((mode, kind)) = words.first().and_then(|word| parse_map_command(word)) {
+            self.map_command(mode, kind, args);
+            return false;
+        }
+
+        match words.as_slice() {
+            &[] => {
+                // A bare range jumps to its last line.
+                if let Some(range) = range {
+                    self.cursor = Point::new(0, range.end);
+                }
+                false
+            }
+            _ if words[0] == "w" && args.starts_with('!') => { self.write_to_command(range, &args[1..]); false }
+            &["w"] => { self.write(range, None, false, false); false }
+            &["w!"] => { self.write(range, None, false, true); false }
+            &["w", ..] | &["w!", ..] => {
+                match args.strip_prefix(">>").map(str::trim) {
+                    Some("") => self.write(range, None, true, false),
+                    Some(path) => self.write(range, Some(path), true, false),
+                    None => self.write(range, Some(args), false, words[0] == "w!"),
+                };
+                false
+            }
+            &["sav", _, ..] | &["saveas", _, ..] => { self.save_as(args, false); false }
+            &["sav!", _, ..] | &["saveas!", _, ..] => { self.save_as(args, true); false }
+            &["q"] | &["qa"] | &["qall"] => self.quit(false),
+            &["q!"] | &["qa!"] | &["qall!"] => self.quit(true),
+            &["wa"] | &["wall"] => { self.save_all(false); false }
+            &["wa!"] | &["wall!"] => { self.save_all(true); false }
+            &["wqa"] | &["wqall"] | &["xa"] | &["xall"] => self.save_all(false) && self.quit(false),
+            &["wq"] | &["wq!"] if unnamed => { self.prompt(&format!("{} ", words[0])); false }
+            &["x"] | &["xit"] if unnamed && modified => { self.prompt(&format!("{} ", words[0])); false }
+            &["wq"] => self.save(false) && self.quit(false),
+            &["wq!"] => self.save(true) && self.quit(false),
+            &["x"] | &["xit"] => (!modified || self.save(false)) && self.quit(false),
+            &["wq", _, ..] | &["x", _, ..] | &["xit", _, ..] => self.write(None, Some(args), false, false) && self.quit(false),
+            &["wq!", _, ..] => self.write(None, Some(args), false, true) && self.quit(false),
+            &["new"] => self.execute_action(Action::NewBuffer),
+            &["bn"] => self.execute_action(Action::NextBuffer),
+            &["bp"] => self.execute_action(Action::PrevBuffer),
+            &["ls"] | &["buffers"] | &["files"] => { self.list_buffers(); false }
+            &["b", arg] | &["buffer", arg] => {
+                if let Some(idx) = self.find_buffer(arg) {
+                    self.switch_to(idx);
+                }
+                false
+            }
+            &[command] if command.starts_with('b') && command[1..].parse::<usize>().is_ok() => {
+                if let Some(idx) = self.find_buffer(&command[1..]) {
+                    self.switch_to(idx);
+                }
+                false
+            }
+            &[command] if is_buffer_delete(command) => { self.delete_buffer_command(command, None); false }
+            &[command, arg] if is_buffer_delete(command) => { self.delete_buffer_command(command, Some(arg)); false }
+            &["e", "#"] => { self.switch_to_alternate(); false }
+            &["e"] | &["e", _] if modified && (words.len() == 1 || words[1].starts_with("++enc=")) => {
+                self.status = Some("No write since last change (add ! to override)".into());
+                false
+            }
+            &["e"] | &["e!"] => {
+                self.reload(None);
+                false
+            }
+            &["e", option] | &["e!", option] if option.starts_with("++enc=") => {
+                if let Some(encoding) = self.parse_encoding(&option[6..]) {
+                    self.reload(Some(encoding));
+                }
+                false
+            }
+            &["e", option, path] if option.starts_with("++enc=") => {
+                if let Some(encoding) = self.parse_encoding(&option[6..]) {
+                    self.open_file(path.into(), Some(encoding));
+                }
+                false
+            }
+            &["e", path] => {
+                self.open_file(path.into(), None);
+                false
+            }
+            _ if words.first() == Some(&"set") => {
+                self.set_options(&words[1..]);
+                false
+            }
+            _ if is_abbreviate(words[0]) => { self.abbreviate(args); false }
+            _ if words[0] == "autocmd" || words[0] == "au" => { self.add_autocmds(args); false }
+            &["colorscheme", name] | &["colo", name] => {
+                match Theme::named(name) {
+                    Some(theme) => self.theme = theme,
+                    None => self.status = Some(format!("Cannot find color scheme: {}", name)),
+                }
+                false
+            }
+            &["source", path] | &["so", path] => { self.load_config(Path::new(&file::expand_home(path))); false }
+            _ if words[0] == "LspServer" => { self.language_server_command(args); false }
+            &["LspDefinition"] => self.execute_action(Action::GotoDefinition),
+            &["LspHover"] => self.execute_action(Action::Hover),
+            &["LspReferences"] => self.execute_action(Action::References),
+            &["LspFormat"] => self.execute_action(Action::Format),
+            &["LspRename", name] => {
+                let uri = self.lsp_documents.get(&self.buffer.borrow().number).map(|(_, uri)| uri.clone());
+                if let Some(uri) = uri {
+                    let change_id = self.buffer.borrow().change_id;
+                    self.lsp_request("textDocument/rename", vec!(("newName", name.into())), Request::Rename(uri, change_id));
+                } else {
+                    self.status = Some(NO_LANGUAGE_SERVER.into());
+                }
+                false
+            }
+            &["LspCodeAction"] => self.execute_action(Action::CodeActions),
+            &["LspCodeAction", n] => {
+                match n.parse() {
+                    Ok(n) => self.apply_code_action(n),
+                    Err(_) => self.status = Some(format!("Invalid number: {}", n)),
+                }
+                false
+            }
+            &["cn"] | &["cnext"] => { self.quickfix_command(1, true); false }
+            &["cp"] | &["cprevious"] | &["cN"] | &["cNext"] => { self.quickfix_command(-1, true); false }
+            &["cc"] => { self.quickfix_command(0, true); false }
+            &["cc", n] => {
+                match n.parse::<usize>() {
+                    Ok(n) if n > 0 => self.quickfix_command(n as isize - 1, false),
+                    _ => self.status = Some(format!("Invalid number: {}", n)),
+                }
+                false
+            }
+            &["cl"] | &["clist"] => { self.list_quickfix(); false }
+            &["reg"] | &["registers"] | &["di"] | &["display"] => { self.list_registers(REGISTERS); false }
+            &["reg", names] | &["registers", names] | &["di", names] | &["display", names] => { self.list_registers(names); false }
+            _ => {
+                self.status = Some(format!("Not a valid command: {}", minibuffer));
+                false
+            }
+        }
+    }
+
+    /// Run the commands of a configuration file, like `:source`. See `config`.
+    /// Errors don't stop it, they are reported once done with their line number.
+    pub fn load_config(&mut self, path: &Path) {
+        let commands = match config::read(path) {
+            Ok(commands) => commands,
+            Err(error) => {
+                self.status = Some(format!("\"{}\": {}", path.display(), buffer::Error::Io(error)));
+                return;
+            }
+        };
+
+        let mut errors = Vec::new();
+        for (line, command) in commands {
+            self.status = None;
+            self.execute_command(&command);
+            if let Some(message) = self.status.take() {
+                errors.push(format!("line {}: {}", line, message));
+            }
+        }
+
+        self.status = errors.first().map(|error| {
+            let more = if errors.len() > 1 { format!(" (+{} more errors)", errors.len() - 1) } else { String::new() };
+            format!("\"{}\" {}{}", path.display(), error, more)
+        });
+    }
+
+    /// Run a mapping command, e.g. `:nnoremap <C-s> :w<CR>`. See `parse_map_command`.
+    /// Without a right-hand side, lists the mappings starting with the given keys, or all of them.
+    /// With `<buffer>` first, only the mappings of the current buffer are concerned.
+    fn map_command(&mut self, mode: char, kind: MapKind, args: &str) {
+        let modes: &[ModeType] = match mode {
+            'n' => &[ModeType::Normal],
+            'i' => &[ModeType::Insert, ModeType::Replace],
+            _ => { self.status = Some("Visual mode isn't supported yet".into()); return }
+        };
+
+        let (local, args) = match args.strip_prefix("<buffer>") { Some(rest) => (true, rest.trim_start()), None => (false, args) };
+        let (lhs, rhs) = command::split_word(args);
+        let result = match kind {
+            MapKind::Unmap if lhs.is_empty() => Err("Usage: unmap {lhs}".into()),
+            MapKind::Unmap => self.unmap(modes, lhs, local),
+            _ if rhs.is_empty() => self.list_mappings(mode, modes[0], lhs, local),
+            MapKind::Map => self.map(modes, lhs, rhs, true, local),
+            MapKind::Noremap => self.map(modes, lhs, rhs, false, local),
+        };
+        if let Err(message) = result {
+            self.status = Some(message);
+        }
+    }
+
+    /// Map keys to others, or to an action with `<Plug>(name)`. See `Action::named`.
+    fn map(&mut self, modes: &[ModeType], lhs: &str, rhs: &str, remap: bool, local: bool) -> Result<(), String> {
+        let lhs = self.parse_keys(lhs)?;
+        let action = if rhs.starts_with("<Plug>(") && rhs.ends_with(')') {
+            let name = &rhs[7..rhs.len() - 1];
+            Action::from_name(name).ok_or_else(|| format!("Unknown action: {}", name))?
+        } else {
+            Action::Feed(self.parse_keys(rhs)?, remap)
+        };
+
+        let mappings = self.mappings_mut(local);
+        for mode in modes {
+            mappings.entry(*mode).or_insert_with(KeyMap::new).bind(&lhs, action.clone());
+        }
+        Ok(())
+    }
+
+    fn unmap(&mut self, modes: &[ModeType], lhs: &str, local: bool) -> Result<(), String> {
+        let keys = self.parse_keys(lhs)?;
+        let mappings = self.mappings_mut(local);
+        let mut found = false;
+        for mode in modes {
+            if let Some(mappings) = mappings.get_mut(mode) {
+                found = mappings.unbind(&keys) || found;
+            }
+        }
+        if found { Ok(()) } else { Err(format!("No such mapping: {}", lhs)) }
+    }
+
+    /// The mappings of the current buffer if `local`, or else the global ones.
+    fn mappings_mut(&mut self, local: bool) -> &mut HashMap<ModeType, KeyMap> {
+        if local {
+            let number = self.buffer.borrow().number;
+            self.buffer_mappings.entry(number).or_default()
+        } else {
+            &mut self.mappings
+        }
+    }
+
+    /// Parse keys in key notation, replacing `<Leader>` and `<LocalLeader>`. See `mapleader`.
+    fn parse_keys(&self, text: &str) -> Result<Vec<Key>, String> {
+        notation::parse_keys(&notation::expand_leaders(text, &self.options.mapleader, &self.options.maplocalleader))
+    }
+
+    /// Show the mappings of a mode in the status bar, like `:ls`, only those of the current buffer if `local`.
+    /// Mappings that aren't mapped again, from `:noremap`, are marked with `*`, and those of the buffer with `@`.
+    fn list_mappings(&mut self, mode: char, mode_type: ModeType, prefix: &str, local: bool) -> Result<(), String> {
+        let prefix = if prefix.is_empty() { Vec::new() } else { self.parse_keys(prefix)? };
+        let number = self.buffer.borrow().number;
+        let local_bindings = self.buffer_mappings.get(&number)
+            .and_then(|modes| modes.get(&mode_type))
+            .map_or_else(Vec::new, KeyMap::bindings);
+        let global_bindings = if local { Vec::new() } else { self.mappings.get(&mode_type).map_or_else(Vec::new, KeyMap::bindings) };
+
+        let bindings = local_bindings.into_iter().map(|binding| (binding, true))
+            .chain(global_bindings.into_iter().map(|binding| (binding, false)));
+        let mappings: Vec<String> = bindings
+            .filter(|&((ref keys, _), _)| keys.starts_with(&prefix))
+            .map(|((keys, action), local)| {
+                let (rhs, noremap) = match action {
+                    Action::Feed(ref keys, remap) => (notation::format_keys(keys), !remap),
+                    ref action => (format!("<Plug>({})", action.name().unwrap_or("?")), false),
+                };
+                let flags = format!("{}{}", if noremap { "*" } else { "" }, if local { "@" } else { "" });
+                let lhs = notation::format_keys(&keys);
+                if flags.is_empty() { format!("{} {} {}", mode, lhs, rhs) } else { format!("{} {} {} {}", mode, lhs, flags, rhs) }
+            })
+            .collect();
+
+        if mappings.is_empty() {
+            return Err("No mapping found".into());
+        }
+        self.status = Some(mappings.join(" | "));
+        Ok(())
+    }
+
+    /// Define an Insert mode abbreviation from the arguments of `:iabbrev`, e.g. `teh the`.
+    fn abbreviate(&mut self, args: &str) {
+        let (word, expansion) = command::split_word(args);
+        if expansion.is_empty() {
+            self.status = Some("Usage: iabbrev {word} {expansion}".into());
+        } else if !word.graphemes(true).all(is_keyword) {
+            self.status = Some(format!("Invalid abbreviation: {}", word));
+        } else {
+            self.abbreviations.insert(word.into(), expansion.into());
+        }
+    }
+
+    /// Replace the word before the cursor if it's an abbreviation.
+    fn expand_abbreviation(&mut self) {
+        if self.abbreviations.is_empty() { return }
+
+        let word = self.word_before_cursor();
+        let expansion = match self.abbreviations.get(&word) {
+            Some(expansion) => expansion.clone(),
+            None => return,
+        };
+
+        self.cursor.x -= word.graphemes(true).count();
+        for _ in word.graphemes(true) {
+            self.buffer.borrow_mut().delete(self.cursor);
+        }
+        let len = expansion.graphemes(true).count();
+        self.buffer.borrow_mut().insert_text(self.cursor, expansion);
+        self.cursor.x += len;
+    }
+
+    fn add_autocmds(&mut self, args: &str) {
+        match Autocmd::parse(args) {
+            Ok(autocmds) => self.autocmds.extend(autocmds),
+            Err(message) => self.status = Some(message),
+        }
+    }
+
+    /// Run the autocommands of an event happening to the file at `path`.
+    fn fire(&mut self, event: Event, path: Option<String>) {
+        if self.in_autocmd { return }
+
+        let commands: Vec<String> = self.autocmds.iter()
+            .filter(|autocmd| autocmd.matches(event, path.as_deref()))
+            .map(|autocmd| autocmd.command.clone())
+            .collect();
+
+        self.in_autocmd = true;
+        for command in commands {
+            self.execute_command(&command);
+        }
+        self.in_autocmd = false;
+    }
+
+    fn parse_encoding(&mut self, name: &str) -> Option<Encoding> {
+        let encoding = Encoding::from_name(name);
+        if encoding.is_none() {
+            self.status = Some(format!("Unknown encoding: {}", name));
+        }
+        encoding
+    }
+
+    /// Set the file type of the current buffer, e.g. when loading a file or with `:set filetype=rust`.
+    /// The mappings of the buffer are removed, they're usually those of the previous file type,
+    /// before running the `FileType` autocommands.
+    /// The buffer is then open in the language server of its new type, if there's one. See `:LspServer`.
+    fn set_filetype(&mut self, filetype: String) {
+        let number = self.buffer.borrow().number;
+        let buffer = self.buffer.clone();
+        self.detach_language_server(&buffer);
+        self.buffer_mappings.remove(&number);
+        self.buffer.borrow_mut().filetype = filetype.clone();
+        if !filetype.is_empty() {
+            self.fire(Event::FileType, Some(filetype));
+        }
+        self.attach_language_server();
+    }
+
+    /// Handle `:LspServer filetype command`, starting `command` as the language server of the files of that type.
+    /// Without arguments, lists the servers configured.
+    fn language_server_command(&mut self, args: &str) {
+        let mut parts = args.splitn(2, char::is_whitespace);
+        let (filetype, command) = (parts.next().unwrap_or(""), parts.next().unwrap_or("").trim());
+        if filetype.is_empty() {
+            let mut servers: Vec<String> = self.lsp_servers.iter().map(|(filetype, command)| format!("{}: {}", filetype, command)).collect();
+            servers.sort();
+            self.status = Some(if servers.is_empty() { "No language servers".into() } else { servers.join(" | ") });
+            return;
+        }
+        if command.is_empty() {
+            self.status = Some("Usage: LspServer filetype command".into());
+            return;
+        }
+
+        self.lsp_servers.insert(filetype.into(), command.into());
+        let number = self.buffer.borrow().number;
+        if self.buffer.borrow().filetype == filetype && !self.lsp_documents.contains_key(&number) {
+            self.attach_language_server();
+        }
+    }
+
+    /// Open the current buffer in the language server of its file type, starting it if needed.
+    /// Its changes are then recorded and sent to the server. See `Buffer::journal`.
+    fn attach_language_server(&mut self) {
+        let (number, filetype, path) = {
+            let buffer = self.buffer.borrow();
+            (buffer.number, buffer.filetype.clone(), buffer.filepath.clone())
+        };
+        let (command, path) = match (self.lsp_servers.get(&filetype), path) {
+            (Some(command), Some(path)) => (command.clone(), path),
+            _ => return,
+        };
+
+        if !self.lsp_clients.contains_key(&filetype) {
+            let root = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+            match Client::start(&command, &root) {
+                Ok(client) => { self.lsp_clients.insert(filetype.clone(), client); }
+                Err(error) => {
+                    self.status = Some(format!("Can't start the language server for {}: {}", filetype, error));
+                    return;
+                }
+            }
+        }
+
+        let uri = lsp::path_to_uri(Path::new(&path));
+        let text = self.buffer.borrow().text();
+        self.lsp_clients.get_mut(&filetype).unwrap().did_open(&uri, &filetype, &text);
+        self.buffer.borrow_mut().journal = Some(Vec::new());
+        self.lsp_documents.insert(number, (filetype, uri));
+    }
+
+    /// Close a buffer in its language server, if it's open there.
+    fn detach_language_server(&mut self, buffer: &Rc<RefCell<Buffer>>) {
+        let mut buffer = buffer.borrow_mut();
+        buffer.journal = None;
+        if let Some((filetype, uri)) = self.lsp_documents.remove(&buffer.number) {
+            if let Some(client) = self.lsp_clients.get_mut(&filetype) {
+                client.did_close(&uri);
+            }
+        }
+    }
+
+    fn did_save(&mut self, buffer: &Rc<RefCell<Buffer>>) {
+        let buffer = buffer.borrow();
+        let uri = buffer.filepath.as_ref().map(|path| lsp::path_to_uri(Path::new(path)));
+        if let Some((filetype, known)) = self.lsp_documents.get(&buffer.number) {
+            // Not after `:saveas`, the server knows the buffer by its previous name.
+            if let (true, Some(client)) = (uri.as_ref() == Some(known), self.lsp_clients.get_mut(filetype)) {
+                client.did_save(known);
+            }
+        }
+    }
+
+    /// Send the changes made to the buffers to their language servers.
+    /// Done before any request, so that the positions of the server match ours.
+    fn sync_language_servers(&mut self) {
+        for buffer in &self.buffers {
+            let mut buffer = buffer.borrow_mut();
+            let edits = match buffer.journal {
+                Some(ref mut journal) if !journal.is_empty() => mem::take(journal),
+                _ => continue,
+            };
+            if let Some((filetype, uri)) = self.lsp_documents.get(&buffer.number) {
+                if let Some(client) = self.lsp_clients.get_mut(filetype) {
+                    client.did_change(uri, &buffer, &edits);
+                }
+            }
+        }
+    }
+
+    /// Send the latest changes to the language servers and handle their messages, without waiting.
+    /// Called by the main loop, at least every `language_server_timeout`.
+    pub fn handle_language_servers(&mut self) {
+        self.sync_language_servers();
+        let filetypes: Vec<String> = self.lsp_clients.keys().cloned().collect();
+        for filetype in filetypes {
+            match self.lsp_clients.get_mut(&filetype).and_then(|client| client.poll()) {
+                Some(messages) => {
+                    for message in messages {
+                        self.handle_lsp_message(&filetype, message);
+                    }
+                }
+                None => {
+                    self.lsp_clients.remove(&filetype);
+                    self.lsp_documents.retain(|_, &mut (ref known, _)| *known != filetype);
+                    for buffer in &self.buffers {
+                        let mut buffer = buffer.borrow_mut();
+                        if !self.lsp_documents.contains_key(&buffer.number) {
+                            buffer.journal = None;
+                        }
+                    }
+                    self.status = Some(format!("The language server for {} exited", filetype));
+                }
+            }
+        }
+    }
+
+    /// Time left before the messages of the language servers should be handled, `None` when none is running.
+    pub fn language_server_timeout(&self) -> Option<Duration> {
+        if self.lsp_clients.is_empty() { None } else { Some(Duration::from_millis(50)) }
+    }
+
+    /// Ask the language servers to exit, e.g. when quitting.
+    pub fn stop_language_servers(&mut self) {
+        for (_, mut client) in self.lsp_clients.drain() {
+            client.stop();
+        }
+        self.lsp_documents.clear();
+    }
+
+    fn handle_lsp_message(&mut self, filetype: &str, message: Message) {
+        match message {
+            Message::Response(request, Ok(result)) => self.handle_lsp_response(request, result),
+            Message::Response(_, Err(message)) => self.status = Some(format!("Language server: {}", message)),
+            Message::Request(id, method, params) => {
+                let result = match method.as_str() {
+                    "workspace/applyEdit" => {
+                        let outcome = match self.apply_workspace_edit(params.get("edit")) {
+                            Ok(_) => vec!(("applied", true.into())),
+                            Err(reason) => vec!(("applied", false.into()), ("failureReason", reason.into())),
+                        };
+                        Ok(object(outcome))
+                    }
+                    // No settings to give.
+                    "workspace/configuration" => Ok(params.get("items").items().iter().map(|_| Json::Null).collect::<Vec<Json>>().into()),
+                    "client/registerCapability" | "window/workDoneProgress/create" => Ok(Json::Null),
+                    _ => Err(format!("Unsupported method: {}", method)),
+                };
+                if let Some(client) = self.lsp_clients.get_mut(filetype) {
+                    client.respond(id, result);
+                }
+            }
+            Message::Notification(ref method, ref params) if method == "window/showMessage" => {
+                self.status = params.get("message").as_str().map(String::from);
+            }
+            Message::Notification(..) => {}
+        }
+    }
+
+    fn handle_lsp_response(&mut self, request: Request, result: Json) {
+        match request {
+            Request::Definition => {
+                match lsp::parse_locations(&result).into_iter().next() {
+                    Some((uri, position)) => match lsp::uri_to_path(&uri) {
+                        Some(path) => self.jump_to(&path, position),
+                        None => self.status = Some(format!("Can't open {}", uri)),
+                    },
+                    None => self.status = Some("No definition found".into()),
+                }
+            }
+            Request::References => {
+                let locations = lsp::parse_locations(&result);
+                self.quickfix = locations.into_iter().filter_map(|(uri, position)| {
+                    lsp::uri_to_path(&uri).map(|path| QuickfixEntry { text: self.line_of(&path, position.0), path, position })
+                }).collect();
+                if self.quickfix.is_empty() {
+                    self.status = Some("No references found".into());
+                } else {
+                    self.quickfix_command(0, false);
+                }
+            }
+            Request::Hover => {
+                let text = lsp::hover_text(result.get("contents"));
+                if text.trim().is_empty() {
+                    self.status = Some("No information available".into());
+                } else {
+                    self.popup = Some(text.trim().lines().map(String::from).collect());
+                }
+            }
+            Request::Rename(uri, change_id) => {
+                if self.document_changed(&uri, change_id) {
+                    self.status = Some(format!("{} changed, rename not applied", uri));
+                    return;
+                }
+                self.status = Some(match self.apply_workspace_edit(&result) {
+                    Ok(changed) => format!("Renamed in {} file{}", changed, if changed == 1 { "" } else { "s" }),
+                    Err(reason) => reason,
+                });
+            }
+            Request::CodeAction(uri, change_id) => {
+                if self.document_changed(&uri, change_id) {
+                    self.status = Some(format!("{} changed, code actions not listed", uri));
+                    return;
+                }
+                self.code_actions = result.items().to_vec();
+                self.code_actions_document = Some((uri, change_id));
+                if self.code_actions.is_empty() {
+                    self.status = Some("No code actions available".into());
+                    return;
+                }
+                let titles = self.code_actions.iter().enumerate()
+                    .map(|(idx, action)| format!("{}: {}", idx + 1, action.get("title").as_str().unwrap_or("")))
+                    .collect();
+                self.popup = Some(titles);
+                self.status = Some("Apply one with :LspCodeAction N".into());
+            }
+            Request::Formatting(uri, change_id) => {
+                if self.document_changed(&uri, change_id) {
+                    self.status = Some(format!("{} changed, formatting not applied", uri));
+                    return;
+                }
+                if let Some(idx) = lsp::uri_to_path(&uri).and_then(|path| self.open_buffer_index(&path)) {
+                    apply_edits(&mut self.buffers[idx].borrow_mut(), &lsp::parse_edits(&result));
+                    self.clamp_cursor();
+                }
+            }
+            Request::Completion(uri, location) => {
+                // Only while still typing where it was asked.
+                let number = self.buffer.borrow().number;
+                let current = self.lsp_documents.get(&number).is_some_and(|(_, known)| *known == uri);
+                if current && self.mode_type == ModeType::Insert && self.cursor == location {
+                    self.server_candidates = Some(lsp::completion_candidates(&result));
+                    self.complete(Source::Server, true);
+                    self.server_candidates = None;
+                }
+            }
+            Request::Initialize | Request::Shutdown | Request::ExecuteCommand => {}
+        }
+    }
+
+    /// Whether the document with this URI was edited or closed since it was at `change_id`.
+    /// The responses of the server about its previous text are then out of date.
+    fn document_changed(&self, uri: &str, change_id: usize) -> bool {
+        let idx = lsp::uri_to_path(uri).and_then(|path| self.open_buffer_index(&path));
+        idx.is_none_or(|idx| self.buffers[idx].borrow().change_id != change_id)
+    }
+
+    /// The file type and URI of the current buffer in its language server, once sent the latest changes.
+    fn lsp_document(&mut self) -> Option<(String, String)> {
+        self.sync_language_servers();
+        let document = self.lsp_documents.get(&self.buffer.borrow().number).cloned();
+        if document.is_none() {
+            self.status = Some(NO_LANGUAGE_SERVER.into());
+        }
+        document
+    }
+
+    /// Send a request about the cursor position in the current buffer to its language server,
+    /// with more parameters if needed.
+    fn lsp_request(&mut self, method: &str, more: Vec<(&str, Json)>, request: Request) {
+        let (filetype, uri) = match self.lsp_document() {
+            Some(document) => document,
+            None => return,
+        };
+        let position = self.buffer.borrow().lsp_position(self.cursor);
+        let mut params = vec!(("textDocument", object(vec!(("uri", uri.into())))), ("position", lsp::position(position)));
+        params.extend(more);
+        self.lsp_clients.get_mut(&filetype).unwrap().request(method, object(params), request);
+    }
+
+    fn request_code_actions(&mut self) {
+        let (filetype, uri) = match self.lsp_document() {
+            Some(document) => document,
+            None => return,
+        };
+        let position = self.buffer.borrow().lsp_position(self.cursor);
+        let params = object(vec!(
+            ("textDocument", object(vec!(("uri", uri.as_str().into())))),
+            ("range", lsp::range(position, position)),
+            ("context", object(vec!(("diagnostics", Vec::new().into())))),
+        ));
+        let change_id = self.buffer.borrow().change_id;
+        self.lsp_clients.get_mut(&filetype).unwrap().request("textDocument/codeAction", params, Request::CodeAction(uri, change_id));
+    }
+
+    /// Apply one of the code actions listed by `CodeActions`, counted from 1: its edit, then its command.
+    fn apply_code_action(&mut self, n: usize) {
+        let action = match self.code_actions.get(n.wrapping_sub(1)) {
+            Some(action) => action.clone(),
+            None => { self.status = Some(format!("No code action {}", n)); return }
+        };
+        if let Some((ref uri, change_id)) = self.code_actions_document {
+            if self.document_changed(uri, change_id) {
+                self.status = Some(format!("{} changed since the code actions were listed", uri));
+                return;
+            }
+        }
+        if !action.get("edit").is_null() {
+            if let Err(reason) = self.apply_workspace_edit(action.get("edit")) {
+                self.status = Some(reason);
+                return;
+            }
+        }
+
+        // Either a command itself, or a code action with one.
+        let command = if action.get("command").as_str().is_some() { &action } else { action.get("command") };
+        if let Some(name) = command.get("command").as_str() {
+            let (filetype, _) = match self.lsp_document() {
+                Some(document) => document,
+                None => return,
+            };
+            let mut params = vec!(("command", name.into()));
+            if !command.get("arguments").is_null() {
+                params.push(("arguments", command.get("arguments").clone()));
+            }
+            self.lsp_clients.get_mut(&filetype).unwrap().request("workspace/executeCommand", object(params), Request::ExecuteCommand);
+        }
+    }
+
+    /// Format the current buffer with its language server, following `shiftwidth` and `expandtab`.
+    fn format(&mut self) {
+        let (filetype, uri) = match self.lsp_document() {
+            Some(document) => document,
+            None => return,
+        };
+        let options = object(vec!(
+            ("tabSize", self.options.shift_width().into()),
+            ("insertSpaces", self.options.expandtab.into()),
+        ));
+        let params = object(vec!(("textDocument", object(vec!(("uri", uri.as_str().into())))), ("options", options)));
+        let change_id = self.buffer.borrow().change_id;
+        self.lsp_clients.get_mut(&filetype).unwrap().request("textDocument/formatting", params, Request::Formatting(uri, change_id));
+    }
+
+    /// Apply the edits of a workspace edit, e.g. of a rename, opening the files that aren't yet.
+    /// Returns the number of files changed, or why nothing was: a file that can't be opened,
+    /// or a document that isn't at the version the edits were made for.
+    fn apply_workspace_edit(&mut self, edit: &Json) -> Result<usize, String> {
+        // Our edits not sent yet give the documents new versions.
+        self.sync_language_servers();
+        let number = self.buffer.borrow().number;
+        let mut documents = Vec::new();
+        let mut failure = None;
+        for (uri, version, edits) in lsp::workspace_edits(edit) {
+            let path = match lsp::uri_to_path(&uri) {
+                Some(path) => path,
+                None => { failure = Some(format!("Can't open {}", uri)); break }
+            };
+            if self.open_buffer_index(&path).is_none() {
+                self.open_file(path.clone(), None);
+            }
+            let current = self.lsp_clients.values().find_map(|client| client.version(&uri));
+            match self.open_buffer_index(&path) {
+                Some(_) if version.is_some() && version != current => {
+                    failure = Some(format!("{} changed, edits not applied", uri));
+                    break;
+                }
+                Some(idx) => documents.push((idx, edits)),
+                None => { failure = Some(format!("Can't open {}", uri)); break }
+            }
+        }
+
+        if failure.is_none() {
+            for (idx, edits) in &documents {
+                apply_edits(&mut self.buffers[*idx].borrow_mut(), edits);
+            }
+        }
+        if let Some(idx) = self.buffer_index(number) {
+            self.switch_to(idx);
+        }
+        self.clamp_cursor();
+        failure.map_or(Ok(documents.len()), Err)
+    }
+
+    /// Go to a position given by a language server, opening its file if needed.
+    fn jump_to(&mut self, path: &str, position: (usize, usize)) {
+        self.open_file(path.into(), None);
+        if self.open_buffer_index(path) == Some(self.buffer_idx) {
+            self.cursor = self.buffer.borrow().lsp_location(position);
+            self.clamp_cursor();
+        }
+    }
+
+    /// A line of a file without its indentation, from its buffer if it's open.
+    fn line_of(&self, path: &str, y: usize) -> String {
+        let line = match self.open_buffer_index(path) {
+            Some(idx) => self.buffers[idx].borrow().data.get(y).cloned(),
+            None => fs::read(path).ok().and_then(|bytes| String::from_utf8_lossy(&bytes).lines().nth(y).map(String::from)),
+        };
+        line.unwrap_or_default().trim().into()
+    }
+
+    /// Go to an entry of the quickfix list, `offset` from the current one if `relative`, like `:cnext`,
+    /// or at that index otherwise, like `:cc N`.
+    fn quickfix_command(&mut self, offset: isize, relative: bool) {
+        if self.quickfix.is_empty() {
+            self.status = Some("No quickfix list".into());
+            return;
+        }
+        let idx = if relative { self.quickfix_idx as isize + offset } else { offset };
+        if idx < 0 || idx as usize >= self.quickfix.len() {
+            self.status = Some("No more items".into());
+            return;
+        }
+
+        self.quickfix_idx = idx as usize;
+        let (path, position, text) = {
+            let entry = &self.quickfix[self.quickfix_idx];
+            (entry.path.clone(), entry.position, entry.text.clone())
+        };
+        self.jump_to(&path, position);
+        self.status = Some(format!("({} of {}) {}", self.quickfix_idx + 1, self.quickfix.len(), text));
+    }
+
+    /// Show the quickfix list in a popup, like `:clist`, the current entry marked with `>`.
+    fn list_quickfix(&mut self) {
+        if self.quickfix.is_empty() {
+            self.status = Some("No quickfix list".into());
+            return;
+        }
+        let lines = self.quickfix.iter().enumerate().map(|(idx, entry)| {
+            let current = if idx == self.quickfix_idx { ">" } else { " " };
+            format!("{}{} {}:{}: {}", current, idx + 1, entry.path, entry.position.0 + 1, entry.text)
+        }).collect();
+        self.popup = Some(lines);
+    }
+
+    fn set_options(&mut self, args: &[&str]) {
+        for arg in args {
+            if let Some(filetype) = options::value_of(arg, &["filetype", "ft"]) {
+                self.set_filetype(filetype.into());
+                continue;
+            }
+            let buffer_result = self.buffer.borrow_mut().set_option(arg);
+            let result = buffer_result.unwrap_or_else(|| self.options.set(arg));
+            if let Err(message) = result {
+                self.status = Some(message);
+                break;
+            }
+        }
+
+        // Follow `emacs`, unless in the middle of editing in another mode.
+        let base = self.base_mode();
+        if base != self.mode_type && (self.mode_type == ModeType::Normal || self.mode_type == ModeType::Emacs) {
+            self.switch_mode(base);
+        }
+    }
+}
+
+/// Apply the text edits of a language server to a buffer. They don't overlap and their positions are
+/// those of the text before any of them, so they're applied from the last one.
+fn apply_edits(buffer: &mut Buffer, edits: &[Edit]) {
+    let mut order: Vec<usize> = (0..edits.len()).collect();
+    order.sort_by_key(|&idx| (edits[idx].start, idx));
+    for idx in order.into_iter().rev() {
+        let start = buffer.lsp_location(edits[idx].start);
+        let end = buffer.lsp_location(edits[idx].end);
+        buffer.replace_range(start, end, &edits[idx].text);
+    }
+}
+
+/// Whether a command closes buffers, i.e. `:bd`, `:bdelete`, `:bw`, `:bwipeout`, with an optional `!`.
+fn is_buffer_delete(command: &str) -> bool {
+    matches!(command.trim_end_matches('!'), "bd" | "bdelete" | "bw" | "bwipeout")
+}
+
+/// Number of keys making up the mapping they start with.
+fn mapping_len(mappings: &KeyMap, keys: &[Key]) -> usize {
+    mappings.bound_len(keys).unwrap_or(keys.len())
+}
+
+/// What a mapping command does. See `parse_map_command`.
+#[derive(PartialEq,Eq,Debug,Copy,Clone)]
+enum MapKind {
+    /// Add a mapping whose keys are mapped again, e.g. `:nmap`.
+    Map,
+    /// Add a mapping whose keys aren't mapped again, e.g. `:nnoremap`.
+    Noremap,
+    /// Remove a mapping, e.g. `:nunmap`.
+    Unmap,
+}
+
+/// The mode and kind of a mapping command, e.g. `('i', Noremap)` for `:inoremap`.
+/// The mode is `n` for Normal, `i` for Insert and Replace and `v` for Visual.
+/// Like Vim, `:map` is for Normal mode and `:map!` for Insert mode.
+fn parse_map_command(command: &str) -> Option<(char, MapKind)> {
+    if let Some(name) = command.strip_suffix('!') {
+        return map_kind(name).map(|kind| ('i', kind));
+    }
+    // Before looking for a mode, since `noremap` starts like `nnoremap`.
+    if let Some(kind) = map_kind(command) {
+        return Some(('n', kind));
+    }
+    match command.chars().next() {
+        Some(c @ 'n') | Some(c @ 'i') | Some(c @ 'v') => map_kind(&command[1..]).map(|kind| (c, kind)),
+        _ => None,
+    }
+}
+
+fn map_kind(name: &str) -> Option<MapKind> {
+    match name {
+        "map" => Some(MapKind::Map),
+        "noremap" => Some(MapKind::Noremap),
+        "unmap" => Some(MapKind::Unmap),
+        _ => None,
+    }
+}
+
+/// Whether a command defines an abbreviation, i.e. `:iabbrev` or `:abbreviate` and their short forms.
+fn is_abbreviate(command: &str) -> bool {
+    matches!(command, "iab" | "iabbrev" | "ab" | "abbreviate")
+}
+
+/// Whether a grapheme is part of a word, for abbreviations.
+fn is_keyword(grapheme: &str) -> bool {
+    grapheme.chars().all(completion::is_keyword_char)
+}
+
+/// Whether a grapheme is a space or a tab.
+fn is_blank(grapheme: &str) -> bool {
+    grapheme == " " || grapheme == "\t"
+}
+
+/// Whether an action typed in Insert mode ends a word, expanding it if it's an abbreviation.
+fn ends_word(action: &Action) -> bool {
+    match *action {
+        Action::Insert(c) => !is_keyword(&c.to_string()),
+        Action::NewLineAtPoint | Action::Tab | Action::Cancel => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::thread;
+    use lsp::test::fake_server;
+    use notation;
+    use mode::ModeType;
+    use state::*;
+    use test_dir::TestDir;
+
+    fn type_keys(state: &mut State, text: &str) {
+        for key in notation::parse_keys(text).unwrap() {
+            state.handle_key(key);
+        }
+    }
+
+    #[test]
+    fn config() {
+        let dir = TestDir::new("config");
+        let path = dir.join("mivrc");
+        File::create(&path).unwrap().write_all(b"\" Comment\nset number\nimap jk <Esc>\niab teh the\nset nosuchoption\n").unwrap();
+
+        let mut state = State::new(80, 24);
+        state.load_config(&path);
+        assert!(state.options.number);
+        assert!(state.status.as_ref().unwrap().contains("line 5"));
+
+        type_keys(&mut state, "iteh jk");
+        assert_eq!(state.buffer.borrow().data, vec!("the \n"));
+        assert_eq!(state.mode_type, ModeType::Normal);
+    }
+
+    #[test]
+    fn descriptions() {
+        fn check<F: Fn(&[Key]) -> Vec<(Key, String)>>(continuations: &F, keys: &mut Vec<Key>) {
+            for (key, description) in continuations(keys) {
+                keys.push(key);
+                // Not the Debug form of the action, e.g. `MoveCursor(Up)`.
+                assert!(!description.contains('(') && !description.starts_with(char::is_uppercase),
+                        "{} is described as {}", notation::format_keys(keys), description);
+                check(continuations, keys);
+                keys.pop();
+            }
+        }
+
+        let popup_keys = completion::popup_keys();
+        check(&|keys: &[Key]| popup_keys.continuations(keys), &mut Vec::new());
+        let (normal, insert) = (NormalMode::new(), InsertMode::new());
+        let (replace, emacs) = (ReplaceMode::new(), EmacsMode::new());
+        check(&|keys: &[Key]| normal.continuations(keys), &mut Vec::new());
+        check(&|keys: &[Key]| insert.continuations(keys), &mut Vec::new());
+        check(&|keys: &[Key]| replace.continuations(keys), &mut Vec::new());
+        check(&|keys: &[Key]| emacs.continuations(keys), &mut Vec::new());
+    }
+
+    #[test]
+    fn map_commands() {
+        assert_eq!(parse_map_command("map"), Some(('n', MapKind::Map)));
+        assert_eq!(parse_map_command("map!"), Some(('i', MapKind::Map)));
+        assert_eq!(parse_map_command("nmap"), Some(('n', MapKind::Map)));
+        assert_eq!(parse_map_command("imap"), Some(('i', MapKind::Map)));
+        assert_eq!(parse_map_command("vmap"), Some(('v', MapKind::Map)));
+        assert_eq!(parse_map_command("noremap"), Some(('n', MapKind::Noremap)));
+        assert_eq!(parse_map_command("noremap!"), Some(('i', MapKind::Noremap)));
+        assert_eq!(parse_map_command("nnoremap"), Some(('n', MapKind::Noremap)));
+        assert_eq!(parse_map_command("inoremap"), Some(('i', MapKind::Noremap)));
+        assert_eq!(parse_map_command("unmap"), Some(('n', MapKind::Unmap)));
+        assert_eq!(parse_map_command("unmap!"), Some(('i', MapKind::Unmap)));
+        assert_eq!(parse_map_command("nunmap"), Some(('n', MapKind::Unmap)));
+        assert_eq!(parse_map_command("iunmap"), Some(('i', MapKind::Unmap)));
+        assert_eq!(parse_map_command("nmap!"), None);
+        assert_eq!(parse_map_command("xmap"), None);
+        assert_eq!(parse_map_command("nnoremap!"), None);
+    }
+
+    #[test]
+    fn recursive_mappings() {
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("ab\n".into(), "cd\n".into());
+        state.execute_command("noremap l j");
+        state.execute_command("nmap M l");
+        state.execute_command("nnoremap N l");
+
+        // The right-hand side of `:nmap` is mapped again, not that of `:nnoremap`.
+        type_keys(&mut state, "N");
+        assert_eq!(state.cursor, Point::new(1, 0));
+        type_keys(&mut state, "M");
+        assert_eq!(state.cursor, Point::new(1, 1));
+
+        state.execute_command("noremap! jk <Esc>");
+        type_keys(&mut state, "ixjk");
+        assert_eq!(state.mode_type, ModeType::Normal);
+        assert_eq!(state.buffer.borrow().data[1], "cxd\n");
+    }
+
+    #[test]
+    fn overlapping_mappings() {
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("a\n".into(), "b\n".into(), "c\n".into());
+        state.execute_command("nnoremap g j");
+        state.execute_command("nnoremap gg k");
+
+        type_keys(&mut state, "g");
+        state.handle_timeout();
+        assert_eq!(state.cursor, Point::new(0, 1));
+
+        // The longer mapping takes all the keys, nothing is left to run `g` afterwards.
+        type_keys(&mut state, "gg");
+        state.handle_timeout();
+        assert_eq!(state.cursor, Point::new(0, 0));
+
+        // An unbound key typed after the shorter mapping still runs.
+        type_keys(&mut state, "gx");
+        assert_eq!(state.cursor, Point::new(0, 1));
+        assert_eq!(state.buffer.borrow().data[1], "\n");
+
+        // The same goes for the bindings of the mode, e.g. Esc and the Esc prefixed ones in Emacs mode.
+        let mut state = emacs_state(&["abc\n"]);
+        type_keys(&mut state, "<Esc><C-e>");
+        assert_eq!(state.cursor, Point::new(3, 0));
+    }
+
+    #[test]
+    fn buffer_mappings() {
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("a\n".into(), "b\n".into(), "c\n".into());
+        state.execute_command("nnoremap x j");
+        state.execute_command("nnoremap g j");
+        state.execute_command("nnoremap <buffer> x jj");
+        state.execute_command("nnoremap <buffer> gk k");
+
+        type_keys(&mut state, "x");
+        assert_eq!(state.cursor, Point::new(0, 2));
+
+        type_keys(&mut state, "gk");
+        assert_eq!(state.cursor, Point::new(0, 1));
+        // The global `g` still runs when the buffer's `gk` isn't typed.
+        type_keys(&mut state, "g");
+        state.handle_timeout();
+        assert_eq!(state.cursor, Point::new(0, 2));
+    }
+
+    #[test]
+    fn insert_deletion() {
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("ab\n".into(), "cd\n".into());
+
+        // Deleting the line ending joins the next line.
+        type_keys(&mut state, "i<End><Del>");
+        assert_eq!(state.buffer.borrow().data, vec!("abcd\n"));
+        assert_eq!(state.cursor, Point::new(2, 0));
+        type_keys(&mut state, "<End><Del>");
+        assert_eq!(state.buffer.borrow().data, vec!("abcd\n"));
+
+        type_keys(&mut state, "<Home><Right><Right><C-w>");
+        assert_eq!(state.buffer.borrow().data, vec!("cd\n"));
+        type_keys(&mut state, "  foo bar<C-u>");
+        assert_eq!(state.buffer.borrow().data, vec!("  cd\n"));
+        type_keys(&mut state, "<C-u>");
+        assert_eq!(state.buffer.borrow().data, vec!("cd\n"));
+        assert_eq!(state.cursor, Point::new(0, 0));
+    }
+
+    #[test]
+    fn insert_completion() {
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("key keys\n".into(), "\n".into());
+        type_keys(&mut state, "jike<C-n>");
+        assert_eq!(state.buffer.borrow().data[1], "key\n");
+        type_keys(&mut state, "<C-n>");
+        assert_eq!(state.buffer.borrow().data[1], "keys\n");
+        type_keys(&mut state, "<C-n>");
+        assert_eq!(state.buffer.borrow().data[1], "ke\n");
+        type_keys(&mut state, "<C-p>");
+        assert_eq!(state.buffer.borrow().data[1], "keys\n");
+    }
+
+    #[test]
+    fn completion_popup() {
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("key keys\n".into(), "\n".into());
+
+        // Tab and S-Tab select the next and previous candidates, previewed in the text.
+        type_keys(&mut state, "jike<C-n><Tab>");
+        assert_eq!(state.buffer.borrow().data[1], "keys\n");
+        type_keys(&mut state, "<S-Tab>");
+        assert_eq!(state.buffer.borrow().data[1], "key\n");
+        assert_eq!(state.status, Some("match 1 of 2".into()));
+
+        // Ctrl-e goes back to the text typed.
+        type_keys(&mut state, "<C-e>");
+        assert_eq!(state.buffer.borrow().data[1], "ke\n");
+        assert!(state.completion.is_none());
+
+        // Enter and Ctrl-y keep the selected candidate, then typing goes on.
+        type_keys(&mut state, "<C-n><CR>s");
+        assert_eq!(state.buffer.borrow().data[1], "keys\n");
+        type_keys(&mut state, "<BS><BS><C-n><Tab><C-y>");
+        assert_eq!(state.buffer.borrow().data[1], "keys\n");
+        assert!(state.completion.is_none());
+
+        // Enter starts a new line when no candidate is selected.
+        type_keys(&mut state, "<C-n><C-p><CR>");
+        assert_eq!(state.buffer.borrow().data, vec!("key keys\n", "keys\n", "\n"));
+        assert!(state.completion.is_none());
+
+        // Esc may begin S-Tab: a key typed after it is handled once the popup is closed.
+        type_keys(&mut state, "ke<C-n><Esc>0x");
+        assert_eq!(state.buffer.borrow().data[2], "ey\n");
+        assert_eq!(state.mode_type, ModeType::Normal);
+        assert!(state.completion.is_none());
+    }
+
+    #[test]
+    fn completion_sources() {
+        let dir = TestDir::new("completion-sources");
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(dir.join("words"), "apple banana\n").unwrap();
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("first line\n".into(), "\n".into());
+        state.execute_command(&format!("set dictionary={}", dir.join("words").display()));
+
+        // Enter keeps the candidate, then starts a new line.
+        type_keys(&mut state, "jifi<C-x><C-l><CR><CR>");
+        assert_eq!(state.buffer.borrow().data[1], "first line\n");
+        type_keys(&mut state, &format!("{}/no<C-x><C-f><CR><CR>", dir.path().display()));
+        assert_eq!(state.buffer.borrow().data[2], format!("{}\n", dir.join("notes.txt").display()));
+        type_keys(&mut state, "ba<C-x><C-k><CR>");
+        assert_eq!(state.buffer.borrow().data[3], "banana\n");
+    }
+
+    #[test]
+    fn insert_commands() {
+        let mut state = State::new(80, 24);
+        state.buffer.borrow_mut().data = vec!("one\n".into(), "two\n".into());
+        type_keys(&mut state, "jx");
+        type_keys(&mut state, "i<C-r>\"<C-r>%");
+        assert_eq!(state.buffer.borrow().data[1], "two\n");
+        assert_eq!(state.status, Some("Nothing in register %".into()));
+
+        // Ctrl-o runs a single Normal mode command.
+        type_keys(&mut state, "<C-o>k");
+        assert_eq!(state.cursor.y, 0);
+        assert_eq!(state.mode_type, ModeType::Insert);
+
+        type_keys(&mut state, "<Esc>yy");
+        state.execute_command("registers");
+        assert_eq!(state.popup, Some(vec!("\"\"   one<CR>".into(), "\"0   one<CR>".into(), "\"-   t".into())));
+
+        // Deleted lines go to `1`, shifting the previous ones, and leave the text yanked in `0`.
+        type_keys(&mut state, "pdddd");
+        state.execute_command("reg 0123-");
+        assert_eq!(state.popup, Some(vec!("\"0   one<CR>".into(), "\"1   two<CR>".into(), "\"2   one<CR>".into(), "\"-   t".into())));
+        type_keys(&mut state, "i<C-r>2<C-r>a");
+        assert_eq!(state.buffer.borrow().data, vec!("one\n", "one\n"));
+        assert_eq!(state.status, Some("Named registers are not supported yet".into()));
+    }
+
+    #[test]
+    fn buffer_list() {
+        let dir = TestDir::new("buffer-list");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "a\n").unwrap();
+        fs::write(&b, "b\nb\n").unwrap();
+        let (a, b) = (a.to_string_lossy().into_owned(), b.to_string_lossy().into_owned());
+        let mut state = State::new(80, 24);
+        state.execute_command("set noswapfile");
+        state.execute_command(&format!("e {}", a));
+        state.execute_command(&format!("e {}", b));
+        type_keys(&mut state, "jx");
+        state.execute_command("ls");
+        assert_eq!(state.status, Some(format!("1# \"{}\" line 1 | 2%+ \"{}\" line 2", a, b)));
+
+        state.execute_command("b 1");
+        assert_eq!(state.buffer.borrow().name(), a);
+        state.execute_command("b2");
+        assert_eq!(state.buffer.borrow().name(), b);
+        assert_eq!(state.cursor, Point::new(0, 1));
+        type_keys(&mut state, "<C-6>");
+        assert_eq!(state.buffer.borrow().name(), a);
+        state.execute_command("e #");
+        assert_eq!(state.buffer.borrow().name(), b);
+        state.execute_command("b 9");
+        assert_eq!(state.status, Some("Buffer 9 does not exist".into()));
+
+        // Modified buffers are only closed when forced.
+        state.execute_command("bd");
+        assert_eq!(state.status, Some("No write since last change for buffer 2 (add ! to override)".into()));
+        state.execute_command("bw 2");
+        assert_eq!(state.buffers.len(), 2);
+        state.execute_command("bw! 2");
+        state.execute_command("ls");
+        assert_eq!(state.status, Some(format!("1% \"{}\" line 1", a)));
+        type_keys(&mut state, "<C-6>");
+        assert_eq!(state.status, Some("No alternate file".into()));
+
+        // Closing the last buffer leaves an empty one.
+        state.execute_command("bd");
+        state.execute_command("ls");
+        assert_eq!(state.status, Some("3% \"[No Name]\" line 1".into()));
+    }
+
+    fn emacs_state(lines: &[&str]) -> State<'static> {
+        let mut state = State::new(80, 24);
+        state.execute_command("set emacs");
+        state.buffer.borrow_mut().data = lines.iter().map(|&line| line.into()).collect();
+        state
+    }
+
+    #[test]
+    fn emacs_delete() {
+        let mut state = emacs_state(&["ab\n", "cd\n"]);
+        type_keys(&mut state, "<C-d><C-e><C-d>");
+        assert_eq!(state.buffer.borrow().data, vec!("bcd\n"));
+        type_keys(&mut state, "<C-e><C-d>");
+        assert_eq!(state.buffer.borrow().data, vec!("bcd\n"));
+    }
+
+    #[test]
+    fn emacs_meta() {
+        // Esc is also the Meta prefix: the key after it is still typed when they aren't bound together.
+        let mut state = emacs_state(&["b\n"]);
+        type_keys(&mut state, "<Esc>a");
+        assert_eq!(state.buffer.borrow().data, vec!("ab\n"));
+        assert_eq!(state.cursor, Point::new(1, 0));
+
+        type_keys(&mut state, "<Esc>");
+        state.handle_timeout();
+        type_keys(&mut state, "c<M-b>");
+        assert_eq!(state.buffer.borrow().data, vec!("acb\n"));
+        assert_eq!(state.cursor, Point::new(0, 0));
+    }
+
+    #[test]
+    fn emacs_kill_ring() {
+        let mut state = emacs_state(&["one\n", "two\n", "three\n"]);
+
+        // Consecutive kills make a single entry.
+        type_keys(&mut state, "<C-k><C-k>");
+        assert_eq!(state.buffer.borrow().data, vec!("two\n", "three\n"));
+        type_keys(&mut state, "<C-n><C-k>");
+        assert_eq!(state.buffer.borrow().data, vec!("two\n", "\n"));
+
+        type_keys(&mut state, "<C-y>");
+        assert_eq!(state.buffer.borrow().data, vec!("two\n", "three\n"));
+        assert_eq!(state.cursor, Point::new(5, 1));
+        type_keys(&mut state, "<Esc>y");
+        assert_eq!(state.buffer.borrow().data, vec!("two\n", "one\n", "\n"));
+        assert_eq!(state.cursor, Point::new(0, 2));
+
+        type_keys(&mut state, "<C-a><Esc>y");
+        assert_eq!(state.status, Some("Previous command was not a yank".into()));
+    }
+
+    #[test]
+    fn emacs_region() {
+        let mut state = emacs_state(&["hello world\n"]);
+        type_keys(&mut state, "<C-w>");
+        assert_eq!(state.status, Some("The mark is not set now, so there is no region".into()));
+
+        type_keys(&mut state, "<C-Space><C-f><C-f><C-f><C-f><C-f><Esc>w<C-e><C-y>");
+        assert_eq!(state.buffer.borrow().data, vec!("hello worldhello\n"));
+
+        type_keys(&mut state, "<C-a><C-Space><C-f><C-f><C-f><C-f><C-f><C-f><C-w>");
+        assert_eq!(state.buffer.borrow().data, vec!("worldhello\n"));
+        assert_eq!(state.cursor, Point::new(0, 0));
+        type_keys(&mut state, "<C-e><C-y>");
+        assert_eq!(state.buffer.borrow().data, vec!("worldhellohello \n"));
+    }
+
+    #[test]
+    fn emacs_isearch() {
+        let mut state = emacs_state(&["foo bar\n", "bar baz\n"]);
+        type_keys(&mut state, "<C-s>bar");
+        assert_eq!(state.cursor, Point::new(7, 0));
+        assert_eq!(state.isearch_prompt(), "I-search: bar");
+        type_keys(&mut state, "<C-s><CR>");
+        assert_eq!(state.cursor, Point::new(3, 1));
+        assert_eq!(state.microstate, MicroState::Mode);
+
+        // A failing search goes back where it started when cancelled.
+        type_keys(&mut state, "<C-s>qux");
+        assert_eq!(state.isearch_prompt(), "Failing I-search: qux");
+        type_keys(&mut state, "<C-g>");
+        assert_eq!(state.cursor, Point::new(3, 1));
+        assert_eq!(state.microstate, MicroState::Mode);
+    }
+
+    /// A state editing `text` as the Rust file `name` of `dir`, open in the fake language server.
+    fn lsp_state(dir: &TestDir, name: &str, text: &str) -> State<'static> {
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+
+        let mut state = State::new(80, 24);
+        state.execute_command("set noswapfile");
+        state.execute_command(&format!("LspServer rust {}", fake_server().display()));
+        state.execute_command(&format!("e {}", path.display()));
+        state
+    }
+
+    /// Handle the messages of the language servers until `done`, for a few seconds at most.
+    fn wait_until<F: Fn(&State) -> bool>(state: &mut State, done: F) {
+        for _ in 0..500 {
+            state.handle_language_servers();
+            if done(state) { return }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no answer from the language server");
+    }
+
+    #[test]
+    fn lsp_navigation() {
+        let dir = TestDir::new("lsp-navigation");
+        let mut state = lsp_state(&dir, "main.rs", "let café = 1;\ncafé + 2\n");
+        assert_eq!(state.buffer.borrow().filetype, "rust");
+        assert_eq!(state.buffer.borrow().journal, Some(Vec::new()));
+
+        state.cursor = Point::new(1, 1);
+        type_keys(&mut state, "gd");
+        wait_until(&mut state, |state| state.cursor.y == 0);
+        assert_eq!(state.cursor, Point::new(4, 0));
+
+        type_keys(&mut state, "K");
+        wait_until(&mut state, |state| state.popup.is_some());
+        assert_eq!(state.popup, Some(vec!("let café = 1;".into())));
+
+        type_keys(&mut state, "gr");
+        wait_until(&mut state, |state| !state.quickfix.is_empty());
+        assert_eq!(state.quickfix.iter().map(|entry| entry.position).collect::<Vec<_>>(), vec!((0, 4), (1, 0)));
+        assert_eq!(state.status, Some("(1 of 2) let café = 1;".into()));
+        state.execute_command("cn");
+        assert_eq!(state.cursor, Point::new(0, 1));
+
+        // The server is sent the changes, and sees the lines as they are now.
+        type_keys(&mut state, "i😀 <Esc>ggA<Del><Esc>");
+        assert_eq!(state.buffer.borrow().data, vec!("let café = 1;😀 café + 2\n"));
+        type_keys(&mut state, "K");
+        wait_until(&mut state, |state| state.popup.is_some());
+        assert_eq!(state.popup, Some(vec!("let café = 1;😀 café + 2".into())));
+        assert_eq!(state.buffer.borrow().journal, Some(Vec::new()));
+
+        state.stop_language_servers();
+    }
+
+    #[test]
+    fn lsp_edits() {
+        let dir = TestDir::new("lsp-edits");
+        let mut state = lsp_state(&dir, "main.rs", "let café = 1;  \ncafé + 2\t\n");
+
+        state.cursor = Point::new(1, 1);
+        state.execute_command("LspRename total");
+        wait_until(&mut state, |state| state.status.is_some());
+        assert_eq!(state.status, Some("Renamed in 1 file".into()));
+        assert_eq!(state.buffer.borrow().data, vec!("let total = 1;  \n", "total + 2\t\n"));
+
+        type_keys(&mut state, "<Space>la");
+        wait_until(&mut state, |state| state.popup.is_some());
+        assert_eq!(state.popup, Some(vec!("1: Uppercase the word".into(), "2: Insert a comment".into())));
+        state.execute_command("LspCodeAction 1");
+        assert_eq!(state.buffer.borrow().data, vec!("let TOTAL = 1;  \n", "TOTAL + 2\t\n"));
+
+        // The code actions listed before that edit are out of date.
+        state.execute_command("LspCodeAction 2");
+        assert_eq!(state.status, Some(format!("{} changed since the code actions were listed", lsp::path_to_uri(&dir.join("main.rs")))));
+        type_keys(&mut state, "<Space>la");
+        wait_until(&mut state, |state| state.popup.is_some());
+
+        // The command makes the server ask us to apply an edit.
+        state.execute_command("LspCodeAction 2");
+        wait_until(&mut state, |state| state.buffer.borrow().line_len() == 3);
+        assert_eq!(state.buffer.borrow().data, vec!("// comment\n", "let TOTAL = 1;  \n", "TOTAL + 2\t\n"));
+
+        // Formatting is right only if the server was sent all the edits above.
+        type_keys(&mut state, "<Space>lf");
+        wait_until(&mut state, |state| !state.buffer.borrow().data[1].ends_with(" \n"));
+        assert_eq!(state.buffer.borrow().data, vec!("// comment\n", "let TOTAL = 1;\n", "TOTAL + 2\n"));
+
+        state.stop_language_servers();
+    }
+
+    #[test]
+    fn lsp_completion() {
+        let dir = TestDir::new("lsp-completion");
+        let mut state = lsp_state(&dir, "main.rs", "let café = 1;\n\n");
+
+        state.cursor = Point::new(0, 1);
+        type_keys(&mut state, "ica<C-x><C-o>");
+        wait_until(&mut state, |state| state.completion.is_some());
+        assert_eq!(state.completion.as_ref().unwrap().candidates, vec!(Candidate::new("café", "variable")));
+        assert_eq!(state.buffer.borrow().data, vec!("let café = 1;\n", "café\n"));
+
+        // Not when the cursor moved in the meantime.
+        type_keys(&mut state, "<C-y> c<C-x><C-o><Left>");
+        for _ in 0..20 {
+            state.handle_language_servers();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(state.completion.is_none());
+        assert_eq!(state.buffer.borrow().data, vec!("let café = 1;\n", "café c\n"));
+
+        state.stop_language_servers();
+    }
+
+    #[test]
+    fn lsp_attach() {
+        let dir = TestDir::new("lsp-attach");
+        let mut state = lsp_state(&dir, "main.rs", "fn main() {}\n");
+        fs::write(dir.join("notes.md"), "# Notes\n").unwrap();
+
+        // Only the files of the type of the server.
+        state.execute_command(&format!("e {}", dir.join("notes.md").display()));
+        assert_eq!(state.buffer.borrow().journal, None);
+        state.execute_command("set filetype=rust");
+        assert_eq!(state.buffer.borrow().journal, Some(Vec::new()));
+        state.execute_command("set filetype=markdown");
+        assert_eq!(state.buffer.borrow().journal, None);
+        type_keys(&mut state, "K");
+        assert_eq!(state.status, Some(NO_LANGUAGE_SERVER.into()));
+
+        // A server that exits is forgotten, and its buffers detached.
+        state.execute_command("LspServer markdown sleep 0.5");
+        assert_eq!(state.buffer.borrow().journal, Some(Vec::new()));
+        wait_until(&mut state, |state| state.buffer.borrow().journal.is_none());
+        assert_eq!(state.status, Some("The language server for markdown exited".into()));
+        state.execute_command("LspServer");
+        assert_eq!(state.status, Some(format!("markdown: sleep 0.5 | rust: {}", fake_server().display())));
+
+        state.stop_language_servers();
+    }
+
+    #[test]
+    fn lsp_responses() {
+        let dir = TestDir::new("lsp-responses");
+        let (main, lib) = (dir.join("main.rs"), dir.join("lib.rs"));
+        fs::write(&main, "mod lib;\n").unwrap();
+        fs::write(&lib, "pub fn f() {}\n").unwrap();
+        let mut state = State::new(80, 24);
+        state.execute_command("set noswapfile");
+        state.execute_command(&format!("e {}", main.display()));
+
+        state.handle_lsp_response(Request::Definition, Json::Null);
+        assert_eq!(state.status, Some("No definition found".into()));
+        state.handle_lsp_response(Request::References, Vec::new().into());
+        assert_eq!(state.status, Some("No references found".into()));
+        state.handle_lsp_response(Request::Hover, object(vec!(("contents", "".into()))));
+        assert_eq!(state.status, Some("No information available".into()));
+        let (uri, change_id) = (lsp::path_to_uri(&main), state.buffer.borrow().change_id);
+        state.handle_lsp_response(Request::CodeAction(uri.clone(), change_id), Json::Null);
+        assert_eq!(state.status, Some("No code actions available".into()));
+
+        // Definitions in other files open them.
+        let location = object(vec!(("uri", lsp::path_to_uri(&lib).into()), ("range", lsp::range((0, 7), (0, 8)))));
+        state.handle_lsp_response(Request::Definition, location);
+        assert_eq!(state.buffer.borrow().filepath, Some(lib.to_string_lossy().into_owned()));
+        assert_eq!(state.cursor, Point::new(7, 0));
+
+        // Edits of files that aren't open open them, and stay in the current buffer.
+        state.execute_command("b1");
+        let edit = |range, text: &str| object(vec!(("range", range), ("newText", text.into())));
+        let changes = Json::Object(vec!(
+            (lsp::path_to_uri(&main), vec!(edit(lsp::range((1, 0), (1, 0)), "fn main() {}\n")).into()),
+            (lsp::path_to_uri(&dir.join("new.rs")), vec!(edit(lsp::range((0, 0), (0, 0)), "// new")).into()),
+        ));
+        assert_eq!(state.apply_workspace_edit(&object(vec!(("changes", changes)))), Ok(2));
+        assert_eq!(state.buffer.borrow().data, vec!("mod lib;\n", "fn main() {}\n"));
+        assert_eq!(state.buffers.len(), 3);
+        assert_eq!(state.buffers[2].borrow().data, vec!("// new\n"));
+
+        // Nothing is applied when a document isn't at the version the edits were made for.
+        let document_changes: Vec<Json> = vec!((&main, Json::Null), (&lib, 1usize.into())).into_iter().map(|(path, version)| {
+            let document = object(vec!(("uri", lsp::path_to_uri(path).into()), ("version", version)));
+            object(vec!(("textDocument", document), ("edits", vec!(edit(lsp::range((0, 0), (0, 0)), "// ")).into())))
+        }).collect();
+        let result = state.apply_workspace_edit(&object(vec!(("documentChanges", document_changes.into()))));
+        assert_eq!(result, Err(format!("{} changed, edits not applied", lsp::path_to_uri(&lib))));
+        assert_eq!(state.buffer.borrow().data, vec!("mod lib;\n", "fn main() {}\n"));
+
+        // Nor the responses about a text edited since the request.
+        let formatting = vec!(edit(lsp::range((0, 0), (2, 0)), "")).into();
+        state.handle_lsp_response(Request::Formatting(uri.clone(), change_id), formatting);
+        assert_eq!(state.status, Some(format!("{} changed, formatting not applied", uri)));
+        state.handle_lsp_response(Request::Rename(uri.clone(), change_id), object(vec!(("changes", Json::Object(Vec::new())))));
+        assert_eq!(state.status, Some(format!("{} changed, rename not applied", uri)));
+        assert_eq!(state.buffer.borrow().data, vec!("mod lib;\n", "fn main() {}\n"));
+
+        let change_id = state.buffer.borrow().change_id;
+        state.handle_lsp_response(Request::Formatting(uri, change_id), vec!(edit(lsp::range((0, 0), (2, 0)), "")).into());
+        assert_eq!(state.buffer.borrow().data, vec!("\n"));
+        assert_eq!(state.cursor, Point::new(0, 0));
+    }
+
+    #[test]
+    fn lsp_apply_edits() {
+        let mut buffer = Buffer::new();
+        buffer.data = vec!("fn main() {\n".into(), "    x\n".into(), "}\n".into());
+        buffer.journal = Some(Vec::new());
+        let edits = vec!(
+            Edit { start: (3, 0), end: (3, 0), text: "// b".into() },
+            Edit { start: (1, 4), end: (1, 5), text: "y".into() },
+            Edit { start: (0, 0), end: (0, 0), text: "// a\n".into() },
+            Edit { start: (0, 0), end: (0, 2), text: "pub fn".into() },
+        );
+        apply_edits(&mut buffer, &edits);
+        assert_eq!(buffer.data, vec!("// a\n", "pub fn main() {\n", "    y\n", "}\n", "// b\n"));
+
+        // Journaled from the last one, as applied.
+        let mut journal = buffer.journal.take().unwrap();
+        journal.reverse();
+        assert_eq!(journal[0], Edit { start: (0, 0), end: (0, 0), text: "// a\n".into() });
+        assert_eq!(journal[1], Edit { start: (0, 0), end: (0, 2), text: "pub fn".into() });
+        assert_eq!(journal[3], Edit { start: (3, 0), end: (3, 0), text: "// b\n".into() });
     }
 }
@@ -0,0 +1,160 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+#[cfg(unix)]
+use libc;
+
+use file;
+
+/// First line of every swap file.
+const MAGIC: &str = "miv swap file";
+
+/// Path of the swap file of the file at `path`, e.g. `.file.txt.swp` next to it.
+pub fn swap_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or_else(Default::default, |name| name.to_string_lossy().into_owned());
+    path.with_file_name(format!(".{}.swp", name))
+}
+
+/// Content of a swap file, i.e. the state of a buffer being edited.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct Swap {
+    /// Process that wrote the swap file.
+    pub pid: u32,
+    /// Whether the buffer had unsaved changes. The lines are worth recovering only if so.
+    pub modified: bool,
+    /// The lines of the buffer, each terminated by `\n`.
+    pub lines: Vec<String>,
+}
+
+impl Swap {
+    pub fn read(path: &Path) -> io::Result<Swap> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Not a swap file");
+        let mut lines = content.split_terminator('\n');
+        if lines.next() != Some(MAGIC) { return Err(invalid()) }
+        let pid = lines.next().and_then(|line| value_of(line, "pid")).ok_or_else(invalid)?;
+        let modified = lines.next().and_then(|line| value_of(line, "modified")).ok_or_else(invalid)?;
+
+        Ok(Swap {
+            pid,
+            modified: modified == 1,
+            lines: lines.map(|line| format!("{}\n", line)).collect(),
+        })
+    }
+
+    /// Whether another process still running wrote this swap file,
+    /// i.e. the file is being edited in another miv.
+    pub fn in_use(&self) -> bool {
+        self.pid != process::id() && process_running(self.pid)
+    }
+}
+
+/// The value of a `name value` line of the header.
+fn value_of(line: &str, name: &str) -> Option<u32> {
+    let mut words = line.split(' ');
+    if words.next() != Some(name) { return None }
+    words.next().and_then(|value| value.parse().ok())
+}
+
+/// A swap file owned by this process, kept up to date with a buffer.
+#[derive(Debug)]
+pub struct SwapFile {
+    pub path: PathBuf,
+    /// `Buffer::change_id` when the swap file was last written.
+    change_id: usize,
+    /// Whether the buffer was modified when the swap file was last written.
+    modified: bool,
+}
+
+impl SwapFile {
+    pub fn create(path: PathBuf, lines: &[String], modified: bool, change_id: usize) -> io::Result<SwapFile> {
+        write(&path, lines, modified)?;
+        Ok(SwapFile { path, change_id, modified })
+    }
+
+    /// Write the buffer again, if it changed or was saved since the last time.
+    pub fn update(&mut self, lines: &[String], modified: bool, change_id: usize) -> io::Result<()> {
+        if change_id == self.change_id && modified == self.modified { return Ok(()) }
+
+        write(&self.path, lines, modified)?;
+        self.change_id = change_id;
+        self.modified = modified;
+        Ok(())
+    }
+
+    /// Delete the swap file, when the buffer is closed.
+    pub fn remove(self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The lines are only written when modified, the file on disk has them otherwise.
+/// Only the user can read the swap file, since it may hold the text of a private file.
+fn write(path: &Path, lines: &[String], modified: bool) -> io::Result<()> {
+    let mut content = format!("{}\npid {}\nmodified {}\n", MAGIC, process::id(), modified as u8);
+    if modified {
+        for line in lines {
+            content.push_str(line);
+        }
+    }
+    file::write_private(path, content.as_bytes()).map(|_| ())
+}
+
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists. EPERM means it does, but isn't ours.
+    let exists = unsafe { libc::kill(pid as libc::pid_t, 0) == 0 };
+    exists || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+    use swap::*;
+    use test_dir::TestDir;
+
+    #[test]
+    fn swap_files() {
+        assert_eq!(swap_path(Path::new("/tmp/file.txt")), Path::new("/tmp/.file.txt.swp"));
+
+        let dir = TestDir::new("swap");
+        let path = dir.join(".file.txt.swp");
+        let lines = vec!("hello\n".to_string(), "\n".to_string());
+        let mut swap_file = SwapFile::create(path.clone(), &lines, false, 0).unwrap();
+        let swap = Swap::read(&path).unwrap();
+        assert_eq!(swap, Swap { pid: process::id(), modified: false, lines: vec!() });
+        assert!(!swap.in_use());
+
+        swap_file.update(&lines, true, 1).unwrap();
+        assert_eq!(Swap::read(&path).unwrap().lines, lines);
+
+        // Saving doesn't change the text, only the modified flag.
+        swap_file.update(&lines, false, 1).unwrap();
+        assert!(!Swap::read(&path).unwrap().modified);
+
+        swap_file.remove();
+        assert!(!path.exists());
+        assert!(Swap { pid: 1, modified: false, lines: vec!() }.in_use());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_swap_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new("private-swap");
+        let path = dir.join(".file.txt.swp");
+        SwapFile::create(path.clone(), &["secret\n".into()], true, 1).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,36 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// An empty temporary directory for the files of a test, removed with them when dropped,
+/// even when the test fails.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Create the directory, named after `name` and the process so that tests running at the same time
+    /// don't share it, e.g. `/tmp/miv-swap-1234`.
+    pub fn new(name: &str) -> TestDir {
+        let path = env::temp_dir().join(format!("miv-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of a file in the directory.
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
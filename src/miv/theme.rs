@@ -0,0 +1,77 @@
+use rustbox::Color;
+
+/// Colors used to draw the editor. Picked with `:colorscheme`.
+#[derive(Debug,Clone,Copy)]
+pub struct Theme {
+    /// Background of the text and of the minibuffer.
+    pub bg: Color,
+    /// The text itself.
+    pub fg: Color,
+    /// Messages, the minibuffer and the file details in the bottom bar.
+    pub text: Color,
+    /// Background of the bottom bar.
+    pub bar_bg: Color,
+    /// Text displayed over a mode color in the bottom bar.
+    pub bar_fg: Color,
+    /// Background of the bottom bar in modes with their own color, e.g. Insert.
+    pub bar_bg_mode: Color,
+    /// Color of the modes that don't have their own, e.g. Normal.
+    pub mode: u16,
+    /// Line numbers and `showbreak`.
+    pub gutter_fg: Color,
+    /// Number of the current line.
+    pub gutter_current_fg: Color,
+    /// Background of the region between the mark and the cursor. See `State::region`.
+    pub region_bg: Color,
+    /// Background of the completion popup.
+    pub popup_bg: Color,
+    /// Background of the candidate selected in the completion popup.
+    pub popup_selected_bg: Color,
+}
+
+impl Theme {
+    pub fn new() -> Theme {
+        Theme {
+            bg: Color::Byte(234),
+            fg: Color::Byte(0),
+            text: Color::White,
+            bar_bg: Color::Byte(237),
+            bar_fg: Color::Byte(233),
+            bar_bg_mode: Color::Byte(26),
+            mode: 220,
+            gutter_fg: Color::Byte(240),
+            gutter_current_fg: Color::Byte(250),
+            region_bg: Color::Byte(239),
+            popup_bg: Color::Byte(238),
+            popup_selected_bg: Color::Byte(31),
+        }
+    }
+
+    /// The theme called `name`, i.e. `default` or `light`.
+    pub fn named(name: &str) -> Option<Theme> {
+        match name {
+            "default" => Some(Theme::new()),
+            "light" => Some(Theme {
+                bg: Color::Byte(255),
+                fg: Color::Byte(235),
+                text: Color::Byte(235),
+                bar_bg: Color::Byte(250),
+                bar_fg: Color::Byte(255),
+                bar_bg_mode: Color::Byte(153),
+                mode: 25,
+                gutter_fg: Color::Byte(246),
+                gutter_current_fg: Color::Byte(238),
+                region_bg: Color::Byte(252),
+                popup_bg: Color::Byte(253),
+                popup_selected_bg: Color::Byte(117),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::new()
+    }
+}
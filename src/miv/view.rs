@@ -1,19 +1,33 @@
 extern crate rustbox;
 
+use std::cmp;
 use rustbox::{Color, RustBox};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-use keys::key_to_string;
+use layout;
 use mode::ModeType;
+use notation;
 use point::Point;
 use state::{State,MicroState};
 
-const BG_COLOR: Color = Color::Byte(234);
-const FG_COLOR: Color = Color::Byte(0);
-const BAR_BG_COLOR: Color = Color::Byte(237);
-const BAR_FG_COLOR: Color = Color::Byte(233);
-const BAR_BG_MODE_COLOR: Color = Color::Byte(26);
 const BAR_HEIGHT: usize = 2;
-const DEFAULT_MODE_COLOR: u16 = 220;
+
+/// A slice of a buffer line displayed on a single screen row.
+/// Without wrapping, each visible buffer line is exactly one row.
+struct ScreenRow {
+    /// Buffer line
+    line: usize,
+    /// First buffer column displayed
+    start: usize,
+    /// Number of buffer columns displayed
+    len: usize,
+    /// Screen column of the first character, i.e. after the gutter and `showbreak`
+    offset: usize,
+    /// Whether this row is the continuation of a wrapped line
+    continuation: bool,
+}
 
 pub struct View<'a> {
     rustbox: &'a RustBox,
@@ -27,8 +41,10 @@ pub struct View<'a> {
     height: usize,
     /// Buffer window height
     window_height: usize,
-    /// Buffer window width
+    /// Buffer window width, excluding the gutter
     window_width: usize,
+    /// Width of the line number gutter (including the separating space). 0 when hidden.
+    gutter_width: usize,
     /// Index of the last row before the bottom bar
     last_row: usize,
     /// The absolute cursor position (as oppose to the cursor position inside the buffer)
@@ -48,6 +64,7 @@ impl<'a> View<'a> {
             height: 0,
             window_height: 0,
             window_width: 0,
+            gutter_width: 0,
             last_row: 0,
             cursor: Point::new(0, 0),
         }
@@ -55,90 +72,349 @@ impl<'a> View<'a> {
 
     /// Renders the whole editor into the termbox: text, bottom bar, cursor... etc
     pub fn render(&mut self, state: &State) {
+        let line_len = state.buffer.borrow().line_len();
         self.height = state.height;
         self.width = state.width;
         self.window_height = state.height - BAR_HEIGHT;
-        self.window_width = state.width - 1;
+        self.gutter_width = layout::gutter_width(&state.options, line_len);
+        self.window_width = state.width.saturating_sub(1 + self.gutter_width);
         self.last_row  = self.window_height - 1;
 
+        self.scroll(state);
+        let rows = self.screen_rows(state);
+        self.cursor = self.adjusted_cursor(state, &rows);
+
+        self.rustbox.clear();
+        self.fill_background(state);
+        let region = state.region();
+
+        for (y, row) in rows.iter().enumerate() {
+            if row.continuation {
+                let showbreak = state.options.showbreak.as_ref();
+                self.rustbox.print(self.gutter_width, y, rustbox::RB_NORMAL, state.theme.gutter_fg, state.theme.bg, showbreak);
+            }
+
+            let buffer = state.buffer.borrow();
+            let line = &buffer.data[row.line];
+            let widths = layout::cell_widths(line, state.options.tabstop);
+            let mut x = row.offset;
+            for (col, (grapheme, width)) in line.graphemes(true).zip(widths).enumerate().skip(row.start).take(row.len) {
+                let selected = in_region(region, Point::new(col, row.line));
+                let bg = if selected { state.theme.region_bg } else { state.theme.bg };
+                if grapheme != "\n" && grapheme != "\t" {
+                    self.rustbox.print_char(x, y, rustbox::RB_NORMAL, state.theme.fg, bg, displayed_char(grapheme));
+                } else if selected {
+                    for dx in 0..width {
+                        self.rustbox.print_char(x + dx, y, rustbox::RB_NORMAL, state.theme.fg, bg, ' ');
+                    }
+                }
+                x += width;
+            }
+        }
+
+        self.print_gutter(state, &rows);
+        self.print_completion(state);
+        self.print_popup(state);
+        self.print_which_key(state);
+
+        self.print_cursor(state);
+        self.print_bar(state);
+
+        self.rustbox.present();
+    }
+
+    /// Adjusts `topline` and `leftcol` so that the cursor stays visible.
+    /// When wrapping, the scrolling is based on screen rows rather than buffer lines.
+    fn scroll(&mut self, state: &State) {
         if state.cursor.y < self.topline {
             self.topline = state.cursor.y;
-        } else if state.cursor.y > self.last_row + self.topline {
-            self.topline = state.cursor.y - self.last_row;
         }
 
-        if state.cursor.x < self.leftcol {
-            self.leftcol = state.cursor.x;
-        } else if state.cursor.x > self.window_width + self.leftcol {
-            self.leftcol = state.cursor.x - self.window_width
+        if !state.options.wrap {
+            if state.cursor.y > self.last_row + self.topline {
+                self.topline = state.cursor.y - self.last_row;
+            }
+
+            // Horizontal scrolling is done in screen columns, so that wide characters are always fully visible.
+            let widths = layout::cell_widths(&state.buffer.borrow().data[state.cursor.y], state.options.tabstop);
+            let col = layout::display_col(&widths, 0, state.cursor.x);
+            let last_col = col + widths.get(state.cursor.x).map_or(0, |w| w - 1);
+            if col < self.leftcol {
+                self.leftcol = col;
+            } else if last_col > self.window_width + self.leftcol {
+                self.leftcol = last_col - self.window_width
+            }
+            return;
         }
 
-        self.cursor = self.adjusted_cursor(state.cursor);
+        self.leftcol = 0;
 
-        self.rustbox.clear();
-        self.fill_background(state);
+        // Walk up from the cursor to find the highest line that still keeps it on screen.
+        let buffer = state.buffer.borrow();
+        let width = self.window_width + 1;
+        let cursor_line = &buffer.data[state.cursor.y];
+        let mut rows = layout::row_at(&layout::wrap_line(cursor_line, width, &state.options), state.cursor.x) + 1;
+        let mut y = state.cursor.y;
+        while y > self.topline {
+            let above = layout::wrap_line(&buffer.data[y - 1], width, &state.options).len();
+            if rows + above > self.window_height { break }
+            rows += above;
+            y -= 1;
+        }
+
+        self.topline = y;
+    }
+
+    /// Lays out the visible part of the buffer, one entry per screen row.
+    fn screen_rows(&self, state: &State) -> Vec<ScreenRow> {
+        let buffer = state.buffer.borrow();
+        let mut rows = Vec::new();
+
+        for (y, line) in buffer.data.iter().enumerate().skip(self.topline) {
+            if rows.len() >= self.window_height { break }
+
+            if !state.options.wrap {
+                rows.push(self.scrolled_row(y, line, state.options.tabstop));
+                continue;
+            }
 
-        for (y, line) in state.buffer.borrow().data.iter().skip(self.topline).take(self.window_height).enumerate() {
-            for (x, character) in line.chars().skip(self.leftcol).take(self.window_width + 1).enumerate() {
-                if character == '\n' { continue };
-                self.rustbox.print_char(x, y, rustbox::RB_NORMAL, FG_COLOR, BG_COLOR, character);
+            let starts = layout::wrap_line(line, self.window_width + 1, &state.options);
+            let line_end = line.chars().count();
+            for (i, &start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).cloned().unwrap_or(line_end);
+                let offset = if i > 0 { self.gutter_width + layout::showbreak_width(&state.options) } else { self.gutter_width };
+                rows.push(ScreenRow { line: y, start, len: end - start, offset, continuation: i > 0 });
             }
         }
 
-        self.print_cursor(state);
-        self.print_bar(state);
+        rows.truncate(self.window_height);
+        rows
+    }
 
-        self.rustbox.present();
+    /// The part of a line visible when scrolled horizontally by `leftcol` screen columns.
+    /// A wide character cut by the left edge isn't displayed at all.
+    fn scrolled_row(&self, y: usize, line: &str, tabstop: usize) -> ScreenRow {
+        let widths = layout::cell_widths(line, tabstop);
+
+        let mut start = 0;
+        let mut col = 0;
+        while start < widths.len() && col < self.leftcol {
+            col += widths[start];
+            start += 1;
+        }
+
+        let room = (self.window_width + 1).saturating_sub(col - self.leftcol);
+        let mut len = 0;
+        let mut used = 0;
+        while start + len < widths.len() && used + widths[start + len] <= room {
+            used += widths[start + len];
+            len += 1;
+        }
+
+        ScreenRow { line: y, start, len, offset: self.gutter_width + col - self.leftcol, continuation: false }
     }
 
     /// The cursor passed by State is the absolute position in the text buffer.
-    /// This adjusts the position by `topline` and `leftcol`. Used for scrolling.
-    fn adjusted_cursor(&self, cursor: Point) -> Point {
-        Point { x: cursor.x - self.leftcol, y: cursor.y - self.topline }
+    /// This finds the matching position on screen, taking into account scrolling, wrapping,
+    /// wide characters and the gutter.
+    fn adjusted_cursor(&self, state: &State, rows: &[ScreenRow]) -> Point {
+        let cursor = state.cursor;
+        let widths = layout::cell_widths(&state.buffer.borrow().data[cursor.y], state.options.tabstop);
+
+        rows.iter()
+            .rposition(|row| row.line == cursor.y && row.start <= cursor.x)
+            .map_or(Point::new(0, 0), |y| {
+                let row = &rows[y];
+                let mut x = row.offset + layout::display_col(&widths, row.start, cursor.x);
+                // Like Vim, the cursor sits at the end of a tab in Normal mode.
+                if state.mode_type == ModeType::Normal && state.buffer.borrow_mut().grapheme_at(cursor) == Some("\t") {
+                    x += widths[cursor.x] - 1;
+                }
+                Point::new(x, y)
+            })
+    }
+
+    /// Prints the right-aligned line numbers to the left of the text.
+    /// With both `number` and `relativenumber` set (hybrid mode) the current line is absolute.
+    fn print_gutter(&self, state: &State, rows: &[ScreenRow]) {
+        if self.gutter_width == 0 { return }
+
+        for (y, row) in rows.iter().enumerate() {
+            if row.continuation { continue }
+
+            let current = row.line == state.cursor.y;
+            let number = if !state.options.relativenumber || (current && state.options.number) {
+                row.line + 1
+            } else {
+                row.line.abs_diff(state.cursor.y)
+            };
+
+            let text = format!("{:>width$} ", number, width = self.gutter_width - 1);
+            let color = if current { state.theme.gutter_current_fg } else { state.theme.gutter_fg };
+            self.rustbox.print(0, y, rustbox::RB_NORMAL, color, state.theme.bg, text.as_ref());
+        }
     }
 
     fn print_bar(&self, state: &State) {
-        if !state.keystrokes.is_empty() {
-            let keys: String = state.keystrokes.iter()
-                .filter_map(|&k| key_to_string(k).or(None))
-                .collect();
-            self.rustbox.print(18, self.window_height, rustbox::RB_BOLD, Color::White, BAR_BG_COLOR, keys.as_ref());
+        let pending = state.pending_keys();
+        if !pending.is_empty() {
+            let keys = notation::format_pending(&pending);
+            self.rustbox.print(18, self.window_height, rustbox::RB_BOLD, state.theme.text, state.theme.bar_bg, keys.as_ref());
         }
 
-        self.print_coords(state);
+        let coords_x = self.print_coords(state);
+        self.print_file_info(state, coords_x);
         self.print_status(state);
         self.print_mode(state);
     }
 
     fn print_mode(&self, state: &State) {
         let mode = format!(" {}  ", state.mode.display());
-        let color = Color::Byte(state.mode.color().unwrap_or(DEFAULT_MODE_COLOR));
-        self.rustbox.print(0, self.window_height, rustbox::RB_BOLD, BAR_FG_COLOR, color, mode.as_ref());
+        let color = Color::Byte(state.mode.color().unwrap_or(state.theme.mode));
+        self.rustbox.print(0, self.window_height, rustbox::RB_BOLD, state.theme.bar_fg, color, mode.as_ref());
+    }
+
+    /// Prints the cursor position at the right of the bar, returning the column where it starts.
+    fn print_coords(&self, state: &State) -> usize {
+        // Like Vim, also show the screen column when it differs, e.g. after wide characters.
+        let widths = layout::cell_widths(&state.buffer.borrow().data[state.cursor.y], state.options.tabstop);
+        let col = layout::display_col(&widths, 0, state.cursor.x);
+        let coords = if col == state.cursor.x {
+            format!("  {}:{}  ", state.cursor.y + 1, state.cursor.x)
+        } else {
+            format!("  {}:{}-{}  ", state.cursor.y + 1, state.cursor.x, col)
+        };
+        let color = Color::Byte(state.mode.color().unwrap_or(state.theme.mode));
+        let x = (self.width - 1).saturating_sub(coords.len());
+        self.rustbox.print(x, self.window_height, rustbox::RB_BOLD, state.theme.bar_fg, color, coords.as_ref());
+        x
     }
 
-    fn print_coords(&self, state: &State) {
-        let coords = format!("  {}:{}  ", state.cursor.y + 1, state.cursor.x);
-        let color = Color::Byte(state.mode.color().unwrap_or(DEFAULT_MODE_COLOR));
-        self.rustbox.print(self.window_width - coords.len(), self.window_height, rustbox::RB_BOLD, BAR_FG_COLOR, color, coords.as_ref());
+    /// Prints details about the current buffer's file, right-aligned against `right`.
+    fn print_file_info(&self, state: &State, right: usize) {
+        let buffer = state.buffer.borrow();
+        let eol = if buffer.endofline { "" } else { "noeol  " };
+        let bom = if buffer.bomb { "[BOM]" } else { "" };
+        let modified = if buffer.modified() { "[+]  " } else { "" };
+        let new = if buffer.new_file { "[New File]  " } else { "" };
+        let ro = if buffer.readonly { "[RO]  " } else { "" };
+        let info = format!("{}{}{}{}{}{}  {}  ", modified, new, ro, eol, buffer.fileencoding.name(), bom, buffer.fileformat.name());
+        let x = right.saturating_sub(info.len());
+        self.rustbox.print(x, self.window_height, rustbox::RB_NORMAL, state.theme.text, self.bar_bg_color(state), info.as_ref());
+    }
+
+    /// Prints the keys that can follow the ones typed in columns above the bar, Spacemacs style.
+    fn print_which_key(&self, state: &State) {
+        let continuations = match state.which_key() {
+            Some(continuations) => continuations,
+            None => return,
+        };
+
+        let entries: Vec<String> = continuations.iter()
+            .map(|&(key, ref description)| format!(" {} → {}", notation::format_keys(&[key]), description))
+            .collect();
+        let col_width = entries.iter().map(|entry| UnicodeWidthStr::width(entry.as_str())).max().unwrap_or(0) + 2;
+        let cols = cmp::max(1, self.width / col_width);
+        let rows = cmp::min(entries.len().div_ceil(cols), self.window_height);
+        if rows == 0 { return }
+        let top = self.window_height - rows;
+
+        for y in top..self.window_height {
+            for x in 0..self.width {
+                self.rustbox.print(x, y, rustbox::RB_NORMAL, state.theme.text, state.theme.bar_bg, " ");
+            }
+        }
+        // Fill the columns first, like a list.
+        for (idx, entry) in entries.iter().enumerate().take(rows * cols) {
+            let (col, row) = (idx / rows, idx % rows);
+            self.rustbox.print(col * col_width, top + row, rustbox::RB_NORMAL, state.theme.text, state.theme.bar_bg, entry);
+        }
+    }
+
+    /// Prints the candidates of the completion in progress in a popup under the completed text,
+    /// or above it when there's more room there. The selected one is highlighted.
+    fn print_completion(&self, state: &State) {
+        let completion = match state.completion {
+            Some(ref completion) => completion,
+            None => return,
+        };
+
+        let text_width = completion.candidates.iter().map(|candidate| UnicodeWidthStr::width(candidate.text.as_str())).max().unwrap_or(0);
+        let menu_width = completion.candidates.iter().map(|candidate| UnicodeWidthStr::width(candidate.menu.as_str())).max().unwrap_or(0);
+        let width = cmp::min(text_width + if menu_width > 0 { menu_width + 4 } else { 2 }, self.width);
+        let (above, below) = (self.cursor.y, self.window_height.saturating_sub(self.cursor.y + 1));
+        let wanted = cmp::min(completion.candidates.len(), state.options.pumheight);
+        let (top, rows) = if below >= wanted || below >= above {
+            (self.cursor.y + 1, cmp::min(wanted, below))
+        } else {
+            let rows = cmp::min(wanted, above);
+            (self.cursor.y - rows, rows)
+        };
+        // Aligned with the beginning of the completed text, scrolled to the selected candidate.
+        let left = cmp::min(self.cursor.x.saturating_sub(UnicodeWidthStr::width(completion.text())), self.width - width);
+        let first = match completion.selected {
+            Some(idx) if idx >= rows => idx + 1 - rows,
+            _ => 0,
+        };
+
+        for (row, (idx, candidate)) in completion.candidates.iter().enumerate().skip(first).take(rows).enumerate() {
+            let bg = if completion.selected == Some(idx) { state.theme.popup_selected_bg } else { state.theme.popup_bg };
+            for x in left..left + width {
+                self.rustbox.print(x, top + row, rustbox::RB_NORMAL, state.theme.text, bg, " ");
+            }
+            self.rustbox.print(left + 1, top + row, rustbox::RB_NORMAL, state.theme.text, bg, &candidate.text);
+            if !candidate.menu.is_empty() {
+                self.rustbox.print(left + text_width + 3, top + row, rustbox::RB_NORMAL, state.theme.gutter_fg, bg, &candidate.menu);
+            }
+        }
+    }
+
+    /// Show the lines of `State::popup`, e.g. hover information, under the cursor or above it if there's more room.
+    fn print_popup(&self, state: &State) {
+        let lines: Vec<String> = match state.popup {
+            Some(ref lines) => lines.iter().map(|line| line.replace('\t', "    ")).collect(),
+            None => return,
+        };
+
+        let width = cmp::min(lines.iter().map(|line| UnicodeWidthStr::width(line.as_str())).max().unwrap_or(0) + 2, self.width);
+        let (above, below) = (self.cursor.y, self.window_height.saturating_sub(self.cursor.y + 1));
+        let (top, rows) = if below >= lines.len() || below >= above {
+            (self.cursor.y + 1, cmp::min(lines.len(), below))
+        } else {
+            let rows = cmp::min(lines.len(), above);
+            (self.cursor.y - rows, rows)
+        };
+        let left = cmp::min(self.cursor.x, self.width - width);
+
+        for (row, line) in lines.iter().take(rows).enumerate() {
+            for x in left..left + width {
+                self.rustbox.print(x, top + row, rustbox::RB_NORMAL, state.theme.text, state.theme.popup_bg, " ");
+            }
+            self.rustbox.print(left + 1, top + row, rustbox::RB_NORMAL, state.theme.text, state.theme.popup_bg, line);
+        }
     }
 
     fn print_cursor(&self, state: &State) {
         if state.microstate == MicroState::MiniBuffer {
-            self.rustbox.set_cursor(state.minibuffer.len() as isize + 1, self.height as isize);
+            let width = UnicodeWidthStr::width(state.minibuffer.as_str());
+            self.rustbox.set_cursor(width as isize + 1, self.height as isize);
         } else {
-            let cursor = self.adjusted_cursor(state.cursor);
-            self.rustbox.set_cursor(cursor.x as isize, cursor.y as isize);
+            self.rustbox.set_cursor(self.cursor.x as isize, self.cursor.y as isize);
         }
     }
 
     fn print_status(&self, state: &State) {
         if let Some(status) = state.status.clone() {
-            self.rustbox.print(0, self.window_height + 1, rustbox::RB_BOLD, Color::White, BG_COLOR, status.as_ref());
+            self.rustbox.print(0, self.window_height + 1, rustbox::RB_BOLD, state.theme.text, state.theme.bg, status.as_ref());
+        }
+
+        if state.microstate == MicroState::Isearch {
+            self.rustbox.print(0, self.window_height + 1, rustbox::RB_BOLD, state.theme.text, state.theme.bg, &state.isearch_prompt());
         }
 
         if state.microstate == MicroState::MiniBuffer {
-            self.rustbox.print(0, self.window_height + 1, rustbox::RB_BOLD, Color::White, BG_COLOR, ":");
-            self.rustbox.print(1, self.window_height + 1, rustbox::RB_BOLD, Color::White, BG_COLOR, state.minibuffer.as_ref());
+            self.rustbox.print(0, self.window_height + 1, rustbox::RB_BOLD, state.theme.text, state.theme.bg, ":");
+            self.rustbox.print(1, self.window_height + 1, rustbox::RB_BOLD, state.theme.text, state.theme.bg, state.minibuffer.as_ref());
         }
     }
 
@@ -146,7 +422,7 @@ impl<'a> View<'a> {
         // Background
         for y in 0..self.rustbox.height() {
             for x in 0..self.rustbox.width() {
-                self.rustbox.print(x, y, rustbox::RB_NORMAL, Color::White, BG_COLOR, " ");
+                self.rustbox.print(x, y, rustbox::RB_NORMAL, state.theme.text, state.theme.bg, " ");
             }
         }
 
@@ -154,15 +430,28 @@ impl<'a> View<'a> {
         let y = self.window_height;
         let bg_color = self.bar_bg_color(state);
         for x in 0..self.rustbox.width() {
-            self.rustbox.print(x, y, rustbox::RB_NORMAL, Color::White, bg_color, " ");
+            self.rustbox.print(x, y, rustbox::RB_NORMAL, state.theme.text, bg_color, " ");
         }
     }
 
     fn bar_bg_color(&self, state: &State) -> Color {
         if state.mode_type == ModeType::Normal {
-            BAR_BG_COLOR
+            state.theme.bar_bg
         } else {
-            BAR_BG_MODE_COLOR
+            state.theme.bar_bg_mode
         }
     }
 }
+
+/// Whether a location is in the region, the end excluded. See `State::region`.
+fn in_region(region: Option<(Point, Point)>, point: Point) -> bool {
+    region.is_some_and(|(start, end)| (start.y, start.x) <= (point.y, point.x) && (point.y, point.x) < (end.y, end.x))
+}
+
+/// The character to print for a grapheme cluster.
+///
+/// A terminal cell holds a single character, so combining marks are composed
+/// with their base character when possible and dropped otherwise.
+fn displayed_char(grapheme: &str) -> char {
+    grapheme.nfc().next().unwrap_or(' ')
+}